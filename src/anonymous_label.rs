@@ -0,0 +1,122 @@
+use crate::{
+    error::AssemblyError,
+    parser::{expression::Expr, Line},
+};
+
+/**
+ * 無名ラベル (ca65 風)
+ *  :       X=0          行頭の ':' が無名ラベル
+ *  :       X=+
+ *          ;=\,:-       直前の無名ラベルへ (:-- は 2 つ前)
+ *          ;==,:+       直後の無名ラベルへ (:++ は 2 つ後)
+ *  :
+ *
+ * 無名ラベルは #anon_<n> に名前を付け替えてから展開・アセンブルする
+ */
+pub fn resolve(lines: &[Line]) -> Result<Vec<Line>, AssemblyError> {
+    let mut result = Vec::new();
+    // number of anonymous labels up to and including the current line
+    let mut defined = 0;
+    let total = lines.iter().filter(|line| is_anonymous(line)).count();
+    for line in lines {
+        let mut line = line.clone();
+        if is_anonymous(&line) {
+            defined += 1;
+            line.label = Some(label_name(defined));
+        }
+        let line_number = line.line_number;
+        let target = |expr: &Expr| match expr {
+            Expr::SystemOperator(s) => reference(s, defined, total)
+                .map(|target| target.map(|n| Expr::Identifier(label_name(n))))
+                .map_err(|e| AssemblyError::program(&format!("line: {} {}", line_number, e))),
+            _ => Ok(None),
+        };
+        line.statements = line
+            .statements
+            .iter()
+            .map(|statement| statement.rewrite(&target))
+            .collect::<Result<_, _>>()?;
+        result.push(line);
+    }
+    Ok(result)
+}
+
+fn is_anonymous(line: &Line) -> bool {
+    line.label.as_deref() == Some(":")
+}
+
+fn label_name(n: usize) -> String {
+    format!("#anon_{}", n)
+}
+
+// :+ :++ :- :-- -> number of the referenced anonymous label
+fn reference(symbol: &str, defined: usize, total: usize) -> Result<Option<usize>, String> {
+    let Some(steps) = symbol.strip_prefix(':') else {
+        return Ok(None);
+    };
+    let forward = steps.chars().all(|c| c == '+');
+    let backward = steps.chars().all(|c| c == '-');
+    if steps.is_empty() || !(forward || backward) {
+        return Ok(None);
+    }
+    let target = if forward {
+        Some(defined + steps.len()).filter(|n| *n <= total)
+    } else {
+        (defined + 1).checked_sub(steps.len()).filter(|n| *n > 0)
+    };
+    target
+        .map(Some)
+        .ok_or_else(|| format!("anonymous label {} not found", symbol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::statement::Statement;
+
+    fn line(label: Option<&str>, command: &str, operand: &str) -> Line {
+        let statement = Statement::new(command, Expr::parse(operand).unwrap());
+        Line::new(0, 0, label.map(str::to_string), vec![statement], vec![])
+    }
+
+    fn operands(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.statements[0].expression.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_references() {
+        let lines = vec![
+            line(None, "#", ":+"),
+            line(Some(":"), "X", "0"),
+            line(Some(":"), ";", "\\,:-"),
+            line(None, "#", ":--"),
+            line(None, "#", ":++"),
+            line(Some(":"), "X", "+"),
+            line(Some(":"), "#", ":-"),
+        ];
+        let result = resolve(&lines).unwrap();
+        assert_eq!(result[1].label.as_deref(), Some("#anon_1"));
+        assert_eq!(result[6].label.as_deref(), Some("#anon_4"));
+        assert_eq!(
+            operands(&result),
+            vec![
+                "#anon_1",
+                "0",
+                "\\,#anon_2",
+                "#anon_1",
+                "#anon_4",
+                "+",
+                "#anon_4"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_anonymous_label() {
+        assert!(resolve(&[line(None, "#", ":-")]).is_err());
+        assert!(resolve(&[line(Some(":"), "#", ":++")]).is_err());
+    }
+}
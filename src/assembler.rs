@@ -1,14 +1,15 @@
 pub mod pseudo_commands;
 
-use crate::opcode;
-use crate::parser::expression::Operator;
+use crate::cycles;
+use crate::opcode::{self, AssemblyInstruction};
+use crate::parser::expression::{Expr, Operator};
 use crate::parser::statement::Statement;
 use crate::{error::AssemblyError, parser::Line};
 use core::fmt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub struct Assembler {
     pub pc: usize,
@@ -17,18 +18,32 @@ pub struct Assembler {
     pub current_label: String,
     pub is_address_set: bool,
     pub current_path: PathBuf,
+    // (line number, start address) of open .page blocks
+    pub page_blocks: Vec<(usize, usize)>,
+    // name of the open .proc, local labels stay in its scope
+    pub proc_label: Option<(usize, String)>,
+    // labels defined in the current pass (and by -D)
+    pub defined_labels: HashSet<String>,
+    // first *=, := or operand label that could not be resolved in the current pass
+    pub unresolved: Option<(Line, AssemblyError)>,
+    // labels.txt (Mesen label file) written after pass2
+    pub label_file: Option<PathBuf>,
 }
 
+// *= and := may refer to labels defined later, pass1 is repeated until the labels settle
+const MAX_PASSES: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct LabelEntry {
     pub name: String,
+    #[allow(dead_code)]
     pub line: usize,
     pub address: Address,
 }
 
 pub type LabelTable = HashMap<String, LabelEntry>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Address {
     Full(u16),
     ZeroPage(u8),
@@ -40,10 +55,10 @@ impl Address {
             Address::Full(n) => {
                 if let Address::Full(m) = other {
                     Ok(Address::Full(match op {
-                        Operator::Add => n + m,
-                        Operator::Sub => n - m,
-                        Operator::Mul => n * m,
-                        Operator::Div => n / m,
+                        Operator::Add => n.wrapping_add(*m),
+                        Operator::Sub => n.wrapping_sub(*m),
+                        Operator::Mul => n.wrapping_mul(*m),
+                        Operator::Div => n.checked_div(*m).ok_or(Self::division_by_zero())?,
                         Operator::And => n & m,
                         Operator::Or => n | m,
                         Operator::Eor => n ^ m,
@@ -52,10 +67,10 @@ impl Address {
                 } else if let Address::ZeroPage(m) = other {
                     // FullとZeroPageの演算は、Fullとして計算する
                     Ok(Address::Full(match op {
-                        Operator::Add => n + (*m as u16),
-                        Operator::Sub => n - (*m as u16),
-                        Operator::Mul => n * (*m as u16),
-                        Operator::Div => n / (*m as u16),
+                        Operator::Add => n.wrapping_add(*m as u16),
+                        Operator::Sub => n.wrapping_sub(*m as u16),
+                        Operator::Mul => n.wrapping_mul(*m as u16),
+                        Operator::Div => n.checked_div(*m as u16).ok_or(Self::division_by_zero())?,
                         Operator::And => n & (*m as u16),
                         Operator::Or => n | (*m as u16),
                         Operator::Eor => n ^ (*m as u16),
@@ -68,10 +83,10 @@ impl Address {
             Address::ZeroPage(n) => {
                 if let Address::ZeroPage(m) = other {
                     Ok(Address::ZeroPage(match op {
-                        Operator::Add => n + m,
-                        Operator::Sub => n - m,
-                        Operator::Mul => n * m,
-                        Operator::Div => n / m,
+                        Operator::Add => n.wrapping_add(*m),
+                        Operator::Sub => n.wrapping_sub(*m),
+                        Operator::Mul => n.wrapping_mul(*m),
+                        Operator::Div => n.checked_div(*m).ok_or(Self::division_by_zero())?,
                         Operator::And => n & m,
                         Operator::Or => n | m,
                         Operator::Eor => n ^ m,
@@ -84,6 +99,10 @@ impl Address {
         }
     }
 
+    fn division_by_zero() -> AssemblyError {
+        AssemblyError::expression("division by zero")
+    }
+
     fn type_error(&self, other: &Address, op: &str) -> Result<Self, AssemblyError> {
         Err(AssemblyError::expression(&format!(
             "cannot {} {:?} and {:?}",
@@ -120,22 +139,80 @@ impl Assembler {
             current_label: String::new(),
             is_address_set: false,
             current_path,
+            page_blocks: Vec::new(),
+            proc_label: None,
+            defined_labels: HashSet::new(),
+            unresolved: None,
+            label_file: None,
         }
     }
 
     pub fn assemble(&mut self, lines: &mut Vec<Line>) -> Result<usize, AssemblyError> {
-        self.pass1(lines)?;
+        self.settle_labels(lines)?;
         let obj_size = self.pass2(lines)?;
+        if let Some(path) = &self.label_file {
+            self.show_labels(path);
+        }
+        cycles::check(&self.opcode_table, lines, &self.labels)?;
         Ok(obj_size)
     }
 
+    /**
+     * repeat pass1 until every *= and := is resolved and no label moves
+     *  - labels of the previous pass are used for forward references
+     */
+    fn settle_labels(&mut self, lines: &mut Vec<Line>) -> Result<(), AssemblyError> {
+        let predefined: HashSet<String> = self.labels.keys().cloned().collect();
+        for _ in 0..MAX_PASSES {
+            let previous = self.labels.clone();
+            self.pc = 0;
+            self.is_address_set = false;
+            self.current_label = String::new();
+            self.proc_label = None;
+            self.defined_labels = predefined.clone();
+            self.unresolved = None;
+            self.page_blocks = Vec::new();
+            self.pass1(lines)?;
+            let settled = self.labels.len() == previous.len()
+                && self.labels.iter().all(|(name, entry)| {
+                    previous
+                        .get(name)
+                        .is_some_and(|before| before.address == entry.address)
+                });
+            if settled {
+                return match self.unresolved.take() {
+                    Some((line, error)) => {
+                        eprintln!("[pass1] line = {:?}, error = {}", line, error.message());
+                        Err(error)
+                    }
+                    None => Ok(()),
+                };
+            }
+        }
+        Err(AssemblyError::program(&format!(
+            "labels did not settle after {} passes",
+            MAX_PASSES
+        )))
+    }
+
     fn pass1(&mut self, lines: &mut Vec<Line>) -> Result<(), AssemblyError> {
         for line in lines {
-            self.pass1_process_line(line).map_err(|e| {
+            self.pass1_process_line(line).inspect_err(|e| {
                 eprintln!("[pass1] line = {:?}, error = {}", line, e.message());
-                e
             })?
         }
+        if let Some((line_number, _)) = self.page_blocks.last() {
+            return Err(AssemblyError::program(&format!(
+                "line: {} .page without .endpage",
+                line_number
+            )));
+        }
+        if let Some((line_number, _)) = &self.proc_label {
+            return Err(AssemblyError::program(&format!(
+                "line: {} .proc without .endproc",
+                line_number
+            )));
+        }
         Ok(())
     }
 
@@ -145,6 +222,7 @@ impl Assembler {
         }
         line.address = self.pc as u16;
         self.entry_label(line)?;
+        self.resolve_defined(line)?;
         for statement in &line.statements {
             if statement.is_pseudo() {
                 self.pseudo_command_pass1(line, statement)?;
@@ -153,7 +231,12 @@ impl Assembler {
             if !self.is_address_set {
                 return Err(AssemblyError::program("address not set"));
             }
-            let assembly_instruction = statement.decode(&self.labels)?;
+            let assembly_instruction = self.decode_forward(line, statement)?;
+            // (z:addr+Y) may ask for a mode the instruction doesn't have
+            self.opcode_table.find(
+                &assembly_instruction.mnemonic,
+                &assembly_instruction.addressing_mode,
+            )?;
             let len = assembly_instruction.addressing_mode.length();
             self.pc += len;
         }
@@ -162,11 +245,11 @@ impl Assembler {
 
     fn pass2(&mut self, lines: &mut Vec<Line>) -> Result<usize, AssemblyError> {
         self.current_label = String::new();
+        self.proc_label = None;
         let mut objects_size = 0;
         for line in lines {
-            let size = self.pass2_process_line(line).map_err(|e| {
+            let size = self.pass2_process_line(line).inspect_err(|e| {
                 eprintln!("[pass2] line = {:?}, error = {}", line, e.message());
-                e
             })?;
             objects_size += size;
         }
@@ -180,28 +263,40 @@ impl Assembler {
         for statement in &line.statements {
             let objects = if statement.is_pseudo() {
                 let pc_u16 = pc as u16;
-                self.pseudo_command_pass2(statement, &pc_u16)?
+                self.pseudo_command_pass2(line, statement, &pc_u16)?
             } else {
-                statement.compile(&self.opcode_table, &self.labels, &self.current_label, pc)?
+                let objects =
+                    statement.compile(&self.opcode_table, &self.labels, &self.current_label, pc)?;
+                Self::warn_jmp_indirect(line.line_number, &objects);
+                objects
             };
             pc += objects.len();
             objects_size += objects.len();
             line.object_codes.extend(objects);
         }
-        self.show_labels();
         Ok(objects_size)
     }
 
+    // NMOS 6502 reads the high byte of JMP ($xxFF) from $xx00, not from the next page
+    fn warn_jmp_indirect(line_number: usize, objects: &[u8]) {
+        if let [0x6C, 0xFF, high] = objects {
+            eprintln!(
+                "line: {} warning: JMP (${:02X}FF) reads its high byte from ${:02X}00",
+                line_number, high, high
+            );
+        }
+    }
+
     fn track_global_label(&mut self, line: &mut Line) {
         if let Some(label) = &line.label {
             let first_char = label.chars().next().unwrap();
-            if first_char != '.' && first_char != '#' {
+            if first_char != '.' && first_char != '#' && self.proc_label.is_none() {
                 self.current_label = label.to_string();
             }
         }
     }
 
-    fn show_labels(&self) {
+    fn show_labels(&self, path: &Path) {
         // labelsに含まれるLabelEntryのリストを作る
         let mut labels: Vec<&LabelEntry> = self.labels.values().collect();
         // 先頭が # の場合は除外する
@@ -221,7 +316,7 @@ impl Assembler {
         // addressでソートする
         labels.sort_by(|a, b| a.address.cmp(&b.address));
         // labelsの内容を labels.txt に出力する
-        let mut file = File::create("labels.txt").expect("Unable to create file");
+        let mut file = File::create(path).expect("Unable to create file");
 
         for entry in labels {
             let name = &entry.name;
@@ -235,7 +330,7 @@ impl Assembler {
     /**
      * entry label to label table
      *  - if start with ".", treat as local label
-     *  - if label is global, set current_label
+     *  - if label is global, set current_label (not inside .proc)
      */
     fn entry_label(&mut self, line: &mut Line) -> Result<(), AssemblyError> {
         if let Some(label) = &line.label {
@@ -247,13 +342,15 @@ impl Assembler {
                 }
                 label = format!("{}{}", self.current_label, label);
                 self.add_entry(&label, line)?;
-            } else if label.starts_with("#macro_") {
-                // macro label -- don't memorize current label
+            } else if label.starts_with('#') {
+                // macro or anonymous label -- don't memorize current label
                 self.add_entry(&label, line)?;
             } else {
                 // global label
                 self.add_entry(&label, line)?;
-                self.current_label = label.to_string();
+                if self.proc_label.is_none() {
+                    self.current_label = label.to_string();
+                }
             }
             if let Some(entry) = self.labels.get_mut(&label) {
                 entry.address = Address::Full(self.pc as u16);
@@ -262,8 +359,34 @@ impl Assembler {
         Ok(())
     }
 
+    /**
+     * defined(name) becomes 1 or 0 by the labels defined so far in the pass
+     *  - the same in every pass, a forward label is 0 (labels of the previous pass are not used)
+     */
+    fn resolve_defined(&self, line: &mut Line) -> Result<(), AssemblyError> {
+        let line_number = line.line_number;
+        let defined = |args: &[Expr]| match args {
+            [Expr::Identifier(name)] => {
+                let name = if name.starts_with('.') {
+                    format!("{}{}", self.current_label, name)
+                } else {
+                    name.clone()
+                };
+                Ok(Expr::DecimalNum(self.defined_labels.contains(&name) as u16))
+            }
+            _ => Err(AssemblyError::program(&format!(
+                "line: {} defined() needs a label name",
+                line_number
+            ))),
+        };
+        for statement in line.statements.iter_mut() {
+            statement.expression = statement.expression.replace_function("defined", &defined)?;
+        }
+        Ok(())
+    }
+
     fn add_entry(&mut self, label: &str, line: &Line) -> Result<(), AssemblyError> {
-        if !self.labels.contains_key(label) {
+        if self.defined_labels.insert(label.to_string()) {
             self.add_label(label, line.line_number, self.pc as u16);
             Ok(())
         } else {
@@ -284,23 +407,179 @@ impl Assembler {
         line: &Line,
         statement: &Statement,
     ) -> Result<(), AssemblyError> {
+        match statement.command()?.as_str() {
+            ".page" => {
+                self.page_blocks.push((line.line_number, self.pc));
+                return Ok(());
+            }
+            ".endpage" => return self.end_page_block(line),
+            ".align" => {
+                if !self.is_address_set {
+                    return Err(AssemblyError::program("address not set"));
+                }
+                let pc = self.pc as u16;
+                match pseudo_commands::command_align(statement, &self.labels, &pc) {
+                    Ok((padding, _)) => self.pc += padding,
+                    Err(e) => self.defer_unresolved(line, e),
+                }
+                return Ok(());
+            }
+            "&" => {
+                // offset and length may use labels defined later
+                let pc = self.pc as u16;
+                match pseudo_commands::pass1_command_include_binary(
+                    statement,
+                    self.current_path.clone(),
+                    &self.labels,
+                    &pc,
+                ) {
+                    Ok(bytes) if self.is_address_set => self.pc += bytes as usize,
+                    Ok(_) => (),
+                    Err(e) => self.defer_unresolved(line, e),
+                }
+                return Ok(());
+            }
+            ".proc" => return self.begin_proc(line),
+            ".endproc" => return self.end_proc(line.line_number),
+            "*" => {
+                let pc = self.pc as u16;
+                match pseudo_commands::start_address(statement, &self.labels, &pc) {
+                    Ok(address) => self.pc = address as usize,
+                    Err(e) => self.defer_unresolved(line, e),
+                }
+                self.is_address_set = true;
+                return Ok(());
+            }
+            ":" => {
+                let pc = self.pc as u16;
+                match pseudo_commands::label_value(line, statement, &self.labels, &pc) {
+                    Ok((name, address)) => self.set_label_value(&name, address)?,
+                    Err(e) => self.defer_unresolved(line, e),
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
         pseudo_commands::pass1(
-            line,
             statement,
-            self.current_path.clone(),
             &mut self.labels,
             &mut self.pc,
             &mut self.is_address_set,
         )
     }
 
+    /**
+     * name .proc ... .endproc
+     *  - local labels in the block belong to `name`,
+     *    global labels inside (extra entry points) don't start a new scope
+     */
+    fn begin_proc(&mut self, line: &Line) -> Result<(), AssemblyError> {
+        if let Some((line_number, _)) = &self.proc_label {
+            return Err(AssemblyError::program(&format!(
+                "line: {} nested .proc (open at line {})",
+                line.line_number, line_number
+            )));
+        }
+        match &line.label {
+            Some(label) if !label.starts_with('.') && !label.starts_with('#') => {
+                self.proc_label = Some((line.line_number, label.clone()));
+                Ok(())
+            }
+            _ => Err(AssemblyError::program(&format!(
+                "line: {} .proc needs a global label",
+                line.line_number
+            ))),
+        }
+    }
+
+    fn end_proc(&mut self, line_number: usize) -> Result<(), AssemblyError> {
+        self.proc_label
+            .take()
+            .ok_or(AssemblyError::program(&format!(
+                "line: {} .endproc without .proc",
+                line_number
+            )))?;
+        // local labels after the block need a new global label
+        self.current_label = String::new();
+        Ok(())
+    }
+
+    // pass1 takes labels not defined yet as $0000, they are reported if they stay missing
+    fn decode_forward(
+        &mut self,
+        line: &Line,
+        statement: &Statement,
+    ) -> Result<AssemblyInstruction, AssemblyError> {
+        let (instruction, missing) = statement.decode_forward(&self.labels)?;
+        if let Some(name) = missing.first() {
+            self.defer_unresolved(line, AssemblyError::label_not_found(name));
+        }
+        Ok(instruction)
+    }
+
+    fn defer_unresolved(&mut self, line: &Line, error: AssemblyError) {
+        if self.unresolved.is_none() {
+            self.unresolved = Some((line.clone(), error));
+        }
+    }
+
+    fn set_label_value(&mut self, name: &str, address: Address) -> Result<(), AssemblyError> {
+        let entry = self
+            .labels
+            .get_mut(name)
+            .ok_or(AssemblyError::program("label not found"))?;
+        entry.address = address;
+        Ok(())
+    }
+
+    /**
+     * .page ... .endpage
+     *  - the code and data between them must not cross a page boundary
+     *  - checked on the settled pass, forward labels may still change the size
+     */
+    fn end_page_block(&mut self, line: &Line) -> Result<(), AssemblyError> {
+        let (_, start) = self
+            .page_blocks
+            .pop()
+            .ok_or(AssemblyError::program("mismatch page block"))?;
+        let end = self.pc;
+        if end > start && start >> 8 != (end - 1) >> 8 {
+            let boundary = (start & !0xff) + 0x100;
+            let error = AssemblyError::program(&format!(
+                "line: {} block ${:04X}-${:04X} ({} bytes) crosses page boundary ${:04X}: {} bytes before, {} bytes after",
+                line.line_number,
+                start,
+                end - 1,
+                end - start,
+                boundary,
+                boundary - start,
+                end - boundary
+            ));
+            self.defer_unresolved(line, error);
+        }
+        Ok(())
+    }
+
     fn pseudo_command_pass2(
         &mut self,
+        line: &Line,
         statement: &Statement,
         current_address: &u16,
     ) -> Result<Vec<u8>, AssemblyError> {
+        match statement.command()?.as_str() {
+            ".proc" => {
+                self.proc_label = Some((line.line_number, self.current_label.clone()));
+                return Ok(vec![]);
+            }
+            ".endproc" => {
+                self.end_proc(line.line_number)?;
+                return Ok(vec![]);
+            }
+            _ => (),
+        }
         let labels = &self.labels;
         pseudo_commands::pass2(
+            &line.location(),
             statement,
             labels,
             current_address,
@@ -317,3 +596,279 @@ impl Assembler {
         self.labels.insert(name.to_string(), entry);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_body;
+    use crate::test_dir::TestDir;
+
+    fn lines(statements: Vec<Statement>) -> Vec<Line> {
+        statements
+            .into_iter()
+            .enumerate()
+            .map(|(i, statement)| Line::new(i + 1, 0, None, vec![statement], vec![]))
+            .collect()
+    }
+
+    fn page_block(start: u16, size: u16) -> Vec<Line> {
+        lines(vec![
+            Statement::new("*", Expr::WordNum(start)),
+            Statement::new(".page", Expr::Empty),
+            Statement::new("$", Expr::parse(&format!("$00,{}", size)).unwrap()),
+            Statement::new(".endpage", Expr::Empty),
+        ])
+    }
+
+    #[test]
+    fn test_page_block() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        assert!(assembler.settle_labels(&mut page_block(0x80F0, 16)).is_ok());
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        assert_eq!(
+            assembler.settle_labels(&mut page_block(0x80F0, 20)),
+            Err(AssemblyError::program(
+                "line: 4 block $80F0-$8103 (20 bytes) crosses page boundary $8100: 16 bytes before, 4 bytes after"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_page_block_forward_label() {
+        // fwd is taken as absolute $0000 on the first pass, zero page after it settles
+        let mut lines: Vec<Line> = ["*=$80FC", ".page", "A=(fwd)", "A=(fwd)", ".endpage", "#=!"]
+            .iter()
+            .enumerate()
+            .map(|(i, body)| Line::new(i + 1, 0, None, parse_body(body).unwrap(), vec![]))
+            .collect();
+        lines.push(label_line(
+            7,
+            "fwd",
+            Some(Statement::new(":", Expr::parse("$10").unwrap())),
+        ));
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        assembler.settle_labels(&mut lines).unwrap();
+        // 4 bytes at $80FC-$80FF, #=! right after the block
+        assert_eq!(lines[5].address, 0x8100);
+    }
+
+    fn label_line(line_number: usize, label: &str, statement: Option<Statement>) -> Line {
+        let statements = statement.into_iter().collect();
+        Line::new(line_number, 0, Some(label.to_string()), statements, vec![])
+    }
+
+    fn address_of(assembler: &Assembler, name: &str) -> Address {
+        assembler.labels[name].address.clone()
+    }
+
+    #[test]
+    fn test_start_address_expression() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = vec![
+            // forward reference to BASE
+            Line::new(
+                1,
+                0,
+                None,
+                vec![Statement::new("*", Expr::parse("BASE+$100").unwrap())],
+                vec![],
+            ),
+            label_line(2, "first", None),
+            Line::new(
+                3,
+                0,
+                None,
+                vec![Statement::new("*", Expr::parse("*+16").unwrap())],
+                vec![],
+            ),
+            label_line(4, "second", None),
+            label_line(5, "BASE", Some(Statement::new(":", Expr::WordNum(0x8000)))),
+        ];
+        assembler.settle_labels(&mut lines).unwrap();
+        assert_eq!(address_of(&assembler, "first"), Address::Full(0x8100));
+        assert_eq!(address_of(&assembler, "second"), Address::Full(0x8110));
+    }
+
+    #[test]
+    fn test_label_def_expression() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = vec![
+            Line::new(
+                1,
+                0,
+                None,
+                vec![Statement::new("*", Expr::WordNum(0x8000))],
+                vec![],
+            ),
+            label_line(
+                2,
+                "SIZE",
+                Some(Statement::new(":", Expr::parse("end-start").unwrap())),
+            ),
+            label_line(3, "start", None),
+            Line::new(
+                4,
+                0,
+                None,
+                vec![Statement::new("$", Expr::parse("0,5").unwrap())],
+                vec![],
+            ),
+            label_line(
+                5,
+                "HERE",
+                Some(Statement::new(":", Expr::parse("*").unwrap())),
+            ),
+            label_line(6, "end", None),
+        ];
+        assembler.settle_labels(&mut lines).unwrap();
+        assert_eq!(address_of(&assembler, "SIZE"), Address::Full(5));
+        assert_eq!(address_of(&assembler, "HERE"), Address::Full(0x8005));
+    }
+
+    #[test]
+    fn test_unresolved_label_def() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = vec![label_line(
+            1,
+            "SIZE",
+            Some(Statement::new(":", Expr::parse("nowhere+1").unwrap())),
+        )];
+        assert!(assembler.settle_labels(&mut lines).is_err());
+    }
+
+    #[test]
+    fn test_forward_operand_label() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = lines(vec![
+            Statement::new("*", Expr::WordNum(0x8000)),
+            Statement::new("A", Expr::parse("hi(fwd)").unwrap()),
+            Statement::new("A", Expr::parse("(fwd+1)").unwrap()),
+        ]);
+        lines.push(label_line(4, "fwd", None));
+        assembler.settle_labels(&mut lines).unwrap();
+        assert_eq!(address_of(&assembler, "fwd"), Address::Full(0x8005));
+    }
+
+    #[test]
+    fn test_defined_forward_label() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = lines(vec![Statement::new("*", Expr::WordNum(0x8000))]);
+        for (line_number, label, body) in [
+            (2, "early", ":=defined(later)"),
+            (3, "later", ":=defined(early)"),
+        ] {
+            let statement = parse_body(body).unwrap().remove(0);
+            lines.push(label_line(line_number, label, Some(statement)));
+        }
+        assembler.settle_labels(&mut lines).unwrap();
+        assert_eq!(address_of(&assembler, "early"), Address::ZeroPage(0));
+        assert_eq!(address_of(&assembler, "later"), Address::ZeroPage(1));
+    }
+
+    #[test]
+    fn test_include_binary_forward_slice() {
+        let dir = TestDir::new();
+        dir.write("tiles.chr", &[0; 16]);
+        let mut assembler = Assembler::new(dir.path().into());
+        let mut lines: Vec<Line> = ["*=$8000", "&=\"tiles.chr\",OFFSET,SIZE"]
+            .iter()
+            .enumerate()
+            .map(|(i, body)| Line::new(i + 1, 0, None, parse_body(body).unwrap(), vec![]))
+            .collect();
+        lines.push(label_line(3, "after", None));
+        for (line_number, label, value) in [(4, "OFFSET", "4"), (5, "SIZE", "8")] {
+            let statement = Statement::new(":", Expr::parse(value).unwrap());
+            lines.push(label_line(line_number, label, Some(statement)));
+        }
+        assembler.settle_labels(&mut lines).unwrap();
+        assert_eq!(address_of(&assembler, "after"), Address::Full(0x8008));
+    }
+
+    #[test]
+    fn test_undefined_operand_label() {
+        for (statement, name) in [
+            ("A=hi(nowhere)", "nowhere"),
+            ("A=max(3,nolabel)", "nolabel"),
+            ("A=<nowhere", "nowhere"),
+            ("A=(nowhere+1)", "nowhere"),
+            ("A=(nowhere)", "nowhere"),
+            ("(nolabel+2)=A", "nolabel"),
+        ] {
+            let mut assembler = Assembler::new(PathBuf::from("."));
+            let mut lines = lines(vec![Statement::new("*", Expr::WordNum(0x8000))]);
+            lines.push(Line::new(
+                2,
+                0,
+                None,
+                parse_body(statement).unwrap(),
+                vec![],
+            ));
+            assert_eq!(
+                assembler.settle_labels(&mut lines).err(),
+                Some(AssemblyError::label_not_found(name))
+            );
+        }
+    }
+
+    #[test]
+    fn test_proc_scope() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = vec![
+            Line::new(
+                1,
+                0,
+                None,
+                vec![Statement::new("*", Expr::WordNum(0x8000))],
+                vec![],
+            ),
+            label_line(2, "one", Some(Statement::new(".proc", Expr::Empty))),
+            label_line(3, "one_o1", Some(Statement::new("X", Expr::DecimalNum(0)))),
+            label_line(4, ".loop", None),
+            Line::new(
+                5,
+                0,
+                None,
+                vec![Statement::new(".endproc", Expr::Empty)],
+                vec![],
+            ),
+        ];
+        assembler.settle_labels(&mut lines).unwrap();
+        assert_eq!(address_of(&assembler, "one.loop"), Address::Full(0x8002));
+        assert!(!assembler.labels.contains_key("one_o1.loop"));
+    }
+
+    #[test]
+    fn test_proc_errors() {
+        let proc = |label: Option<&str>| {
+            let statement = Statement::new(".proc", Expr::Empty);
+            Line::new(1, 0, label.map(str::to_string), vec![statement], vec![])
+        };
+        let endproc = Line::new(
+            2,
+            0,
+            None,
+            vec![Statement::new(".endproc", Expr::Empty)],
+            vec![],
+        );
+        for mut lines in [
+            vec![proc(None), endproc.clone()],
+            vec![proc(Some("a"))],
+            vec![endproc.clone()],
+            vec![proc(Some("a")), proc(Some("b")), endproc.clone()],
+        ] {
+            let mut assembler = Assembler::new(PathBuf::from("."));
+            assert!(assembler.settle_labels(&mut lines).is_err());
+        }
+    }
+
+    #[test]
+    fn test_align() {
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        let mut lines = lines(vec![
+            Statement::new("*", Expr::WordNum(0x8003)),
+            Statement::new(".align", Expr::DecimalNum(256)),
+        ]);
+        assembler.pass1(&mut lines).unwrap();
+        assert_eq!(assembler.pc, 0x8100);
+    }
+}
@@ -1,28 +1,22 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 
+mod png_tiles;
+use png_tiles::TileFormat;
+
 use crate::parser::expression::{Expr, Operator};
 use crate::Line;
 
 use super::*;
 
 pub fn pass1(
-    line: &Line,
     statement: &Statement,
-    current_path: PathBuf,
     labels: &mut LabelTable,
     pc: &mut usize,
     is_address_set: &mut bool,
 ) -> Result<(), AssemblyError> {
     let command = statement.command()?;
-    if command == "*" {
-        let address = pass1_command_start_address(statement)?;
-        *pc = address as usize;
-        *is_address_set = true;
-        Ok(())
-    } else if command == ":" {
-        pass1_command_label_def(line, statement, labels)
-    } else if command == "?" {
+    if command == "?" {
         let bytes = pass1_command_data_def(statement)?;
         if *is_address_set {
             *pc += bytes as usize;
@@ -30,13 +24,13 @@ pub fn pass1(
         Ok(())
     } else if command == "$" {
         let pc_u16 = *pc as u16;
-        let bytes = pass1_command_data_fill(statement, &labels, &pc_u16)?;
+        let bytes = pass1_command_data_fill(statement, labels, &pc_u16)?;
         if *is_address_set {
             *pc += bytes as usize;
         }
         Ok(())
-    } else if command == "&" {
-        let bytes = pass1_command_include_binary(statement, current_path)?;
+    } else if let Some(form) = DataForm::from_command(&command) {
+        let bytes = pass1_command_typed_data(statement, form)?;
         if *is_address_set {
             *pc += bytes as usize;
         }
@@ -46,24 +40,31 @@ pub fn pass1(
     }
 }
 
-// label def
-fn pass1_command_label_def(
+// *=$0800  *=BASE+$100  *=*+16
+pub fn start_address(
+    statement: &Statement,
+    labels: &LabelTable,
+    pc: &u16,
+) -> Result<u16, AssemblyError> {
+    statement.expression.evaluate(labels, pc)
+}
+
+// label :=$10  label :=*  label :=end-start
+pub fn label_value(
     line: &Line,
     statement: &Statement,
-    labels: &mut LabelTable,
-) -> Result<(), AssemblyError> {
-    let address = statement.expression.calculate_address(&labels)?;
+    labels: &LabelTable,
+    pc: &u16,
+) -> Result<(String, Address), AssemblyError> {
     let label_name = line
         .label
         .clone()
         .ok_or(AssemblyError::program("label def need label"))?;
-    // fetch label entry
-    let label_entry = labels
-        .get_mut(&label_name)
-        .ok_or(AssemblyError::program("label not found"))?;
-    // set address to entry
-    label_entry.address = address;
-    Ok(())
+    let address = statement
+        .expression
+        .replace_current_address(*pc)
+        .calculate_address(labels)?;
+    Ok((label_name, address))
 }
 
 fn pass1_command_data_def(statement: &Statement) -> Result<u16, AssemblyError> {
@@ -82,6 +83,81 @@ fn pass1_command_data_def(statement: &Statement) -> Result<u16, AssemblyError> {
     Ok(pc)
 }
 
+// data directives with explicit width
+//   .byte=1,<label,"text"   -> 1 byte each (strings are emitted as is)
+//   .word=label,*+2         -> little endian word
+//   .dbyt=label             -> big endian word
+//   .lobytes=reset,nmi,irq  -> low bytes of each value
+//   .hibytes=reset,nmi,irq  -> high bytes of each value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DataForm {
+    Byte,
+    Word,
+    WordBigEndian,
+    LoBytes,
+    HiBytes,
+}
+
+impl DataForm {
+    fn from_command(command: &str) -> Option<Self> {
+        match command {
+            ".byte" => Some(Self::Byte),
+            ".word" => Some(Self::Word),
+            ".dbyt" => Some(Self::WordBigEndian),
+            ".lobytes" => Some(Self::LoBytes),
+            ".hibytes" => Some(Self::HiBytes),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> u16 {
+        match self {
+            Self::Word | Self::WordBigEndian => 2,
+            _ => 1,
+        }
+    }
+
+    fn emit(&self, value: u16, objects: &mut Vec<u8>) -> Result<(), AssemblyError> {
+        match self {
+            // negative constants -128..-1 wrap to a byte ($FF80..$FFFF)
+            Self::Byte => {
+                if value > 0xff && value < 0xff80 {
+                    return Err(AssemblyError::program(&format!(
+                        "value ${:04X} does not fit in a byte",
+                        value
+                    )));
+                }
+                objects.push(value as u8);
+            }
+            Self::Word => {
+                objects.push((value & 0xff) as u8);
+                objects.push((value >> 8) as u8);
+            }
+            Self::WordBigEndian => {
+                objects.push((value >> 8) as u8);
+                objects.push((value & 0xff) as u8);
+            }
+            Self::LoBytes => objects.push((value & 0xff) as u8),
+            Self::HiBytes => objects.push((value >> 8) as u8),
+        }
+        Ok(())
+    }
+}
+
+fn pass1_command_typed_data(statement: &Statement, form: DataForm) -> Result<u16, AssemblyError> {
+    let mut pc = 0;
+    for value in statement.expression.traverse_comma() {
+        pc += match value {
+            Expr::StringLiteral(ref s) if form == DataForm::Byte => s.len() as u16,
+            Expr::StringLiteral(_) | Expr::Empty => {
+                return Err(AssemblyError::program("invalid data command"))
+            }
+            _ => form.size(),
+        }
+    }
+    Ok(pc)
+}
+
 // %=$FF,12 -> fill 12 bytes with data $FF
 fn pass1_command_data_fill(
     statement: &Statement,
@@ -101,17 +177,36 @@ fn pass1_command_data_fill(
             return Ok(fill_count);
         }
     }
-    dbg!(statement);
     Err(AssemblyError::program("invalid fill command"))
 }
 
-fn pass1_command_start_address(statement: &Statement) -> Result<u16, AssemblyError> {
-    if let Expr::WordNum(address) = statement.expression {
-        return Ok(address);
+// .align=256      -> pad with 0 up to the next multiple of 256
+// .align=256,$FF  -> pad with $FF
+// returns (padding size, fill byte)
+pub fn command_align(
+    statement: &Statement,
+    labels: &LabelTable,
+    current_address: &u16,
+) -> Result<(usize, u8), AssemblyError> {
+    let args = statement.expression.traverse_comma();
+    let boundary = args[0].evaluate(labels, current_address)? as usize;
+    if boundary == 0 {
+        return Err(AssemblyError::program("align boundary must not be 0"));
     }
-    Err(AssemblyError::program("invalid start address"))
+    let fill = match args.get(1) {
+        Some(expr) => expr.evaluate(labels, current_address)?,
+        None => 0,
+    };
+    if fill > 0xff {
+        return Err(AssemblyError::program("align fill value must be 8bit"));
+    }
+    let address = *current_address as usize;
+    let padding = (boundary - address % boundary) % boundary;
+    Ok((padding, fill as u8))
 }
+
 pub fn pass2(
+    location: &str,
     statement: &Statement,
     labels: &LabelTable,
     current_address: &u16,
@@ -120,37 +215,215 @@ pub fn pass2(
     let command = statement.command()?;
     let expression = &statement.expression;
     if command == "?" {
-        return pass2_command_data_def(expression, statement, labels);
+        return pass2_command_data_def(expression, labels);
     } else if command == "$" {
         return pass2_command_data_fill(statement, labels, current_address);
     } else if command == "&" {
-        return pass2_command_include_binary(statement, current_path);
+        return pass2_command_include_binary(statement, current_path, labels, current_address);
+    } else if let Some(form) = DataForm::from_command(&command) {
+        return pass2_command_typed_data(statement, form, labels, current_address);
+    } else if command == ".align" {
+        let (padding, fill) = command_align(statement, labels, current_address)?;
+        return Ok(vec![fill; padding]);
+    } else if [".assert", ".error", ".warning", ".print"].contains(&command.as_str()) {
+        return pass2_command_diagnostic(location, &command, statement, labels, current_address);
     }
-    return Ok(Vec::new());
+    Ok(Vec::new())
+}
+
+// &="file"                  -> include whole file
+// &="file",$1000             -> from offset $1000 to the end of file
+// &="file",$1000,$1000       -> $1000 bytes from offset $1000
+// &="file",0,16,XOR,$FF,SWAP,REV -> apply transformations in order
+// &="tiles.png",NES,0,3,1,2       -> convert indexed png to tiles (see png_tiles.rs)
+struct BinaryInclude {
+    path: PathBuf,
+    offset: usize,
+    length: usize,
+    transforms: Vec<Transform>,
+    tiles: Option<(TileFormat, Vec<u8>)>,
 }
 
-fn pass1_command_include_binary(
+#[derive(Debug, PartialEq)]
+enum Transform {
+    Xor(u8),
+    SwapNibbles,
+    Reverse,
+}
+
+impl Transform {
+    fn is_keyword(expr: &Expr) -> bool {
+        matches!(expr, Expr::Identifier(name) if ["SWAP", "REV", "XOR"].contains(&name.as_str()))
+    }
+
+    fn parse(
+        expr: &Expr,
+        args: &mut impl Iterator<Item = Expr>,
+        labels: &LabelTable,
+        pc: &u16,
+    ) -> Result<Self, AssemblyError> {
+        match expr {
+            Expr::Identifier(name) if name == "SWAP" => Ok(Self::SwapNibbles),
+            Expr::Identifier(name) if name == "REV" => Ok(Self::Reverse),
+            Expr::Identifier(name) if name == "XOR" => {
+                let value = args
+                    .next()
+                    .ok_or(AssemblyError::program("XOR needs a value"))?
+                    .evaluate(labels, pc)?;
+                if value > 0xff {
+                    return Err(AssemblyError::program("XOR value must be 8bit"));
+                }
+                Ok(Self::Xor(value as u8))
+            }
+            _ => Err(AssemblyError::program(&format!(
+                "invalid include transformation: {:?}",
+                expr
+            ))),
+        }
+    }
+
+    fn apply(&self, data: &mut [u8]) {
+        match self {
+            Self::Xor(value) => data.iter_mut().for_each(|b| *b ^= value),
+            Self::SwapNibbles => data.iter_mut().for_each(|b| *b = b.rotate_left(4)),
+            Self::Reverse => data.reverse(),
+        }
+    }
+}
+
+impl BinaryInclude {
+    fn parse(
+        statement: &Statement,
+        current_path: PathBuf,
+        labels: &LabelTable,
+        pc: &u16,
+    ) -> Result<Self, AssemblyError> {
+        let mut args = statement.expression.traverse_comma().into_iter();
+        let path = match args.next() {
+            Some(Expr::StringLiteral(filename)) => current_path.join(filename),
+            _ => return Err(AssemblyError::program("invalid include command")),
+        };
+        if Self::is_png(&path) {
+            return Self::parse_png(path, args, labels, pc);
+        }
+        let filesize = inspect_filesize(&path)?;
+
+        // offset and length come first, transformations are keywords
+        let mut numbers = vec![];
+        let mut transforms = vec![];
+        while let Some(arg) = args.next() {
+            if transforms.is_empty() && numbers.len() < 2 && !Transform::is_keyword(&arg) {
+                numbers.push(arg.evaluate(labels, pc)? as usize);
+                continue;
+            }
+            transforms.push(Transform::parse(&arg, &mut args, labels, pc)?);
+        }
+        let offset = numbers.first().copied().unwrap_or(0);
+        if offset > filesize {
+            return Err(AssemblyError::program(&format!(
+                "include offset ${:04X} is beyond the end of {} ({} bytes)",
+                offset,
+                path.display(),
+                filesize
+            )));
+        }
+        let length = numbers.get(1).copied().unwrap_or(filesize - offset);
+        if offset + length > filesize {
+            return Err(AssemblyError::program(&format!(
+                "include range ${:04X}-${:04X} is out of bounds of {} ({} bytes)",
+                offset,
+                offset + length,
+                path.display(),
+                filesize
+            )));
+        }
+        check_include_size(length)?;
+        Ok(Self {
+            path,
+            offset,
+            length,
+            transforms,
+            tiles: None,
+        })
+    }
+
+    fn is_png(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+    }
+
+    fn parse_png(
+        path: PathBuf,
+        mut args: impl Iterator<Item = Expr>,
+        labels: &LabelTable,
+        pc: &u16,
+    ) -> Result<Self, AssemblyError> {
+        let format = match args.next() {
+            Some(Expr::Identifier(name)) => TileFormat::from_name(&name),
+            _ => None,
+        }
+        .ok_or(AssemblyError::program(
+            "png include needs tile format NES or C64",
+        ))?;
+        let mut mapping = vec![];
+        for arg in args {
+            let colour = arg.evaluate(labels, pc)?;
+            if colour > 0xff {
+                return Err(AssemblyError::program("invalid colour mapping"));
+            }
+            mapping.push(colour as u8);
+        }
+        let length = png_tiles::convert(&path, format, &mapping)?.len();
+        check_include_size(length)?;
+        Ok(Self {
+            path,
+            offset: 0,
+            length,
+            transforms: vec![],
+            tiles: Some((format, mapping)),
+        })
+    }
+
+    fn read(&self) -> Result<Vec<u8>, AssemblyError> {
+        if let Some((format, mapping)) = &self.tiles {
+            return png_tiles::convert(&self.path, *format, mapping);
+        }
+        let data = read_binary_file(self.path.clone())?;
+        let mut data = data[self.offset..self.offset + self.length].to_vec();
+        for transform in &self.transforms {
+            transform.apply(&mut data);
+        }
+        Ok(data)
+    }
+}
+
+fn check_include_size(length: usize) -> Result<(), AssemblyError> {
+    if length > 0xffff {
+        return Err(AssemblyError::program(&format!(
+            "include size {} bytes is too large",
+            length
+        )));
+    }
+    Ok(())
+}
+
+pub fn pass1_command_include_binary(
     statement: &Statement,
     current_path: PathBuf,
+    labels: &LabelTable,
+    current_address: &u16,
 ) -> Result<u16, AssemblyError> {
-    let expr = &statement.expression;
-    match expr {
-        Expr::StringLiteral(ref filename) => {
-            let path = current_path.join(filename);
-            inspect_filesize(&path)
-        }
-        _ => Err(AssemblyError::program("invalid include command")),
-    }
+    let include = BinaryInclude::parse(statement, current_path, labels, current_address)?;
+    Ok(include.length as u16)
 }
 
-fn inspect_filesize(path: &Path) -> Result<u16, AssemblyError> {
+fn inspect_filesize(path: &Path) -> Result<usize, AssemblyError> {
     let metadata = fs::metadata(path)?;
-    Ok(metadata.len() as u16)
+    Ok(metadata.len() as usize)
 }
 
 fn pass2_command_data_def(
     expression: &Expr,
-    statement: &Statement,
     labels: &LabelTable,
 ) -> Result<Vec<u8>, AssemblyError> {
     let mut objects = Vec::new();
@@ -182,12 +455,10 @@ fn pass2_command_data_def(
                 } else if let Address::ZeroPage(address) = label.address {
                     objects.push(address);
                 } else {
-                    dbg!(statement);
                     return Err(AssemblyError::program("invalid data command"));
                 }
             }
             _ => {
-                dbg!(statement);
                 return Err(AssemblyError::program(
                     "invalid data command nothing matched",
                 ));
@@ -197,6 +468,83 @@ fn pass2_command_data_def(
     Ok(objects)
 }
 
+fn pass2_command_typed_data(
+    statement: &Statement,
+    form: DataForm,
+    labels: &LabelTable,
+    current_address: &u16,
+) -> Result<Vec<u8>, AssemblyError> {
+    let mut objects = Vec::new();
+    for value in statement.expression.traverse_comma() {
+        match value {
+            Expr::StringLiteral(ref s) if form == DataForm::Byte => {
+                objects.extend(s.bytes());
+            }
+            _ => {
+                let value = value.evaluate(labels, current_address)?;
+                form.emit(value, &mut objects)?;
+            }
+        }
+    }
+    Ok(objects)
+}
+
+// .assert=*<$FFFA,"code too big"  -> stop assembly when the condition is 0
+// .error="not supported"          -> stop assembly
+// .warning="deprecated"           -> print a warning and continue
+// .print="free: ",$FFFA-*         -> print strings and values
+fn pass2_command_diagnostic(
+    location: &str,
+    command: &str,
+    statement: &Statement,
+    labels: &LabelTable,
+    current_address: &u16,
+) -> Result<Vec<u8>, AssemblyError> {
+    let mut args = statement.expression.traverse_comma();
+    if command == ".assert" {
+        let condition = args.remove(0).evaluate(labels, current_address)?;
+        if condition != 0 {
+            return Ok(Vec::new());
+        }
+    }
+    let message = diagnostic_message(args, labels, current_address)?;
+    match command {
+        ".assert" => Err(AssemblyError::user(
+            location,
+            &format!("assertion failed: {}", message),
+        )),
+        ".error" => Err(AssemblyError::user(location, &message)),
+        ".warning" => {
+            eprintln!("{} warning: {}", location, message);
+            Ok(Vec::new())
+        }
+        _ => {
+            eprintln!("{}", message);
+            Ok(Vec::new())
+        }
+    }
+}
+
+// strings as they are, values as $XXXX
+fn diagnostic_message(
+    args: Vec<Expr>,
+    labels: &LabelTable,
+    current_address: &u16,
+) -> Result<String, AssemblyError> {
+    let mut message = String::new();
+    for arg in args {
+        match arg {
+            Expr::StringLiteral(s) => message.push_str(&s),
+            Expr::Empty => (),
+            _ => {
+                let value = arg.evaluate(labels, current_address)?;
+                message.push_str(&format!("${:04X}", value));
+            }
+        }
+    }
+    Ok(message)
+}
+
 // %=$FF,12 -> fill 12 bytes with data $FF
 fn pass2_command_data_fill(
     statement: &Statement,
@@ -205,47 +553,38 @@ fn pass2_command_data_fill(
 ) -> Result<Vec<u8>, AssemblyError> {
     let expr = &statement.expression;
     let mut objects = Vec::new();
-    match expr {
-        Expr::BinOp(left, Operator::Comma, right) => {
-            let fill_count = right.evaluate(labels, current_address)?;
-            if let Expr::ByteNum(fill_value) = **left {
-                for _ in 0..fill_count {
-                    objects.push(fill_value);
-                }
-                return Ok(objects);
+    if let Expr::BinOp(left, Operator::Comma, right) = expr {
+        let fill_count = right.evaluate(labels, current_address)?;
+        if let Expr::ByteNum(fill_value) = **left {
+            for _ in 0..fill_count {
+                objects.push(fill_value);
             }
-            if let Expr::DecimalNum(fill_value) = **left {
-                for _ in 0..fill_count {
-                    objects.push(fill_value as u8);
-                }
-                return Ok(objects);
+            return Ok(objects);
+        }
+        if let Expr::DecimalNum(fill_value) = **left {
+            for _ in 0..fill_count {
+                objects.push(fill_value as u8);
             }
-            if let Expr::WordNum(fill_value) = **left {
-                for _ in 0..fill_count {
-                    objects.push((fill_value & 0xff) as u8);
-                    objects.push((fill_value >> 8) as u8);
-                }
-                return Ok(objects);
+            return Ok(objects);
+        }
+        if let Expr::WordNum(fill_value) = **left {
+            for _ in 0..fill_count {
+                objects.push((fill_value & 0xff) as u8);
+                objects.push((fill_value >> 8) as u8);
             }
+            return Ok(objects);
         }
-        _ => (),
     }
-    dbg!(statement);
-    return Err(AssemblyError::program("invalid fill command"));
+    Err(AssemblyError::program("invalid fill command"))
 }
 
 fn pass2_command_include_binary(
     statement: &Statement,
     current_path: PathBuf,
+    labels: &LabelTable,
+    current_address: &u16,
 ) -> Result<Vec<u8>, AssemblyError> {
-    let expr = &statement.expression;
-    match expr {
-        Expr::StringLiteral(ref s) => {
-            let path = current_path.join(s);
-            read_binary_file(path)
-        }
-        _ => Err(AssemblyError::program("invalid include command")),
-    }
+    BinaryInclude::parse(statement, current_path, labels, current_address)?.read()
 }
 
 fn read_binary_file(path: PathBuf) -> Result<Vec<u8>, AssemblyError> {
@@ -256,6 +595,7 @@ fn read_binary_file(path: PathBuf) -> Result<Vec<u8>, AssemblyError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_dir::TestDir;
 
     #[test]
     fn test_pass1_command_data_fill() {
@@ -270,16 +610,7 @@ mod tests {
         let mut labels = HashMap::new();
         let mut pc = 0;
         let mut is_address_set = true;
-        let statement_clone = statement.clone();
-        let path = PathBuf::from(".");
-        let result = pass1(
-            &Line::new(0, 0, None, vec![statement], vec![]),
-            &statement_clone,
-            path,
-            &mut labels,
-            &mut pc,
-            &mut is_address_set,
-        );
+        let result = pass1(&statement, &mut labels, &mut pc, &mut is_address_set);
         assert!(result.is_ok());
         assert_eq!(pc, 12);
     }
@@ -297,7 +628,7 @@ mod tests {
         let labels = HashMap::new();
         let pc = 0;
         let path = PathBuf::from(".");
-        let result = pass2(&statement, &labels, &pc, path);
+        let result = pass2("", &statement, &labels, &pc, path);
         assert!(result.is_ok());
         let objects = result.unwrap();
         assert_eq!(objects.len(), 12);
@@ -317,16 +648,7 @@ mod tests {
         let mut labels = HashMap::new();
         let mut pc = 0;
         let mut is_address_set = true;
-        let path = PathBuf::from(".");
-        let statement_clone = statement.clone();
-        let result = pass1(
-            &Line::new(0, 0, None, vec![statement], vec![]),
-            &statement_clone,
-            path,
-            &mut labels,
-            &mut pc,
-            &mut is_address_set,
-        );
+        let result = pass1(&statement, &mut labels, &mut pc, &mut is_address_set);
         assert!(result.is_ok());
         assert_eq!(pc, 12 * 2);
     }
@@ -344,7 +666,7 @@ mod tests {
         let labels = HashMap::new();
         let pc = 0;
         let path = PathBuf::from(".");
-        let result = pass2(&statement, &labels, &pc, path);
+        let result = pass2("", &statement, &labels, &pc, path);
         assert!(result.is_ok());
         let objects = result.unwrap();
         assert_eq!(objects.len(), 12 * 2);
@@ -357,4 +679,232 @@ mod tests {
             assert_eq!(objects[i * 2 + 1], 0x12);
         }
     }
+
+    #[test]
+    fn test_pass1_command_typed_data() {
+        let expr = Expr::parse("reset,nmi,*+2").unwrap();
+        let statement = Statement::new(".word", expr.clone());
+        assert_eq!(pass1_command_typed_data(&statement, DataForm::Word), Ok(6));
+        let statement = Statement::new(".lobytes", expr);
+        assert_eq!(
+            pass1_command_typed_data(&statement, DataForm::LoBytes),
+            Ok(3)
+        );
+        let statement = Statement::new(".byte", Expr::parse("\"AB\",0").unwrap());
+        assert_eq!(pass1_command_typed_data(&statement, DataForm::Byte), Ok(3));
+    }
+
+    #[test]
+    fn test_pass2_command_split_tables() {
+        let mut labels = HashMap::new();
+        labels.insert(
+            "reset".to_string(),
+            LabelEntry {
+                name: "reset".to_string(),
+                line: 0,
+                address: Address::Full(0x8012),
+            },
+        );
+        let path = PathBuf::from(".");
+        let expr = Expr::parse("reset,*+1").unwrap();
+        let lo = Statement::new(".lobytes", expr.clone());
+        let hi = Statement::new(".hibytes", expr.clone());
+        let be = Statement::new(".dbyt", expr);
+        assert_eq!(
+            pass2("", &lo, &labels, &0x9000, path.clone()),
+            Ok(vec![0x12, 0x01])
+        );
+        assert_eq!(
+            pass2("", &hi, &labels, &0x9000, path.clone()),
+            Ok(vec![0x80, 0x90])
+        );
+        assert_eq!(
+            pass2("", &be, &labels, &0x9000, path),
+            Ok(vec![0x80, 0x12, 0x90, 0x01])
+        );
+    }
+
+    #[test]
+    fn test_pass2_command_byte_out_of_range() {
+        let statement = Statement::new(".byte", Expr::WordNum(0x1234));
+        let labels = HashMap::new();
+        let result = pass2("", &statement, &labels, &0, PathBuf::from("."));
+        assert!(result.is_err());
+        let statement = Statement::new(".byte", Expr::parse("0-129").unwrap());
+        assert!(pass2("", &statement, &labels, &0, PathBuf::from(".")).is_err());
+    }
+
+    #[test]
+    fn test_pass2_command_byte_negative() {
+        let statement = Statement::new(".byte", Expr::parse("0-1,0-128,255").unwrap());
+        let labels = HashMap::new();
+        assert_eq!(
+            pass2("", &statement, &labels, &0, PathBuf::from(".")),
+            Ok(vec![0xff, 0x80, 0xff])
+        );
+    }
+
+    fn write_test_file(name: &str, data: &[u8]) -> TestDir {
+        let dir = TestDir::new();
+        dir.write(name, data);
+        dir
+    }
+
+    #[test]
+    fn test_include_binary_slice() {
+        let dir = write_test_file("vtbase_slice.bin", &[0, 1, 2, 3, 4, 5, 6, 7]);
+        let labels = HashMap::new();
+        let expr = Expr::parse("\"vtbase_slice.bin\",2,4").unwrap();
+        let statement = Statement::new("&", expr);
+        assert_eq!(
+            pass1_command_include_binary(&statement, dir.path().into(), &labels, &0),
+            Ok(4)
+        );
+        assert_eq!(
+            pass2("", &statement, &labels, &0, dir.path().into()),
+            Ok(vec![2, 3, 4, 5])
+        );
+    }
+
+    #[test]
+    fn test_include_binary_transforms() {
+        let dir = write_test_file("vtbase_transform.bin", &[0x12, 0x34, 0x56]);
+        let labels = HashMap::new();
+        let expr = Expr::parse("\"vtbase_transform.bin\",1,XOR,$FF,SWAP,REV").unwrap();
+        let statement = Statement::new("&", expr);
+        assert_eq!(
+            pass2("", &statement, &labels, &0, dir.path().into()),
+            Ok(vec![0x9A, 0xBC])
+        );
+    }
+
+    #[test]
+    fn test_include_binary_forward_offset() {
+        let dir = write_test_file("vtbase_forward.bin", &[0; 16]);
+        let labels = HashMap::new();
+        let expr = Expr::parse("\"vtbase_forward.bin\",later,SWAP").unwrap();
+        let statement = Statement::new("&", expr);
+        assert_eq!(
+            pass1_command_include_binary(&statement, dir.path().into(), &labels, &0),
+            Err(AssemblyError::program("label not found"))
+        );
+    }
+
+    #[test]
+    fn test_include_binary_out_of_bounds() {
+        let dir = write_test_file("vtbase_bounds.bin", &[0; 16]);
+        let labels = HashMap::new();
+        let expr = Expr::parse("\"vtbase_bounds.bin\",8,9").unwrap();
+        let statement = Statement::new("&", expr);
+        assert!(pass1_command_include_binary(&statement, dir.path().into(), &labels, &0).is_err());
+        let expr = Expr::parse("\"vtbase_bounds.bin\",17").unwrap();
+        let statement = Statement::new("&", expr);
+        assert!(pass1_command_include_binary(&statement, dir.path().into(), &labels, &0).is_err());
+    }
+
+    #[test]
+    fn test_include_png_too_large() {
+        // 64x65 NES tiles of 16 bytes are 66560 bytes
+        let dir = TestDir::new();
+        dir.write_png("large.png", 512, 520, &vec![0; 512 * 520]);
+        let labels = HashMap::new();
+        let statement = Statement::new("&", Expr::parse("\"large.png\",NES").unwrap());
+        assert!(pass1_command_include_binary(&statement, dir.path().into(), &labels, &0).is_err());
+    }
+
+    #[test]
+    fn test_pass2_command_assert() {
+        let labels = HashMap::new();
+        let path = PathBuf::from(".");
+        let statement = Statement::new(".assert", Expr::parse("*<$FFFA,\"too big\"").unwrap());
+        assert_eq!(
+            pass2("main.vtl:1", &statement, &labels, &0xFFF0, path.clone()),
+            Ok(vec![])
+        );
+        assert_eq!(
+            pass2("main.vtl:12", &statement, &labels, &0xFFFA, path),
+            Err(AssemblyError::user(
+                "main.vtl:12",
+                "assertion failed: too big"
+            ))
+        );
+    }
+
+    fn table_labels() -> LabelTable {
+        let mut labels = HashMap::new();
+        for (name, address) in [("start", 0x8000), ("end", 0x8006)] {
+            let address = Address::Full(address);
+            let name = name.to_string();
+            labels.insert(
+                name.clone(),
+                LabelEntry {
+                    name,
+                    line: 1,
+                    address,
+                },
+            );
+        }
+        labels
+    }
+
+    // operators group from the right: end<start+4 == end<(start+4)
+    #[test]
+    fn test_pass2_command_assert_arithmetic() {
+        let labels = table_labels();
+        let path = PathBuf::from(".");
+        let statement = Statement::new(".assert", Expr::parse("end<start+4,\"table\"").unwrap());
+        assert_eq!(
+            pass2("main.vtl:5", &statement, &labels, &0x8000, path.clone()),
+            Err(AssemblyError::user("main.vtl:5", "assertion failed: table"))
+        );
+        let statement = Statement::new(".assert", Expr::parse("end<start+7").unwrap());
+        assert_eq!(
+            pass2("main.vtl:5", &statement, &labels, &0x8000, path),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_print_message_arithmetic() {
+        let labels = table_labels();
+        let expr = Expr::parse("\"size \",end-start").unwrap();
+        assert_eq!(
+            diagnostic_message(expr.traverse_comma(), &labels, &0x8000),
+            Ok("size $0006".to_string())
+        );
+        let expr = Expr::parse("end>start+4").unwrap();
+        assert_eq!(
+            diagnostic_message(expr.traverse_comma(), &labels, &0x8000),
+            Ok("$0001".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pass2_command_error_with_value() {
+        let labels = HashMap::new();
+        let statement = Statement::new(".error", Expr::parse("\"pc=\",*").unwrap());
+        assert_eq!(
+            pass2(
+                "main.vtl:3",
+                &statement,
+                &labels,
+                &0x8000,
+                PathBuf::from(".")
+            ),
+            Err(AssemblyError::user("main.vtl:3", "pc=$8000"))
+        );
+    }
+
+    #[test]
+    fn test_command_align() {
+        let labels = HashMap::new();
+        let statement = Statement::new(".align", Expr::parse("256,$FF").unwrap());
+        assert_eq!(command_align(&statement, &labels, &0x80F0), Ok((16, 0xff)));
+        assert_eq!(command_align(&statement, &labels, &0x8100), Ok((0, 0xff)));
+        let statement = Statement::new(".align", Expr::DecimalNum(4));
+        assert_eq!(
+            pass2("", &statement, &labels, &0x8001, PathBuf::from(".")),
+            Ok(vec![0, 0, 0])
+        );
+    }
 }
@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::path::Path;
+
+use crate::error::AssemblyError;
+
+// &="font.png",NES          -> 2bpp planar NES tiles (16 bytes per tile)
+// &="font.png",C64          -> 1bpp C64 characters (8 bytes per character)
+// &="font.png",NES,0,3,1,2  -> palette index 0..3 of the png become colour 0,3,1,2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileFormat {
+    Nes,
+    C64,
+}
+
+impl TileFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "NES" => Some(Self::Nes),
+            "C64" => Some(Self::C64),
+            _ => None,
+        }
+    }
+
+    fn colours(&self) -> usize {
+        match self {
+            Self::Nes => 4,
+            Self::C64 => 2,
+        }
+    }
+
+    // encode one 8x8 tile of colour numbers (row major)
+    fn encode(&self, tile: &[u8; 64], objects: &mut Vec<u8>) {
+        let row_bits = |row: usize, plane: usize| {
+            (0..8).fold(0u8, |acc, x| acc << 1 | ((tile[row * 8 + x] >> plane) & 1))
+        };
+        match self {
+            Self::Nes => {
+                objects.extend((0..8).map(|row| row_bits(row, 0)));
+                objects.extend((0..8).map(|row| row_bits(row, 1)));
+            }
+            Self::C64 => objects.extend((0..8).map(|row| row_bits(row, 0))),
+        }
+    }
+}
+
+struct IndexedImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl IndexedImage {
+    fn load(path: &Path) -> Result<Self, AssemblyError> {
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let mut reader = decoder.read_info().map_err(|e| png_error(path, e))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buf)
+            .map_err(|e| png_error(path, e))?;
+        if info.color_type != png::ColorType::Indexed {
+            return Err(AssemblyError::program(&format!(
+                "{} is not an indexed png ({:?})",
+                path.display(),
+                info.color_type
+            )));
+        }
+        let depth = info.bit_depth as usize;
+        let per_byte = 8 / depth;
+        let mask = ((1u16 << depth) - 1) as u8;
+        let (width, height) = (info.width as usize, info.height as usize);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = &buf[y * info.line_size..];
+            for x in 0..width {
+                let shift = 8 - depth * (x % per_byte + 1);
+                pixels.push((row[x / per_byte] >> shift) & mask);
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+fn png_error(path: &Path, error: png::DecodingError) -> AssemblyError {
+    AssemblyError::program(&format!("can't decode {}: {}", path.display(), error))
+}
+
+pub fn convert(path: &Path, format: TileFormat, mapping: &[u8]) -> Result<Vec<u8>, AssemblyError> {
+    let image = IndexedImage::load(path)?;
+    if image.width % 8 != 0 || image.height % 8 != 0 {
+        return Err(AssemblyError::program(&format!(
+            "image size {}x{} is not a multiple of 8",
+            image.width, image.height
+        )));
+    }
+    let mut objects = vec![];
+    for tile_y in 0..image.height / 8 {
+        for tile_x in 0..image.width / 8 {
+            let tile = map_tile(&image, tile_x, tile_y, format, mapping)?;
+            format.encode(&tile, &mut objects);
+        }
+    }
+    Ok(objects)
+}
+
+fn map_tile(
+    image: &IndexedImage,
+    tile_x: usize,
+    tile_y: usize,
+    format: TileFormat,
+    mapping: &[u8],
+) -> Result<[u8; 64], AssemblyError> {
+    let mut tile = [0u8; 64];
+    for (i, colour) in tile.iter_mut().enumerate() {
+        let (x, y) = (tile_x * 8 + i % 8, tile_y * 8 + i / 8);
+        let index = image.pixels[y * image.width + x];
+        *colour = if mapping.is_empty() {
+            index
+        } else {
+            *mapping.get(index as usize).ok_or_else(|| {
+                AssemblyError::program(&format!(
+                    "palette index {} at ({},{}) has no colour mapping",
+                    index, x, y
+                ))
+            })?
+        };
+    }
+    let mut used = tile.to_vec();
+    used.sort();
+    used.dedup();
+    if used.len() > format.colours() {
+        return Err(AssemblyError::program(&format!(
+            "tile ({},{}) has {} colours, {:?} allows {}",
+            tile_x,
+            tile_y,
+            used.len(),
+            format,
+            format.colours()
+        )));
+    }
+    if let Some(colour) = used.iter().find(|c| **c as usize >= format.colours()) {
+        return Err(AssemblyError::program(&format!(
+            "tile ({},{}) uses colour {}, {:?} allows 0..{}",
+            tile_x,
+            tile_y,
+            colour,
+            format,
+            format.colours() - 1
+        )));
+    }
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    #[test]
+    fn test_convert_nes_tile() {
+        // row 0 = colour 3, row 1 = colour 1, row 2 = colour 2, rest = 0
+        let mut pixels = vec![0u8; 64];
+        pixels[0..8].fill(3);
+        pixels[8..16].fill(1);
+        pixels[16..24].fill(2);
+        let dir = TestDir::new();
+        let path = dir.write_png("nes_tile.png", 8, 8, &pixels);
+        let objects = convert(&path, TileFormat::Nes, &[]).unwrap();
+        assert_eq!(
+            objects,
+            vec![
+                0xff, 0xff, 0, 0, 0, 0, 0, 0, // plane 0
+                0xff, 0, 0xff, 0, 0, 0, 0, 0, // plane 1
+            ]
+        );
+        let objects = convert(&path, TileFormat::Nes, &[0, 0, 0, 1]).unwrap();
+        assert_eq!(objects[0..8], [0xff, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(objects[8..16], [0; 8]);
+    }
+
+    #[test]
+    fn test_convert_c64_tile_order() {
+        // 16x8 image, left tile blank, right tile has its leftmost column set
+        let mut pixels = vec![0u8; 16 * 8];
+        for y in 0..8 {
+            pixels[y * 16 + 8] = 1;
+        }
+        let dir = TestDir::new();
+        let path = dir.write_png("c64_tiles.png", 16, 8, &pixels);
+        let objects = convert(&path, TileFormat::C64, &[]).unwrap();
+        assert_eq!(objects[0..8], [0; 8]);
+        assert_eq!(objects[8..16], [0x80; 8]);
+    }
+
+    #[test]
+    fn test_convert_too_many_colours() {
+        let pixels: Vec<u8> = (0..64).map(|i| (i % 5) as u8).collect();
+        let dir = TestDir::new();
+        let path = dir.write_png("colours.png", 8, 8, &pixels);
+        assert!(convert(&path, TileFormat::Nes, &[]).is_err());
+        assert!(convert(&path, TileFormat::Nes, &[0, 1, 2, 3, 3]).is_ok());
+    }
+}
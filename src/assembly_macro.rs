@@ -1,6 +1,18 @@
 use regex::Regex;
+use std::slice::Iter;
+
+mod block;
+mod condition;
+mod for_loop;
+mod multiply;
+mod procedure;
+mod switch;
+mod word;
+
+pub use condition::greater_warnings;
 
 use crate::{
+    assembler::{Address, LabelEntry, LabelTable},
     error::AssemblyError,
     parser::{
         expression::{Expr, Operator},
@@ -9,26 +21,136 @@ use crate::{
     },
 };
 
-pub fn expand(lines: &Vec<Line>) -> Result<Vec<Line>, AssemblyError> {
-    let mut result = Vec::new();
+// an open @ loop, label is the top and label.1 the exit
+struct Loop {
+    label: String,
+    kind: LoopKind,
+    line_number: usize,
+}
+
+enum LoopKind {
+    Do,
+    For(for_loop::Counter),
+    While,
+}
+
+impl Loop {
+    fn new(line: &Line, kind: LoopKind) -> Self {
+        Self {
+            label: generate_macro_identifier(),
+            kind,
+            line_number: line.line_number,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self.kind {
+            LoopKind::Do => "do",
+            LoopKind::For(_) => "for",
+            LoopKind::While => "while",
+        }
+    }
+
+    fn break_label(&self) -> String {
+        format!("{}.1", self.label)
+    }
+
+    // the condition of do / for loops, the top of while loops
+    fn continue_label(&self) -> String {
+        match self.kind {
+            LoopKind::While => self.label.clone(),
+            _ => format!("{}.2", self.label),
+        }
+    }
+}
+
+pub fn expand(lines: &[Line]) -> Result<Vec<Line>, AssemblyError> {
+    let (lines, contracts) = procedure::save_restore(lines)?;
     let mut stack = Vec::new();
-    for line in lines {
-        let lines = transform_line(line, &mut stack)?;
-        result.extend(lines);
+    let result = expand_lines(&mut lines.iter(), &known_constants(&lines), &mut stack)?;
+    if let Some(open) = stack.last() {
+        return Err(AssemblyError::Macro(format!(
+            "line: {} {} loop without end",
+            open.line_number,
+            open.name()
+        )));
+    }
+    for warning in procedure::check(&result, &contracts) {
+        eprintln!("{}", warning);
+    }
+    multiply::runtime(procedure::emit(result, &contracts))
+}
+
+fn expand_lines(
+    lines: &mut Iter<Line>,
+    constants: &LabelTable,
+    stack: &mut Vec<Loop>,
+) -> Result<Vec<Line>, AssemblyError> {
+    let mut result = Vec::new();
+    while let Some(line) = lines.next() {
+        let command = match line.statements.first() {
+            Some(statement) => statement.command()?,
+            None => String::new(),
+        };
+        match command.as_str() {
+            ".switch" => {
+                let switch = switch::collect(line, lines)?;
+                result.extend(switch.expand(constants, stack)?);
+            }
+            ".copy" | ".fill" => {
+                result.extend(block::expand(line, constants)?);
+                if line.statements.len() > 1 {
+                    let mut rest = line.clone();
+                    rest.label = None;
+                    rest.statements.remove(0);
+                    result.extend(transform_line(&rest, stack)?);
+                }
+            }
+            ".case" | ".default" | ".endswitch" => {
+                return Err(AssemblyError::Macro(format!(
+                    "line: {} {} without .switch",
+                    line.line_number, command
+                )))
+            }
+            _ => result.extend(transform_line(line, stack)?),
+        }
     }
     Ok(result)
 }
 
-fn transform_line(line: &Line, stack: &mut Vec<String>) -> Result<Vec<Line>, AssemblyError> {
+// label :=number (.enum members etc.), used to decide .switch tables
+fn known_constants(lines: &[Line]) -> LabelTable {
+    let mut constants = LabelTable::new();
+    for line in lines {
+        let (Some(label), Some(statement)) = (&line.label, line.statements.first()) else {
+            continue;
+        };
+        if statement.command().ok().as_deref() != Some(":") {
+            continue;
+        }
+        if let Ok(value) = statement.expression.evaluate(&constants, &0) {
+            let entry = LabelEntry {
+                name: label.clone(),
+                line: line.line_number,
+                address: Address::Full(value),
+            };
+            constants.insert(label.clone(), entry);
+        }
+    }
+    constants
+}
+
+fn transform_line(line: &Line, stack: &mut Vec<Loop>) -> Result<Vec<Line>, AssemblyError> {
     if line.statements.is_empty() {
         return Ok(vec![line.clone()]);
     }
     let statement = &line.statements[0];
     let command = statement.command()?;
     match command.as_str() {
-        ";" => transform_if_statement(line),
+        ";" => transform_if_statement(line, stack),
         "@" => transform_do_statement(line, stack),
-        _ => transform_statements(line),
+        "@;" => transform_while_statement(line, stack),
+        _ => transform_statements(line, stack),
     }
 }
 
@@ -45,7 +167,7 @@ fn transform_line(line: &Line, stack: &mut Vec<String>) -> Result<Vec<Line>, Ass
  *      A=A+1 Y=Y+1
  *  #macro_0.1
  */
-fn transform_if_statement(line: &Line) -> Result<Vec<Line>, AssemblyError> {
+fn transform_if_statement(line: &Line, loops: &[Loop]) -> Result<Vec<Line>, AssemblyError> {
     if !line.statements[0].check_macro_if_statement() {
         return Ok(vec![line.clone()]);
     }
@@ -56,13 +178,17 @@ fn transform_if_statement(line: &Line) -> Result<Vec<Line>, AssemblyError> {
     let trailer_label = format!("{}.1", label);
     let trailer = line.new_label(&trailer_label);
     result.push(header);
-    let lines = expand_if_statement(line, &trailer_label)?;
+    let lines = expand_if_statement(line, &trailer_label, loops)?;
     result.extend(lines);
     result.push(trailer);
     Ok(result)
 }
 
-fn expand_if_statement(line: &Line, macro_label: &str) -> Result<Vec<Line>, AssemblyError> {
+fn expand_if_statement(
+    line: &Line,
+    macro_label: &str,
+    loops: &[Loop],
+) -> Result<Vec<Line>, AssemblyError> {
     let mut result = vec![];
     let if_stmt = &line.statements[0];
     let cmd = if_stmt.command()?;
@@ -70,53 +196,10 @@ fn expand_if_statement(line: &Line, macro_label: &str) -> Result<Vec<Line>, Asse
     if cmd != ";" {
         return Err(AssemblyError::Macro("invalid command".to_string()));
     }
-    if let Expr::BinOp(lhs, op, rhs) = expr {
-        // 1st line
-        // T=X-10
-        let stmt1 = Statement::new("T", Expr::BinOp(lhs.clone(), Operator::Sub, rhs.clone()));
-        let inst1 = Line::new(line.line_number, line.address, None, vec![stmt1], vec![]);
-        result.push(inst1);
-        // 2nd line
-        // ;=<,#macro_1.1
-        let sysop = match op {
-            // 条件を逆にしてTHEN節をスキップする判定を行う
-            Operator::Equal => "/",
-            Operator::NotEqual => "=",
-            Operator::Less => ">",
-            Operator::Greater => "<",
-            _ => {
-                return Err(AssemblyError::Macro(format!(
-                    "{:?} expand_if_statement() invalid operator {:?}",
-                    line, op
-                )))
-            }
-        };
-        let lhs = Box::new(Expr::SystemOperator(sysop.to_string()));
-        let rhs = Box::new(Expr::Identifier(macro_label.to_string()));
-        let expr2 = Expr::BinOp(lhs, Operator::Comma, rhs);
-        let stmt2 = Statement::new(";", expr2);
-        let inst2 = Line::new(line.line_number, line.address, None, vec![stmt2], vec![]);
-        result.push(inst2);
-
-        // 3rd line
-        // A=A+1 Y=Y+1
-        let then_statements = line.statements[1..].to_vec();
-        let mut expanded_statements = vec![];
-        for statement in then_statements {
-            let stmt = transform_statement(&statement)?;
-            expanded_statements.extend(stmt);
-        }
-
-        let inst3 = Line::new(
-            line.line_number,
-            line.address,
-            None,
-            expanded_statements,
-            vec![],
-        );
-        result.push(inst3);
-    }
-
+    // T=X-10 ;=<,#macro_1.1 (条件を逆にしてTHEN節をスキップする)
+    result.extend(condition::branch_unless(line, expr, macro_label)?);
+    // A=A+1 Y=Y+1
+    result.extend(expand_statements(line, None, &line.statements[1..], loops)?);
     Ok(result)
 }
 
@@ -136,95 +219,192 @@ fn expand_if_statement(line: &Line, macro_label: &str) -> Result<Vec<Line>, Asse
  *     #=#macro_1
  * #macro_1.1
  */
-fn transform_do_statement(
-    line: &Line,
-    stack: &mut Vec<String>,
-) -> Result<Vec<Line>, AssemblyError> {
+fn transform_do_statement(line: &Line, stack: &mut Vec<Loop>) -> Result<Vec<Line>, AssemblyError> {
     let mut result = vec![];
     let statement = &line.statements[0];
+    if loop_jump(line, statement, stack)?.is_some() {
+        return transform_statements(line, stack);
+    }
     if statement.expression == Expr::Empty {
         // ループ開始行 @ の処理
-        let label = generate_macro_identifier();
-        stack.push(label.clone());
-        let label_line = line.new_label(&label);
-        result.push(label_line);
-        let rest = line.statements[1..].to_vec();
-        let rest_line = Line::new(line.line_number, line.address, None, rest, vec![]);
-        result.push(rest_line);
+        let top = Loop::new(line, LoopKind::Do);
+        result.push(line.new_label(&top.label));
+        stack.push(top);
+    } else if let Some((counter, init)) = for_loop::start(line, &statement.expression)? {
+        // ループ開始行 @=X=0,9 の処理
+        let top = Loop::new(line, LoopKind::For(counter));
+        result.push(line.derive(None, init));
+        result.push(line.new_label(&top.label));
+        stack.push(top);
     } else {
-        // ループ終了行 @=X>10 の処理
-        let label = stack
-            .pop()
-            .ok_or(AssemblyError::Macro("mismatch do loop".to_string()))?;
-        let lines = expand_do_statement(line, &label)?;
-        result.extend(lines);
-        let stmts = &line.statements[1..];
-        let rest_line = Line::new(line.line_number, line.address, None, stmts.to_vec(), vec![]);
-        result.push(rest_line);
+        // ループ終了行 @=X>10 / @=X+1 / @=@ の処理
+        result.extend(expand_loop_end(line, stack)?);
+    }
+    result.extend(expand_rest(line, stack)?);
+    Ok(result)
+}
+
+/**
+ * 元のステートメント
+ *   @;=X<10
+ *   A=A+1 X=+
+ *   @=@
+ *
+ * 展開形 (最初に条件を判定する)
+ * #macro_1
+ *     T=X-10
+ *     ;=>,#macro_1.1
+ *     A=A+1 X=+
+ *     #=#macro_1
+ * #macro_1.1
+ */
+fn transform_while_statement(
+    line: &Line,
+    stack: &mut Vec<Loop>,
+) -> Result<Vec<Line>, AssemblyError> {
+    let mut result = vec![];
+    let top = Loop::new(line, LoopKind::While);
+    result.push(line.new_label(&top.label));
+    let condition = &line.statements[0].expression;
+    result.extend(condition::branch_unless(
+        line,
+        condition,
+        &top.break_label(),
+    )?);
+    stack.push(top);
+    result.extend(expand_rest(line, stack)?);
+    Ok(result)
+}
+
+// statements after @ on the same line
+fn expand_rest(line: &Line, loops: &[Loop]) -> Result<Vec<Line>, AssemblyError> {
+    if line.statements.len() == 1 {
+        return Ok(vec![]);
+    }
+    expand_statements(line, None, &line.statements[1..], loops)
+}
+
+fn expand_loop_end(line: &Line, stack: &mut Vec<Loop>) -> Result<Vec<Line>, AssemblyError> {
+    let statement = &line.statements[0];
+    let expr = &statement.expression;
+    let end = match expr {
+        Expr::SystemOperator(op) if op == "@" => "while",
+        expr if for_loop::is_next(expr) => "for",
+        _ => "do",
+    };
+    let top = stack.pop().ok_or_else(|| {
+        AssemblyError::Macro(format!(
+            "line: {} mismatch {} loop: {} without a loop",
+            line.line_number, end, statement
+        ))
+    })?;
+    if top.name() != end {
+        return Err(AssemblyError::Macro(format!(
+            "line: {} mismatch {} loop (line {}): {} ends a {} loop",
+            line.line_number,
+            top.name(),
+            top.line_number,
+            statement,
+            end
+        )));
+    }
+    let mut result = vec![];
+    if !matches!(top.kind, LoopKind::While) {
+        result.push(line.new_label(&top.continue_label()));
+    }
+    match &top.kind {
+        LoopKind::Do => result.extend(expand_do_statement(line, &top.label)?),
+        LoopKind::For(counter) => {
+            result.extend(for_loop::next(line, counter, expr, &top.label)?);
+            result.push(line.new_label(&top.break_label()));
+        }
+        LoopKind::While => {
+            let jump = Statement::new("#", Expr::Identifier(top.label.clone()));
+            result.push(line.derive(None, vec![jump]));
+            result.push(line.new_label(&top.break_label()));
+        }
     }
     Ok(result)
 }
 
+// @=> (break) / @=< (continue) -> #=label of the innermost loop
+fn loop_jump(
+    line: &Line,
+    statement: &Statement,
+    loops: &[Loop],
+) -> Result<Option<Statement>, AssemblyError> {
+    let Expr::SystemOperator(op) = &statement.expression else {
+        return Ok(None);
+    };
+    if statement.command()? != "@" || (op != ">" && op != "<") {
+        return Ok(None);
+    }
+    let top = loops.last().ok_or_else(|| {
+        AssemblyError::Macro(format!(
+            "line: {} {} outside of a loop",
+            line.line_number, statement
+        ))
+    })?;
+    let label = if op == ">" {
+        top.break_label()
+    } else {
+        top.continue_label()
+    };
+    Ok(Some(Statement::new("#", Expr::Identifier(label))))
+}
+
 fn expand_do_statement(line: &Line, label: &str) -> Result<Vec<Line>, AssemblyError> {
     let mut result = vec![];
     let Statement {
         command: _,
         expression: expr,
     } = &line.statements[0];
-    if let Expr::BinOp(lhs, op, rhs) = expr {
-        // 1st line
-        // T=X-10
-        let stmt1 = Statement::new("T", Expr::BinOp(lhs.clone(), Operator::Sub, rhs.clone()));
-        let inst1 = Line::new(line.line_number, line.address, None, vec![stmt1], vec![]);
-        result.push(inst1);
-
-        // 2nd line
-        // ;=<,#macro_1.1
-        let sysop = match op {
-            // 条件を逆にしてループを抜ける判定を行う
-            Operator::Equal => "/",
-            Operator::NotEqual => "=",
-            Operator::Less => ">",
-            Operator::Greater => "<",
-            _ => {
-                return Err(AssemblyError::Macro(format!(
-                    "expand_do_statement() invalid operator {:?}",
-                    op
-                )))
-            }
-        };
-        let lhs = Box::new(Expr::SystemOperator(sysop.to_string()));
-        let next_label = format!("{}.1", label);
-        let rhs = Box::new(Expr::Identifier(next_label.clone()));
-        let expr2 = Expr::BinOp(lhs, Operator::Comma, rhs);
-        let stmt2 = Statement::new(";", expr2);
-        let inst2 = Line::new(line.line_number, line.address, None, vec![stmt2], vec![]);
-        result.push(inst2);
-        // 3rd line
-        // #=#macro_1
-        let stmt3 = Statement::new("#", Expr::Identifier(label.to_string()));
-        let inst3 = Line::new(line.line_number, line.address, None, vec![stmt3], vec![]);
-        result.push(inst3);
-        // 4th line
-        // #macro_1.1
-        let inst4 = line.new_label(next_label.as_str());
-        result.push(inst4);
-    }
-
+    // T=X-10 ;=<,#macro_1.1 (条件を逆にしてループを抜ける)
+    let next_label = format!("{}.1", label);
+    result.extend(condition::branch_unless(line, expr, &next_label)?);
+    // #=#macro_1
+    let stmt = Statement::new("#", Expr::Identifier(label.to_string()));
+    result.push(line.derive(None, vec![stmt]));
+    // #macro_1.1
+    result.push(line.new_label(next_label.as_str()));
     Ok(result)
 }
 
-fn transform_statements(line: &Line) -> Result<Vec<Line>, AssemblyError> {
-    let statements = &line.statements;
+fn transform_statements(line: &Line, loops: &[Loop]) -> Result<Vec<Line>, AssemblyError> {
+    expand_statements(line, line.label.clone(), &line.statements, loops)
+}
+
+// 16bit macros have labels of their own, so the line is split around them
+fn expand_statements(
+    line: &Line,
+    label: Option<String>,
+    statements: &[Statement],
+    loops: &[Loop],
+) -> Result<Vec<Line>, AssemblyError> {
     let mut result = vec![];
+    let mut current = line.derive(label, vec![]);
     for statement in statements {
-        let transformed_statements = transform_statement(statement)?;
-        result.extend(transformed_statements);
+        match word::expand(line, statement)? {
+            Some(lines) => {
+                if current.label.is_some() || !current.statements.is_empty() {
+                    result.push(current);
+                }
+                result.extend(lines);
+                current = line.derive(None, vec![]);
+            }
+            None => match loop_jump(line, statement, loops)? {
+                Some(jump) => current.statements.push(jump),
+                None => match multiply::expand(line, statement)? {
+                    Some(statements) => current.statements.extend(statements),
+                    None => current.statements.extend(transform_statement(statement)?),
+                },
+            },
+        }
     }
-
-    let mut inst = line.clone();
-    inst.statements = result;
-    Ok(vec![inst])
+    if current.label.is_some() || !current.statements.is_empty() || result.is_empty() {
+        result.push(current);
+    }
+    Ok(result)
 }
 
 fn transform_statement(statement: &Statement) -> Result<Vec<Statement>, AssemblyError> {
@@ -411,6 +591,45 @@ fn generate_macro_identifier() -> String {
     format!("#macro_{}", count)
 }
 
+// lines as "label statements", generated labels written #m
+#[cfg(test)]
+fn source(lines: &[Line]) -> Vec<String> {
+    let generated = Regex::new(r"#macro_\d+").unwrap();
+    lines
+        .iter()
+        .map(|line| {
+            let statements: Vec<String> = line.statements.iter().map(|s| s.to_string()).collect();
+            let text = format!(
+                "{} {}",
+                line.label.clone().unwrap_or_default(),
+                statements.join(" ")
+            );
+            generated.replace_all(&text, "#m").to_string()
+        })
+        .collect()
+}
+
+// one line for each item, parsed as the body of a source line
+// a first word that looks like a label is the label (a lone .directive is not)
+#[cfg(test)]
+fn source_lines(lines: &[&str]) -> Vec<Line> {
+    let label = Regex::new(r"^[.a-zA-Z][a-zA-Z0-9_]*( |$)").unwrap();
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let (label, body) = match label.find(line) {
+                Some(word) if !word.as_str().starts_with('.') || word.end() < line.len() => {
+                    (Some(word.as_str().trim().to_string()), &line[word.end()..])
+                }
+                _ => (None, *line),
+            };
+            let statements = crate::parser::parse_body(body).unwrap();
+            Line::new(i + 1, 0, label, statements, vec![])
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -440,6 +659,45 @@ mod tests {
         );
     }
 
+    // the lines expanded by expand(), generated labels written #m
+    fn loop_source(lines: &[&str]) -> Result<Vec<String>, AssemblyError> {
+        Ok(source(&expand(&source_lines(lines))?))
+    }
+
+    #[test]
+    fn test_loop_control() {
+        assert_eq!(
+            loop_source(&["@;=X<10", "X=+", "@=@"]).unwrap(),
+            vec!["#m ", " T=X-10 ;=>,#m.1", " X=+", " #=#m", "#m.1 "]
+        );
+        // break jumps to the exit, continue to the condition
+        assert_eq!(
+            loop_source(&["@=", "@=> @=<", "@=X<10"]).unwrap(),
+            vec![
+                "#m ",
+                " #=#m.1 #=#m.2",
+                "#m.2 ",
+                " T=X-10 ;=>,#m.1",
+                " #=#m",
+                "#m.1 "
+            ]
+        );
+        let continue_while = loop_source(&["@;=X<10", ";=X=5 @=<", "@=@"]).unwrap();
+        assert_eq!(continue_while[4], " #=#m");
+        let break_for = loop_source(&["@=Y=8,1", ";=A=0 @=>", "@=Y-1"]).unwrap();
+        assert_eq!(break_for[4], " #=#m.1");
+        assert_eq!(break_for.last().unwrap(), "#m.1 ");
+
+        assert!(loop_source(&["@=>"]).is_err());
+        assert!(loop_source(&["@="]).is_err());
+        assert!(loop_source(&["@;=X<10", "@=X<10"]).is_err());
+        assert!(loop_source(&["@=", "@=@"]).is_err());
+        assert!(loop_source(&["@=@"]).is_err());
+        // @=X+1 ends only a for loop
+        assert!(loop_source(&["@=", "@=X+1"]).is_err());
+        assert!(loop_source(&[".case=1"]).is_err());
+    }
+
     #[test]
     fn test_transform_inxx_statement() {
         let statement = Statement::new("X", Expr::SystemOperator("++".to_string()));
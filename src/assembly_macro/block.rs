@@ -0,0 +1,361 @@
+use super::{generate_macro_identifier, word};
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        statement::Statement,
+        Line,
+    },
+};
+
+const UNROLL: u16 = 4;
+
+/**
+ * ブロック転送 / 塗りつぶし (長さは定数)
+ *  .copy=dest,src,length[,pointer]     dest が (port) なら同じ番地へ書き続ける
+ *  .fill=dest,value,length[,pointer]   value が A なら A の値で埋める
+ *
+ *  4 バイトまで    A=(src) (dest)=A A=(src+1) (dest+1)=A ...
+ *  256 バイトまで  X=0 #l A=(src+X) (dest+X)=A X=+ T=X-length ;=\,#l  (256 なら比較を省く)
+ *  それより長いとき pointer (ゼロページ, src 用 2 バイト と dest 用 2 バイト) を使う
+ *    A=<src (pointer)=A A=>src (pointer+1)=A A=<dest (pointer+2)=A A=>dest (pointer+3)=A
+ *    X=pages Y=0 #l A=[pointer]+Y [pointer+2]+Y=A Y=+ ;=\,#l
+ *                   (pointer+1)=+ (pointer+3)=+ X=- ;=\,#l
+ *    #r A=[pointer]+Y [pointer+2]+Y=A Y=+ T=Y-rest ;=\,#r           (端数があるとき)
+ *  .fill=dest,A は pointer を設定する間 A をスタックに積む
+ *
+ * A, X, Y は壊れる
+ */
+enum Target {
+    Port(Expr),
+    Block(Expr),
+}
+
+enum Source {
+    Block(Expr),
+    Value(Expr),
+}
+
+enum Index {
+    Offset(u16),
+    X,
+    Pointer(Expr),
+}
+
+pub fn expand(line: &Line, constants: &LabelTable) -> Result<Vec<Line>, AssemblyError> {
+    let statement = &line.statements[0];
+    let command = statement.command()?;
+    let operands = statement.expression.traverse_comma();
+    let (target, source, length, pointer) = match operands.as_slice() {
+        [target, source, length] => (target, source, length, None),
+        [target, source, length, pointer] => (target, source, length, Some(pointer)),
+        _ => {
+            return Err(block_error(
+                line,
+                &format!("{}=dest,source,length[,pointer]", command),
+            ))
+        }
+    };
+    let target = match target {
+        Expr::Parenthesized(port) => Target::Port(*port.clone()),
+        address => Target::Block(address.clone()),
+    };
+    let source = if command == ".copy" {
+        Source::Block(source.clone())
+    } else {
+        Source::Value(source.clone())
+    };
+    let length = match length.evaluate(constants, &0) {
+        Ok(length) if length > 0 => length,
+        _ => {
+            return Err(block_error(
+                line,
+                &format!("the length of {} must be a constant over 0", command),
+            ))
+        }
+    };
+    let mut result = if length <= UNROLL {
+        unrolled(line, &target, &source, length)
+    } else if length <= 0x100 {
+        indexed(line, &target, &source, length)
+    } else {
+        let needs_pointer =
+            matches!(source, Source::Block(_)) || matches!(target, Target::Block(_));
+        if needs_pointer && pointer.is_none() {
+            return Err(block_error(
+                line,
+                &format!("{} over 256 bytes needs a zero page pointer", command),
+            ));
+        }
+        pointers(line, &target, &source, length, pointer)
+    };
+    result[0].label = line.label.clone();
+    Ok(result)
+}
+
+// A=(src) (dest)=A A=(src+1) (dest+1)=A ...
+fn unrolled(line: &Line, target: &Target, source: &Source, length: u16) -> Vec<Line> {
+    let mut statements: Vec<Statement> = load_value(source).into_iter().collect();
+    for offset in 0..length {
+        statements.extend(load(source, &Index::Offset(offset)));
+        statements.push(store(target, &Index::Offset(offset)));
+    }
+    vec![word::code_line(line, statements)]
+}
+
+// X=0 #l A=(src+X) (dest+X)=A X=+ T=X-length ;=\,#l
+fn indexed(line: &Line, target: &Target, source: &Source, length: u16) -> Vec<Line> {
+    let label = generate_macro_identifier();
+    let mut head: Vec<Statement> = load_value(source).into_iter().collect();
+    head.push(Statement::new("X", Expr::DecimalNum(0)));
+    let mut body: Vec<Statement> = load(source, &Index::X).into_iter().collect();
+    body.push(store(target, &Index::X));
+    body.push(Statement::new("X", Expr::SystemOperator("+".to_string())));
+    if length < 0x100 {
+        body.push(compare("X", length));
+    }
+    body.push(branch(&label));
+    vec![
+        word::code_line(line, head),
+        line.new_label(&label),
+        word::code_line(line, body),
+    ]
+}
+
+fn pointers(
+    line: &Line,
+    target: &Target,
+    source: &Source,
+    length: u16,
+    pointer: Option<&Expr>,
+) -> Vec<Line> {
+    // the source pointer comes first, the target one follows when both are used
+    let mut head = vec![];
+    let mut source_pointer = None;
+    let mut target_pointer = None;
+    let mut offset = 0;
+    if let (Source::Block(address), Some(pointer)) = (source, pointer) {
+        head.extend(set_pointer(pointer, offset, address));
+        source_pointer = Some(offset);
+        offset += 2;
+    }
+    if let (Target::Block(address), Some(pointer)) = (target, pointer) {
+        head.extend(set_pointer(pointer, offset, address));
+        target_pointer = Some(offset);
+    }
+    match load_value(source) {
+        Some(value) => head.push(value),
+        // .fill=dest,A keeps A on the stack while the pointer is set
+        None if matches!(source, Source::Value(_)) && !head.is_empty() => {
+            head.insert(0, Statement::new("[", Expr::Identifier("A".to_string())));
+            head.push(Statement::new("A", Expr::SystemOperator("]".to_string())));
+        }
+        None => {}
+    }
+    let at = |offset: u16| add(pointer.unwrap(), offset);
+    let source_index = source_pointer.map(|offset| Index::Pointer(at(offset)));
+    let target_index = match target_pointer {
+        Some(offset) => Index::Pointer(at(offset)),
+        None => Index::Offset(0),
+    };
+    let copy = || {
+        let mut statements: Vec<Statement> = source_index
+            .as_ref()
+            .and_then(|index| load(source, index))
+            .into_iter()
+            .collect();
+        statements.push(store(target, &target_index));
+        statements.push(Statement::new("Y", Expr::SystemOperator("+".to_string())));
+        statements
+    };
+    let page = generate_macro_identifier();
+    head.push(Statement::new("X", Expr::DecimalNum(length >> 8)));
+    head.push(Statement::new("Y", Expr::DecimalNum(0)));
+    let mut body = copy();
+    body.push(branch(&page));
+    for offset in [source_pointer, target_pointer].into_iter().flatten() {
+        body.push(Statement {
+            command: Expr::Parenthesized(Box::new(at(offset + 1))),
+            expression: Expr::SystemOperator("+".to_string()),
+        });
+    }
+    body.push(Statement::new("X", Expr::SystemOperator("-".to_string())));
+    body.push(branch(&page));
+    let mut result = vec![
+        word::code_line(line, head),
+        line.new_label(&page),
+        word::code_line(line, body),
+    ];
+    let rest = length & 0xff;
+    if rest > 0 {
+        let label = generate_macro_identifier();
+        let mut body = copy();
+        body.push(compare("Y", rest));
+        body.push(branch(&label));
+        result.push(line.new_label(&label));
+        result.push(word::code_line(line, body));
+    }
+    result
+}
+
+// A=value before the loop
+fn load_value(source: &Source) -> Option<Statement> {
+    match source {
+        Source::Value(Expr::Identifier(register)) if register == "A" => None,
+        Source::Value(value) => Some(Statement::new("A", value.clone())),
+        Source::Block(_) => None,
+    }
+}
+
+fn load(source: &Source, index: &Index) -> Option<Statement> {
+    match source {
+        Source::Block(address) => Some(Statement::new("A", access(address, index))),
+        Source::Value(_) => None,
+    }
+}
+
+fn store(target: &Target, index: &Index) -> Statement {
+    let command = match target {
+        Target::Port(port) => Expr::Parenthesized(Box::new(port.clone())),
+        Target::Block(address) => access(address, index),
+    };
+    Statement {
+        command,
+        expression: Expr::Identifier("A".to_string()),
+    }
+}
+
+// (address+offset) / (address+X) / [pointer]+Y
+fn access(address: &Expr, index: &Index) -> Expr {
+    match index {
+        Index::Offset(offset) => Expr::Parenthesized(Box::new(add(address, *offset))),
+        Index::X => Expr::Parenthesized(Box::new(Expr::BinOp(
+            Box::new(address.clone()),
+            Operator::Add,
+            Box::new(Expr::Identifier("X".to_string())),
+        ))),
+        Index::Pointer(pointer) => Expr::BinOp(
+            Box::new(Expr::Bracketed(Box::new(pointer.clone()))),
+            Operator::Add,
+            Box::new(Expr::Identifier("Y".to_string())),
+        ),
+    }
+}
+
+// A=<address (pointer)=A A=>address (pointer+1)=A
+fn set_pointer(pointer: &Expr, offset: u16, address: &Expr) -> Vec<Statement> {
+    let store = |offset| Statement {
+        command: Expr::Parenthesized(Box::new(add(pointer, offset))),
+        expression: Expr::Identifier("A".to_string()),
+    };
+    vec![
+        Statement::new("A", Expr::LoByte(Box::new(address.clone()))),
+        store(offset),
+        Statement::new("A", Expr::HiByte(Box::new(address.clone()))),
+        store(offset + 1),
+    ]
+}
+
+fn add(address: &Expr, offset: u16) -> Expr {
+    if offset == 0 {
+        return address.clone();
+    }
+    Expr::BinOp(
+        Box::new(address.clone()),
+        Operator::Add,
+        Box::new(Expr::DecimalNum(offset)),
+    )
+}
+
+// T=X-n / T=Y-n
+fn compare(register: &str, value: u16) -> Statement {
+    Statement::new(
+        "T",
+        Expr::BinOp(
+            Box::new(Expr::Identifier(register.to_string())),
+            Operator::Sub,
+            Box::new(Expr::DecimalNum(value)),
+        ),
+    )
+}
+
+// ;=\,label
+fn branch(label: &str) -> Statement {
+    Statement::new(
+        ";",
+        Expr::BinOp(
+            Box::new(Expr::SystemOperator("\\".to_string())),
+            Operator::Comma,
+            Box::new(Expr::Identifier(label.to_string())),
+        ),
+    )
+}
+
+fn block_error(line: &Line, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line.line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly_macro::{known_constants, source, source_lines};
+
+    // the last line expanded with the constants of the lines before it
+    fn block_source(lines: &[&str]) -> Result<Vec<String>, AssemblyError> {
+        let lines = source_lines(lines);
+        Ok(source(&expand(
+            lines.last().unwrap(),
+            &known_constants(&lines),
+        )?))
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            block_source(&["top .copy=buf,pal,2"]).unwrap(),
+            vec!["top A=(pal) (buf)=A A=(pal+1) (buf+1)=A"]
+        );
+        assert_eq!(
+            block_source(&[".fill=(io),A,3"]).unwrap(),
+            vec![" (io)=A (io)=A (io)=A"]
+        );
+        assert_eq!(
+            block_source(&["top .copy=(io),pal,32"]).unwrap(),
+            vec!["top X=0", "#m ", " A=(pal+X) (io)=A X=+ T=X-32 ;=\\,#m"]
+        );
+        // 256 bytes end when X wraps to 0
+        assert_eq!(
+            block_source(&["N :=256", ".fill=buf,0,N"]).unwrap(),
+            vec![" A=0 X=0", "#m ", " (buf+X)=A X=+ ;=\\,#m"]
+        );
+        assert_eq!(
+            block_source(&[".copy=buf,pal,$120,p"]).unwrap(),
+            vec![
+                " A=<pal (p)=A A=>pal (p+1)=A A=<buf (p+2)=A A=>buf (p+3)=A X=1 Y=0",
+                "#m ",
+                " A=[p]+Y [p+2]+Y=A Y=+ ;=\\,#m (p+1)=+ (p+3)=+ X=- ;=\\,#m",
+                "#m ",
+                " A=[p]+Y [p+2]+Y=A Y=+ T=Y-32 ;=\\,#m",
+            ]
+        );
+        assert_eq!(
+            block_source(&[".fill=buf,A,512,p"]).unwrap(),
+            vec![
+                " [=A A=<buf (p)=A A=>buf (p+1)=A A=] X=2 Y=0",
+                "#m ",
+                " [p]+Y=A Y=+ ;=\\,#m (p+1)=+ X=- ;=\\,#m",
+            ]
+        );
+        // a port needs no pointer
+        assert_eq!(
+            block_source(&[".fill=(io),0,512"]).unwrap(),
+            vec![" A=0 X=2 Y=0", "#m ", " (io)=A Y=+ ;=\\,#m X=- ;=\\,#m",]
+        );
+
+        assert!(block_source(&[".copy=buf,pal"]).is_err());
+        assert!(block_source(&[".copy=buf,pal,n"]).is_err());
+        assert!(block_source(&[".copy=buf,pal,0"]).is_err());
+        assert!(block_source(&[".copy=buf,pal,300"]).is_err());
+    }
+}
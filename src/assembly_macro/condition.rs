@@ -0,0 +1,317 @@
+use super::{generate_macro_identifier, word};
+use crate::{
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        statement::Statement,
+        Line,
+    },
+};
+
+/**
+ * IF / DO の条件 (条件が成り立たないとき target へ分岐する)
+ *  X<10              T=X-10 ;=>,target
+ *  X>=10             T=X-10 ;=<,target
+ *  X>10              T=X-10 ;==,target ;=<,target
+ *  X<=10             T=X-10 ;==,#over ;=>,target #over
+ *  signed(A)<n       C=1 A=AC-n ;=_,#v A=A^$80 #v ;=+,target    (N xor V)
+ *  word(p)<n         A=(p) T=A-<n A=(p+1) A=AC->n ;=>,target
+ *  word(p)>n         A=<n T=A-(p) A=>n A=AC-(p+1) ;=>,target    (n<p)
+ *  word(p)=n         A=(p) T=A-<n ;=/,target A=(p+1) T=A->n ;=/,target
+ *
+ * signed() は符号付き比較, word(p) は左辺のみ (A, X, Y は 8bit)
+ */
+enum Code {
+    Statement(Statement),
+    Label(String),
+}
+
+pub fn branch_unless(
+    line: &Line,
+    condition: &Expr,
+    target: &str,
+) -> Result<Vec<Line>, AssemblyError> {
+    let Expr::BinOp(lhs, op, rhs) = condition else {
+        return Err(condition_error(line, condition));
+    };
+    let (lhs, signed) = match lhs.as_ref() {
+        Expr::Function(name, args) if name == "signed" && args.len() == 1 => (&args[0], true),
+        lhs => (lhs, false),
+    };
+    let code = match (word::word_address(lhs), op) {
+        (Some(_), Operator::Equal | Operator::NotEqual) => {
+            word_equal(line, lhs, rhs, *op == Operator::Equal, target)?
+        }
+        (
+            Some(_),
+            Operator::Less | Operator::GreaterEqual | Operator::Greater | Operator::LessEqual,
+        ) => {
+            let (left, right) = (word::operand(lhs), word::operand(rhs));
+            // p>n is n<p and p<=n is n>=p
+            let (left, right) = match op {
+                Operator::Greater | Operator::LessEqual => (right, left),
+                _ => (left, right),
+            };
+            let mut code: Vec<Code> = word::compare(line, &left, &right)?
+                .into_iter()
+                .map(Code::Statement)
+                .collect();
+            let when_less = matches!(op, Operator::Less | Operator::Greater);
+            code.extend(unless_less(signed, when_less, target));
+            code
+        }
+        (None, Operator::Equal | Operator::NotEqual) => {
+            let condition = if *op == Operator::Equal { "/" } else { "=" };
+            vec![
+                Code::Statement(Statement::new(
+                    "T",
+                    Expr::BinOp(Box::new(lhs.clone()), Operator::Sub, rhs.clone()),
+                )),
+                branch(condition, target),
+            ]
+        }
+        (None, Operator::Less | Operator::GreaterEqual) => {
+            let mut code = subtract(lhs, rhs, signed);
+            code.extend(unless_less(signed, *op == Operator::Less, target));
+            code
+        }
+        (None, Operator::Greater) => {
+            let mut code = subtract(lhs, rhs, signed);
+            code.push(branch("=", target));
+            code.extend(unless_less(signed, false, target));
+            code
+        }
+        (None, Operator::LessEqual) => {
+            let over = generate_macro_identifier();
+            let mut code = subtract(lhs, rhs, signed);
+            code.push(branch("=", &over));
+            code.extend(unless_less(signed, true, target));
+            code.push(Code::Label(over));
+            code
+        }
+        _ => return Err(condition_error(line, condition)),
+    };
+    Ok(to_lines(line, code))
+}
+
+// migration: > was >= in IF / DO conditions before the strict comparisons,
+// every > comparison of the source (also .if and .assert) gets a warning unless --no-warn-gt
+pub fn greater_warnings(lines: &[Line]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for line in lines {
+        for statement in &line.statements {
+            let mut comparisons = Vec::new();
+            greater_comparisons(&statement.expression, &mut comparisons);
+            warnings.extend(comparisons.into_iter().map(|(lhs, rhs)| {
+                format!(
+                    "{} warning: {}>{} is now strictly greater, write {}>={} for the old meaning",
+                    line.location(),
+                    lhs,
+                    rhs,
+                    lhs,
+                    rhs
+                )
+            }));
+        }
+    }
+    warnings
+}
+
+fn greater_comparisons<'a>(expr: &'a Expr, result: &mut Vec<(&'a Expr, &'a Expr)>) {
+    match expr {
+        Expr::BinOp(lhs, op, rhs) => {
+            greater_comparisons(lhs, result);
+            if *op == Operator::Greater {
+                result.push((lhs, rhs));
+            }
+            greater_comparisons(rhs, result);
+        }
+        Expr::HiByte(expr)
+        | Expr::LoByte(expr)
+        | Expr::Parenthesized(expr)
+        | Expr::Bracketed(expr)
+        | Expr::Forced(_, expr) => greater_comparisons(expr, result),
+        Expr::Function(_, args) => args.iter().for_each(|arg| greater_comparisons(arg, result)),
+        _ => (),
+    }
+}
+
+// T=lhs-rhs (unsigned) / C=1 A=lhs A=AC-rhs (signed, the flags N and V are used)
+fn subtract(lhs: &Expr, rhs: &Expr, signed: bool) -> Vec<Code> {
+    if !signed {
+        let expr = Expr::BinOp(Box::new(lhs.clone()), Operator::Sub, Box::new(rhs.clone()));
+        return vec![Code::Statement(Statement::new("T", expr))];
+    }
+    let mut code = vec![Code::Statement(Statement::new("C", Expr::DecimalNum(1)))];
+    if *lhs != Expr::Identifier("A".to_string()) {
+        code.push(Code::Statement(Statement::new("A", lhs.clone())));
+    }
+    code.push(Code::Statement(Statement::new(
+        "A",
+        word::ac("-", rhs.clone()),
+    )));
+    code
+}
+
+// branch to target unless (left < right) == when_less
+fn unless_less(signed: bool, when_less: bool, target: &str) -> Vec<Code> {
+    if !signed {
+        return vec![branch(if when_less { ">" } else { "<" }, target)];
+    }
+    // N xor V: flip N when the subtraction overflowed
+    let no_overflow = generate_macro_identifier();
+    let flip = Expr::BinOp(
+        Box::new(Expr::Identifier("A".to_string())),
+        Operator::Eor,
+        Box::new(Expr::ByteNum(0x80)),
+    );
+    vec![
+        branch("_", &no_overflow),
+        Code::Statement(Statement::new("A", flip)),
+        Code::Label(no_overflow),
+        branch(if when_less { "+" } else { "-" }, target),
+    ]
+}
+
+fn word_equal(
+    line: &Line,
+    lhs: &Expr,
+    rhs: &Expr,
+    equal: bool,
+    target: &str,
+) -> Result<Vec<Code>, AssemblyError> {
+    let (left_low, left_high) = word::bytes(line, &word::operand(lhs))?;
+    let (right_low, right_high) = word::bytes(line, &word::operand(rhs))?;
+    let compare = |left: Expr, right: Expr| {
+        [
+            Code::Statement(Statement::new("A", left)),
+            Code::Statement(Statement::new(
+                "T",
+                Expr::BinOp(
+                    Box::new(Expr::Identifier("A".to_string())),
+                    Operator::Sub,
+                    Box::new(right),
+                ),
+            )),
+        ]
+    };
+    let mut code = Vec::new();
+    code.extend(compare(left_low, right_low));
+    if equal {
+        code.push(branch("/", target));
+        code.extend(compare(left_high, right_high));
+        code.push(branch("/", target));
+    } else {
+        let over = generate_macro_identifier();
+        code.push(branch("/", &over));
+        code.extend(compare(left_high, right_high));
+        code.push(branch("=", target));
+        code.push(Code::Label(over));
+    }
+    Ok(code)
+}
+
+// ;=condition,label
+fn branch(condition: &str, label: &str) -> Code {
+    Code::Statement(Statement::new(
+        ";",
+        Expr::BinOp(
+            Box::new(Expr::SystemOperator(condition.to_string())),
+            Operator::Comma,
+            Box::new(Expr::Identifier(label.to_string())),
+        ),
+    ))
+}
+
+fn to_lines(line: &Line, code: Vec<Code>) -> Vec<Line> {
+    let mut result = vec![word::code_line(line, vec![])];
+    for code in code {
+        match code {
+            Code::Statement(statement) => result.last_mut().unwrap().statements.push(statement),
+            Code::Label(label) => result.push(line.new_label(&label)),
+        }
+    }
+    result
+}
+
+fn condition_error(line: &Line, condition: &Expr) -> AssemblyError {
+    AssemblyError::Macro(format!(
+        "line: {} invalid condition: {}",
+        line.line_number, condition
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly_macro::{source, source_lines};
+
+    fn condition_source(condition: &str) -> Result<Vec<String>, AssemblyError> {
+        let line = Line::new(1, 0, None, vec![], vec![]);
+        Ok(source(&branch_unless(
+            &line,
+            &Expr::parse(condition)?,
+            "end",
+        )?))
+    }
+
+    #[test]
+    fn test_branch_unless() {
+        assert_eq!(condition_source("X<10").unwrap(), vec![" T=X-10 ;=>,end"]);
+        assert_eq!(condition_source("X>=10").unwrap(), vec![" T=X-10 ;=<,end"]);
+        assert_eq!(
+            condition_source("X>10").unwrap(),
+            vec![" T=X-10 ;==,end ;=<,end"]
+        );
+        assert_eq!(
+            condition_source("X<=10").unwrap(),
+            vec![" T=X-10 ;==,#m ;=>,end", "#m "]
+        );
+        assert_eq!(condition_source("A\\0").unwrap(), vec![" T=A-0 ;==,end"]);
+        assert_eq!(
+            condition_source("signed(A)<$10").unwrap(),
+            vec![" C=1 A=AC-$10 ;=_,#m A=A^$80", "#m ;=+,end"]
+        );
+        assert_eq!(
+            condition_source("signed(X)>3").unwrap(),
+            vec![" C=1 A=X A=AC-3 ;==,end ;=_,#m A=A^$80", "#m ;=-,end"]
+        );
+        assert_eq!(
+            condition_source("word(p)<word(q)").unwrap(),
+            vec![" A=(p) T=A-(q) A=(p+1) A=AC-(q+1) ;=>,end"]
+        );
+        // p>n is n<p
+        assert_eq!(
+            condition_source("word(p)>1000").unwrap(),
+            vec![" A=<1000 T=A-(p) A=>1000 A=AC-(p+1) ;=>,end"]
+        );
+        assert_eq!(
+            condition_source("signed(word(p))>=0").unwrap(),
+            vec![" A=(p) T=A-<0 A=(p+1) A=AC->0 ;=_,#m A=A^$80", "#m ;=-,end"]
+        );
+        assert_eq!(
+            condition_source("word(p)\\word(q)").unwrap(),
+            vec![" A=(p) T=A-(q) ;=/,#m A=(p+1) T=A-(q+1) ;==,end", "#m "]
+        );
+        assert!(condition_source("X+1").is_err());
+        assert!(condition_source("word(p)<A").is_err());
+    }
+
+    #[test]
+    fn test_greater_warnings() {
+        let lines = source_lines(&[
+            ";=A>9 A=0",
+            ".if=MODE>1",
+            ".assert=*<$FFFA",
+            ";=>,skip A=>",
+            ".assert=>table=>table_end",
+        ]);
+        assert_eq!(
+            greater_warnings(&lines),
+            vec![
+                "line: 1 warning: A>9 is now strictly greater, write A>=9 for the old meaning",
+                "line: 2 warning: MODE>1 is now strictly greater, write MODE>=1 for the old meaning",
+            ]
+        );
+    }
+}
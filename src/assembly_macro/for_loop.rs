@@ -0,0 +1,243 @@
+use super::word;
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        statement::Statement,
+        Line,
+    },
+};
+
+/**
+ * FOR ループ (GAME80 と同じく @=変数=初期値,終値 ... @=変数+増分)
+ *  @=Y=64,1            Y=64
+ *                     #macro_0
+ *    ...                ...
+ *  @=Y-1               Y=- ;=\,#macro_0              終値+増分 が 0 なら比較を省く
+ *
+ *  @=X=0,9 ... @=X+1           X=0 #l ... X=+ T=X-10 ;=\,#l
+ *  @=X=0,60 ... @=X+6          X=0 #l ... A=X C=0 A=AC+6 X=A T=X-66 ;=\,#l
+ *  @=(i)=0,30 ... @=(i)+3      A=0 (i)=A #l ... A=(i) C=0 A=AC+3 (i)=A T=A-33 ;=\,#l
+ *
+ * 変数は X, Y, (address)。変数が 終値+増分 になったときループを抜ける
+ * A を使う形 (X, Y の増分 4 以上、(address) の比較) は A と C を毎回壊す
+ */
+pub struct Counter {
+    variable: Expr,
+    first: Expr,
+    end: Expr,
+}
+
+// @=X=start,end -> the counter and the statements that set the start value
+pub fn start(line: &Line, expr: &Expr) -> Result<Option<(Counter, Vec<Statement>)>, AssemblyError> {
+    let Expr::BinOp(assign, Operator::Comma, end) = expr else {
+        return Ok(None);
+    };
+    let Expr::BinOp(variable, Operator::Equal, first) = assign.as_ref() else {
+        return Ok(None);
+    };
+    let init = if is_register(variable) {
+        vec![Statement {
+            command: *variable.clone(),
+            expression: *first.clone(),
+        }]
+    } else if let Expr::Parenthesized(_) = variable.as_ref() {
+        vec![
+            Statement::new("A", *first.clone()),
+            Statement {
+                command: *variable.clone(),
+                expression: Expr::Identifier("A".to_string()),
+            },
+        ]
+    } else {
+        return Err(for_error(
+            line,
+            "the loop variable must be X, Y or (address)",
+        ));
+    };
+    let counter = Counter {
+        variable: *variable.clone(),
+        first: *first.clone(),
+        end: *end.clone(),
+    };
+    Ok(Some((counter, init)))
+}
+
+// @=X+1 / @=X-1
+pub fn is_next(expr: &Expr) -> bool {
+    matches!(expr, Expr::BinOp(_, Operator::Add | Operator::Sub, _))
+}
+
+pub fn next(
+    line: &Line,
+    counter: &Counter,
+    expr: &Expr,
+    label: &str,
+) -> Result<Vec<Line>, AssemblyError> {
+    let Expr::BinOp(variable, op, step) = expr else {
+        return Err(for_error(line, "the end of a for loop is @=variable+step"));
+    };
+    if **variable != counter.variable {
+        return Err(for_error(
+            line,
+            &format!("@={} does not match the loop variable", expr),
+        ));
+    }
+    let step = match constant(step) {
+        Some(step @ 1..=0xff) => step,
+        _ => return Err(for_error(line, "the step must be a number 1..255")),
+    };
+    let up = *op == Operator::Add;
+    if let (Some(first), Some(end)) = (constant(&counter.first), constant(&counter.end)) {
+        let distance = if up {
+            end.wrapping_sub(first)
+        } else {
+            first.wrapping_sub(end)
+        };
+        if (distance & 0xff) % step != 0 {
+            return Err(for_error(
+                line,
+                &format!("{} never reaches {}", counter.variable, counter.end),
+            ));
+        }
+    }
+    let (mut statements, in_a) = step_statements(&counter.variable, up, step);
+    // the loop ends at end+step, the step itself sets Z when it is 0
+    let stop = Expr::BinOp(
+        Box::new(counter.end.clone()),
+        op.clone(),
+        Box::new(Expr::DecimalNum(step)),
+    );
+    let stop = match constant(&stop) {
+        Some(value) if value & 0xff == 0 => None,
+        Some(value) => Some(Expr::DecimalNum(value & 0xff)),
+        None => Some(Expr::LoByte(Box::new(stop))),
+    };
+    if let Some(stop) = stop {
+        let register = match &counter.variable {
+            Expr::Identifier(register) => register.clone(),
+            variable => {
+                if !in_a {
+                    statements.push(Statement::new("A", variable.clone()));
+                }
+                "A".to_string()
+            }
+        };
+        statements.push(Statement::new(
+            "T",
+            Expr::BinOp(
+                Box::new(Expr::Identifier(register)),
+                Operator::Sub,
+                Box::new(stop),
+            ),
+        ));
+    }
+    statements.push(Statement::new(
+        ";",
+        Expr::BinOp(
+            Box::new(Expr::SystemOperator("\\".to_string())),
+            Operator::Comma,
+            Box::new(Expr::Identifier(label.to_string())),
+        ),
+    ));
+    Ok(vec![word::code_line(line, statements)])
+}
+
+// X=+ (up to 3 times) / (i)=+ or the addition in A, and whether A holds the new value
+fn step_statements(variable: &Expr, up: bool, step: u16) -> (Vec<Statement>, bool) {
+    let (carry, op) = if up { (0, "+") } else { (1, "-") };
+    let modify = Statement {
+        command: variable.clone(),
+        expression: Expr::SystemOperator(op.to_string()),
+    };
+    if (is_register(variable) && step <= 3) || step == 1 {
+        return (vec![modify; step as usize], false);
+    }
+    let store = Statement {
+        command: variable.clone(),
+        expression: Expr::Identifier("A".to_string()),
+    };
+    let statements = vec![
+        Statement::new("A", variable.clone()),
+        Statement::new("C", Expr::DecimalNum(carry)),
+        Statement::new("A", word::ac(op, Expr::DecimalNum(step))),
+        store,
+    ];
+    (statements, true)
+}
+
+fn is_register(expr: &Expr) -> bool {
+    matches!(expr, Expr::Identifier(register) if register == "X" || register == "Y")
+}
+
+// numbers only, labels are not known yet
+fn constant(expr: &Expr) -> Option<u16> {
+    expr.evaluate(&LabelTable::new(), &0).ok()
+}
+
+fn for_error(line: &Line, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line.line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly_macro::source;
+
+    fn line() -> Line {
+        Line::new(1, 0, None, vec![], vec![])
+    }
+
+    // the start statements and the end of the loop @=header ... @=step
+    fn for_source(header: &str, step: &str) -> Result<(String, Vec<String>), AssemblyError> {
+        let (counter, init) = start(&line(), &Expr::parse(header)?)?.unwrap();
+        let init: Vec<String> = init.iter().map(|s| s.to_string()).collect();
+        let next = next(&line(), &counter, &Expr::parse(step)?, "top")?;
+        Ok((init.join(" "), source(&next)))
+    }
+
+    #[test]
+    fn test_for_loop() {
+        // counting down to 0 needs no compare
+        assert_eq!(
+            for_source("Y=64,1", "Y-1").unwrap(),
+            ("Y=64".to_string(), vec![" Y=- ;=\\,top".to_string()])
+        );
+        assert_eq!(
+            for_source("X=0,9", "X+1").unwrap().1,
+            vec![" X=+ T=X-10 ;=\\,top"]
+        );
+        assert_eq!(
+            for_source("X=0,10", "X+2").unwrap().1,
+            vec![" X=+ X=+ T=X-12 ;=\\,top"]
+        );
+        assert_eq!(
+            for_source("X=0,60", "X+6").unwrap().1,
+            vec![" A=X C=0 A=AC+6 X=A T=X-66 ;=\\,top"]
+        );
+        assert_eq!(
+            for_source("(i)=0,30", "(i)+6").unwrap(),
+            (
+                "A=0 (i)=A".to_string(),
+                vec![" A=(i) C=0 A=AC+6 (i)=A T=A-36 ;=\\,top".to_string()]
+            )
+        );
+        assert_eq!(
+            for_source("(i)=0,n", "(i)+1").unwrap().1,
+            vec![" (i)=+ A=(i) T=A-<n+1 ;=\\,top"]
+        );
+
+        // the end is not reached by the step
+        assert!(for_source("X=0,9", "X+2").is_err());
+        assert!(for_source("X=0,9", "Y+1").is_err());
+        assert!(for_source("X=0,9", "X+0").is_err());
+        assert!(for_source("A=0,9", "A+1").is_err());
+        // @=X<10 is the end of a do loop
+        assert!(start(&line(), &Expr::parse("X<10").unwrap())
+            .unwrap()
+            .is_none());
+        assert!(is_next(&Expr::parse("X+1").unwrap()));
+        assert!(!is_next(&Expr::parse("X<10").unwrap()));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        parse_body,
+        statement::Statement,
+        Line,
+    },
+};
+
+const TEMP: &str = "#temp";
+const MUL: &str = "#mul8";
+const DIV: &str = "#div8";
+
+/**
+ * 掛け算 / 割り算 (A のみ, 8bit)
+ *  A=A*4           A=< A=<
+ *  A=A*10          (#temp)=A A=< A=< C=0 A=AC+(#temp) A=<     (1 の立つビットが 3 個まで)
+ *  A=A/8           A=> A=> A=>
+ *  A=A*X           (#temp)=X !=#mul8       A=A*(n) / A=A*200 は X=(n) (#temp)=X !=#mul8
+ *  A=A/3           X=3 (#temp)=X !=#div8
+ *
+ *  .temp=$F0       作業用のゼロページ 2 バイト (#temp)
+ *  .runtime        使われたヘルパーを置く場所 (無ければソースの最後)
+ *
+ *  #mul8   A * (#temp) -> A (下位), (#temp+1) (上位)
+ *  #div8   A / (#temp) -> A (商), (#temp+1) (余り)     X は壊れる
+ */
+// labels of the helpers are written _name for #name
+const HELPERS: [(&str, &[(&str, &str)]); 2] = [
+    (
+        MUL,
+        &[
+            ("#mul8", "(_temp+1)=A A=0 X=8 (_temp)=>"),
+            ("#mul8_add", ";=<,_mul8_shift C=0 A=AC+(_temp+1)"),
+            ("#mul8_shift", "A=) (_temp)=) X=- ;=\\,_mul8_add"),
+            ("", "(_temp+1)=A A=(_temp) #=!"),
+        ],
+    ),
+    (
+        DIV,
+        &[
+            // the 9th bit of the remainder is the carry of A=(
+            ("#div8", "(_temp+1)=A A=0 X=8 (_temp+1)=<"),
+            ("#div8_loop", "A=( ;=>,_div8_sub T=A-(_temp) ;=<,_div8_next"),
+            ("#div8_sub", "A=AC-(_temp) C=1"),
+            ("#div8_next", "(_temp+1)=( X=- ;=\\,_div8_loop"),
+            ("", "X=(_temp+1) (_temp+1)=A A=X #=!"),
+        ],
+    ),
+];
+
+// None when the statement is not A=A*n / A=A/n
+pub fn expand(line: &Line, statement: &Statement) -> Result<Option<Vec<Statement>>, AssemblyError> {
+    let (Expr::Identifier(command), Expr::BinOp(lhs, op @ (Operator::Mul | Operator::Div), rhs)) =
+        (&statement.command, &statement.expression)
+    else {
+        return Ok(None);
+    };
+    if command != "A" || **lhs != Expr::Identifier("A".to_string()) {
+        return Ok(None);
+    }
+    let multiply = *op == Operator::Mul;
+    let statements = match rhs.evaluate(&LabelTable::new(), &0).ok() {
+        Some(n) if n > 0xff => {
+            return Err(multiply_error(line, &format!("{} is over 255", rhs)));
+        }
+        Some(0) if !multiply => return Err(multiply_error(line, "division by 0")),
+        Some(0) => vec![Statement::new("A", Expr::DecimalNum(0))],
+        Some(n) if n.is_power_of_two() => {
+            let shift = if multiply { "<" } else { ">" };
+            let shift = Statement::new("A", Expr::SystemOperator(shift.to_string()));
+            vec![shift; n.trailing_zeros() as usize]
+        }
+        Some(n) if multiply && n.count_ones() <= 3 => shift_add(n),
+        _ => call(rhs, if multiply { MUL } else { DIV }),
+    };
+    Ok(Some(statements))
+}
+
+// .temp is defined as #temp and the helpers in use are put at .runtime
+pub fn runtime(lines: Vec<Line>) -> Result<Vec<Line>, AssemblyError> {
+    let mut temp = None;
+    let mut place = None;
+    let mut first_use = None;
+    let mut used = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        for statement in &line.statements {
+            let command = statement.command().unwrap_or_default();
+            match command.as_str() {
+                ".temp" if temp.is_some() => {
+                    return Err(multiply_error(line, ".temp is defined twice"))
+                }
+                ".temp" => temp = Some(i),
+                ".runtime" => place = Some(i),
+                _ => {}
+            }
+            if mentions(&statement.command, TEMP) || mentions(&statement.expression, TEMP) {
+                first_use.get_or_insert(line.line_number);
+            }
+            if let (Expr::Identifier(callee), "!") = (&statement.expression, command.as_str()) {
+                if HELPERS.iter().any(|(name, _)| name == callee) && !used.contains(callee) {
+                    used.push(callee.clone());
+                }
+            }
+        }
+    }
+    if let (Some(line_number), None) = (first_use, temp) {
+        return Err(AssemblyError::Macro(format!(
+            "line: {} multiply / divide needs .temp=zero page address",
+            line_number
+        )));
+    }
+    let end = place.unwrap_or(lines.len());
+    let line_number = lines
+        .get(end)
+        .or(lines.last())
+        .map_or(0, |line| line.line_number);
+    let mut helpers = vec![];
+    for (name, code) in HELPERS {
+        if !used.iter().any(|used| used == name) {
+            continue;
+        }
+        for (label, body) in code {
+            let label = Some(label.to_string()).filter(|label| !label.is_empty());
+            helpers.push(Line::new(line_number, 0, label, helper_code(body)?, vec![]));
+        }
+    }
+    let mut result = vec![];
+    for (i, line) in lines.into_iter().enumerate() {
+        if Some(i) == temp {
+            result.extend(define_temp(&line)?);
+        } else if Some(i) == place {
+            result.extend(line.label.as_ref().map(|label| line.new_label(label)));
+            result.append(&mut helpers);
+        } else {
+            result.push(line);
+        }
+    }
+    result.append(&mut helpers);
+    Ok(result)
+}
+
+fn helper_code(body: &str) -> Result<Vec<Statement>, AssemblyError> {
+    let hidden = |expr: &Expr| -> Result<Option<Expr>, AssemblyError> {
+        Ok(match expr {
+            Expr::Identifier(name) if name.starts_with('_') => {
+                Some(Expr::Identifier(format!("#{}", &name[1..])))
+            }
+            _ => None,
+        })
+    };
+    parse_body(body)?
+        .iter()
+        .map(|statement| {
+            Ok(Statement {
+                command: statement.command.rewrite(&hidden)?,
+                expression: statement.expression.rewrite(&hidden)?,
+            })
+        })
+        .collect()
+}
+
+// (#temp)=A A=< A=< C=0 A=AC+(#temp) A=<
+fn shift_add(n: u16) -> Vec<Statement> {
+    let temp = Expr::Parenthesized(Box::new(Expr::Identifier(TEMP.to_string())));
+    let mut statements = vec![Statement {
+        command: temp.clone(),
+        expression: Expr::Identifier("A".to_string()),
+    }];
+    for bit in (0..15 - n.leading_zeros()).rev() {
+        statements.push(Statement::new("A", Expr::SystemOperator("<".to_string())));
+        if n & (1 << bit) != 0 {
+            statements.push(Statement::new("C", Expr::DecimalNum(0)));
+            statements.push(Statement::new(
+                "A",
+                Expr::BinOp(
+                    Box::new(Expr::Identifier("AC".to_string())),
+                    Operator::Add,
+                    Box::new(temp.clone()),
+                ),
+            ));
+        }
+    }
+    statements
+}
+
+// (#temp)=X !=#mul8 / X=n (#temp)=X !=#div8
+fn call(operand: &Expr, helper: &str) -> Vec<Statement> {
+    let mut statements = vec![];
+    let register = match operand {
+        Expr::Identifier(register) if register == "X" || register == "Y" => register.clone(),
+        operand => {
+            statements.push(Statement::new("X", operand.clone()));
+            "X".to_string()
+        }
+    };
+    statements.push(Statement {
+        command: Expr::Parenthesized(Box::new(Expr::Identifier(TEMP.to_string()))),
+        expression: Expr::Identifier(register),
+    });
+    statements.push(Statement::new("!", Expr::Identifier(helper.to_string())));
+    statements
+}
+
+// .temp=$F0 -> #temp :=$F0
+fn define_temp(line: &Line) -> Result<Vec<Line>, AssemblyError> {
+    if line.statements.len() > 1 {
+        return Err(multiply_error(line, ".temp must be alone on its line"));
+    }
+    let address = line.statements[0].expression.clone();
+    let mut result: Vec<Line> = line
+        .label
+        .iter()
+        .map(|label| line.new_label(label))
+        .collect();
+    result.push(line.derive(Some(TEMP.to_string()), vec![Statement::new(":", address)]));
+    Ok(result)
+}
+
+fn mentions(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::Identifier(identifier) => identifier == name,
+        Expr::HiByte(expr)
+        | Expr::LoByte(expr)
+        | Expr::Parenthesized(expr)
+        | Expr::Bracketed(expr)
+        | Expr::Forced(_, expr) => mentions(expr, name),
+        Expr::BinOp(left, _, right) => mentions(left, name) || mentions(right, name),
+        Expr::Function(_, args) => args.iter().any(|arg| mentions(arg, name)),
+        _ => false,
+    }
+}
+
+fn multiply_error(line: &Line, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line.line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly_macro::{source, source_lines};
+
+    fn multiply_source(token: &str) -> Result<Option<String>, AssemblyError> {
+        let line = &source_lines(&[token])[0];
+        let statements = expand(line, &line.statements[0])?;
+        Ok(statements.map(|statements| {
+            let statements: Vec<String> = statements.iter().map(|s| s.to_string()).collect();
+            statements.join(" ")
+        }))
+    }
+
+    #[test]
+    fn test_expand() {
+        let expanded = |token| multiply_source(token).unwrap().unwrap();
+        assert_eq!(expanded("A=A*8"), "A=< A=< A=<");
+        assert_eq!(expanded("A=A/4"), "A=> A=>");
+        assert_eq!(expanded("A=A*0"), "A=0");
+        assert_eq!(expanded("A=A*1"), "");
+        assert_eq!(expanded("A=A*10"), "(#temp)=A A=< A=< C=0 A=AC+(#temp) A=<");
+        assert_eq!(expanded("A=A*15"), "X=15 (#temp)=X !=#mul8");
+        assert_eq!(expanded("A=A*Y"), "(#temp)=Y !=#mul8");
+        assert_eq!(expanded("A=A/(n)"), "X=(n) (#temp)=X !=#div8");
+
+        assert_eq!(multiply_source("A=X*2").unwrap(), None);
+        assert_eq!(multiply_source("X=A*2").unwrap(), None);
+        assert!(multiply_source("A=A/0").is_err());
+        assert!(multiply_source("A=A*256").is_err());
+    }
+
+    #[test]
+    fn test_runtime() {
+        // the helpers in use are put at .runtime, .temp becomes #temp
+        let mut lines = source_lines(&[".temp=$F0", "", "#=!", ".runtime", "v ?=1"]);
+        lines[1].statements = expand(&lines[1], &parse_body("A=A/3").unwrap()[0])
+            .unwrap()
+            .unwrap();
+        let program = source(&runtime(lines).unwrap());
+        assert_eq!(
+            program,
+            vec![
+                "#temp :=$F0",
+                " X=3 (#temp)=X !=#div8",
+                " #=!",
+                "#div8 (#temp+1)=A A=0 X=8 (#temp+1)=<",
+                "#div8_loop A=( ;=>,#div8_sub T=A-(#temp) ;=<,#div8_next",
+                "#div8_sub A=AC-(#temp) C=1",
+                "#div8_next (#temp+1)=( X=- ;=\\,#div8_loop",
+                " X=(#temp+1) (#temp+1)=A A=X #=!",
+                "v ?=1",
+            ]
+        );
+        // without .runtime the helpers go to the end
+        let mut lines = source_lines(&[".temp=$F0", ""]);
+        lines[1].statements = expand(&lines[1], &parse_body("A=A*X").unwrap()[0])
+            .unwrap()
+            .unwrap();
+        let program = source(&runtime(lines).unwrap());
+        assert_eq!(program[2], "#mul8 (#temp+1)=A A=0 X=8 (#temp)=>");
+        assert_eq!(program.last().unwrap(), " (#temp+1)=A A=(#temp) #=!");
+
+        let mut lines = source_lines(&[""]);
+        lines[0].statements = expand(&lines[0], &parse_body("A=A*10").unwrap()[0])
+            .unwrap()
+            .unwrap();
+        assert!(runtime(lines).is_err());
+        assert!(runtime(source_lines(&[".temp=$F0", ".temp=$F2"])).is_err());
+        assert!(runtime(source_lines(&[".temp=$F0 A=0"])).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    opcode::Mnemonic::*,
+    parser::{expression::Expr, statement::Statement, Line},
+};
+
+const REGISTERS: [&str; 3] = ["A", "X", "Y"];
+const FLAGS: [&str; 4] = ["N", "V", "Z", "C"];
+
+/**
+ * .proc のレジスタ・フラグ規約
+ * draw     .proc
+ *          .save=A,X,Y         入口で積み, 戻る (#=! ] ^) 前に戻す (P も可)
+ *          .clobber=A,C        壊すレジスタ (A, X, Y の残りは保存を約束する) とフラグ
+ *          .preserve=X,C       積まずに保存を約束する (フラグ N V Z C も可)
+ *
+ *  入口    [=P [=A A=X [=A A=Y [=A       X, Y は A を通して積むので A も .save に書く
+ *  戻る前  A=] Y=A A=] X=A A=] P=]
+ *
+ * 約束したレジスタ・フラグへの書き込みと, それを壊す .proc の呼び出しを警告する
+ * 入口と戻る前のコードは警告を調べた後に入れる
+ */
+#[derive(Debug, Default)]
+pub struct Contract {
+    save: Vec<String>,
+    clobber: Option<Vec<String>>,
+    preserve: Vec<String>,
+}
+
+impl Contract {
+    fn saves(&self, register: &str) -> bool {
+        self.save.iter().any(|saved| saved == register)
+    }
+
+    // registers and flags the procedure promises not to change without saving them
+    fn promised(&self) -> Vec<String> {
+        let mut promised = self.preserve.clone();
+        if let Some(clobber) = &self.clobber {
+            promised.extend(
+                REGISTERS
+                    .iter()
+                    .map(|register| register.to_string())
+                    .filter(|register| !clobber.contains(register)),
+            );
+        }
+        // saving P keeps every flag
+        let saved =
+            |name: &String| self.saves(name) || self.saves("P") && FLAGS.contains(&name.as_str());
+        promised.retain(|name| !saved(name));
+        promised
+    }
+
+    // registers and flags a caller loses, None when nothing is declared
+    fn clobbered(&self) -> Option<Vec<String>> {
+        if self.clobber.is_none() && self.preserve.is_empty() {
+            return None;
+        }
+        let promised = self.promised();
+        let mut clobbered: Vec<String> = REGISTERS
+            .iter()
+            .map(|register| register.to_string())
+            .filter(|register| !promised.contains(register) && !self.saves(register))
+            .collect();
+        // flags are lost only when .clobber lists them
+        if !self.saves("P") {
+            let flags = self.clobber.iter().flatten();
+            clobbered.extend(flags.filter(|name| FLAGS.contains(&name.as_str())).cloned());
+        }
+        Some(clobbered)
+    }
+
+    // X and Y go through A, and pulling them changes N and Z
+    fn validate(&self, line: &Line) -> Result<(), AssemblyError> {
+        if (self.saves("X") || self.saves("Y")) && !self.saves("A") {
+            return Err(proc_error(
+                line,
+                ".save of X or Y goes through A, save A too (.save=A,X)",
+            ));
+        }
+        let pulls = REGISTERS.iter().any(|register| self.saves(register));
+        let flags = self
+            .preserve
+            .iter()
+            .find(|name| *name == "N" || *name == "Z");
+        match flags {
+            Some(flag) if pulls && !self.saves("P") => Err(proc_error(
+                line,
+                &format!("restoring the saved registers changes {}, save P too", flag),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn prologue(&self) -> Vec<Statement> {
+        let mut statements = vec![];
+        for register in ["P", "A", "X", "Y"] {
+            if !self.saves(register) {
+                continue;
+            }
+            if register == "X" || register == "Y" {
+                statements.push(Statement::new("A", Expr::Identifier(register.to_string())));
+            }
+            let pushed = if register == "P" { "P" } else { "A" };
+            statements.push(Statement::new("[", Expr::Identifier(pushed.to_string())));
+        }
+        statements
+    }
+
+    fn epilogue(&self) -> Vec<Statement> {
+        let mut statements = vec![];
+        for register in ["Y", "X", "A", "P"] {
+            if !self.saves(register) {
+                continue;
+            }
+            let pulled = if register == "P" { "P" } else { "A" };
+            statements.push(Statement::new(
+                pulled,
+                Expr::SystemOperator("]".to_string()),
+            ));
+            if register == "X" || register == "Y" {
+                statements.push(Statement::new(register, Expr::Identifier("A".to_string())));
+            }
+        }
+        statements
+    }
+}
+
+// contracts are removed, a .prologue marker is put after them and an .epilogue marker before each return
+pub fn save_restore(
+    lines: &[Line],
+) -> Result<(Vec<Line>, HashMap<String, Contract>), AssemblyError> {
+    let mut result = vec![];
+    let mut contracts = HashMap::new();
+    // .proc line, contract and whether the contract lines are still read
+    let mut current: Option<(Line, Contract, bool)> = None;
+    for line in lines {
+        let command = line
+            .statements
+            .first()
+            .and_then(|statement| statement.command().ok());
+        match (command.as_deref(), &mut current) {
+            (Some(".proc"), _) => {
+                let mut contract = Contract::default();
+                let mut header = line.clone();
+                header.statements = read_contract(line, &line.statements[1..], &mut contract)?;
+                header.statements.insert(0, line.statements[0].clone());
+                result.push(header);
+                current = Some((line.clone(), contract, true));
+                continue;
+            }
+            (Some(".endproc"), Some(_)) => {
+                let (proc, contract, header) = current.take().unwrap();
+                if header {
+                    result.extend(prologue_line(line, &proc, &contract)?);
+                }
+                contracts.insert(proc.label.unwrap_or_default(), contract);
+                result.push(line.clone());
+                continue;
+            }
+            _ => {}
+        }
+        let Some((proc, contract, header)) = &mut current else {
+            result.push(line.clone());
+            continue;
+        };
+        let statements = read_contract(line, &line.statements, contract)?;
+        if *header {
+            if statements.is_empty() && line.label.is_none() {
+                result.push(line.derive(None, vec![]));
+                continue;
+            }
+            *header = false;
+            result.extend(prologue_line(line, proc, contract)?);
+        } else if statements.len() != line.statements.len() {
+            return Err(proc_error(
+                line,
+                "register contracts must come before the code",
+            ));
+        }
+        let mut line = line.clone();
+        line.statements = vec![];
+        for statement in statements {
+            if is_return(&statement) && !contract.save.is_empty() {
+                line.statements.push(marker(".epilogue", proc));
+            }
+            line.statements.push(statement);
+        }
+        result.push(line);
+    }
+    Ok((result, contracts))
+}
+
+// the .prologue / .epilogue markers replaced with the save and restore code
+pub fn emit(lines: Vec<Line>, contracts: &HashMap<String, Contract>) -> Vec<Line> {
+    let code = |statement: &Statement| {
+        let contract = match &statement.expression {
+            Expr::Identifier(name) => contracts.get(name),
+            _ => None,
+        };
+        match (statement.command().unwrap_or_default().as_str(), contract) {
+            (".prologue", Some(contract)) => contract.prologue(),
+            (".epilogue", Some(contract)) => contract.epilogue(),
+            _ => vec![statement.clone()],
+        }
+    };
+    lines
+        .into_iter()
+        .map(|mut line| {
+            line.statements = line.statements.iter().flat_map(code).collect();
+            line
+        })
+        .collect()
+}
+
+// warnings for writes to promised registers and flags, and calls that clobber them
+pub fn check(lines: &[Line], contracts: &HashMap<String, Contract>) -> Vec<String> {
+    let mut warnings = vec![];
+    let mut current: Option<(&str, Vec<String>)> = None;
+    for line in lines {
+        for statement in &line.statements {
+            let command = statement.command().unwrap_or_default();
+            match command.as_str() {
+                ".proc" => {
+                    current = line.label.as_deref().and_then(|name| {
+                        contracts
+                            .get(name)
+                            .map(|contract| (name, contract.promised()))
+                    });
+                    continue;
+                }
+                ".endproc" => {
+                    current = None;
+                    continue;
+                }
+                _ => {}
+            }
+            let Some((name, promised)) = &current else {
+                continue;
+            };
+            let flags = match promised.iter().any(|name| FLAGS.contains(&name.as_str())) {
+                true => written_flags(statement),
+                false => "",
+            };
+            for written in promised {
+                if (*written == command && REGISTERS.contains(&command.as_str()))
+                    || flags.contains(written.as_str())
+                {
+                    warnings.push(format!(
+                        "line: {} warning: {} writes {} ({})",
+                        line.line_number, name, written, statement
+                    ));
+                }
+            }
+            if let (Expr::Identifier(callee), "!") = (&statement.expression, command.as_str()) {
+                let clobbered = contracts.get(callee).and_then(Contract::clobbered);
+                for register in clobbered.unwrap_or_default() {
+                    if promised.contains(&register) {
+                        warnings.push(format!(
+                            "line: {} warning: {} calls {} that clobbers {}",
+                            line.line_number, name, callee, register
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    warnings
+}
+
+// flags changed by the instruction of the statement
+fn written_flags(statement: &Statement) -> &'static str {
+    if statement.is_pseudo() || statement.command().unwrap_or_default().starts_with('.') {
+        return "";
+    }
+    let Ok((instruction, _)) = statement.decode_forward(&LabelTable::new()) else {
+        return "";
+    };
+    match instruction.mnemonic {
+        ADC | SBC | PLP | RTI => "NVZC",
+        ASL | LSR | ROL | ROR | CMP | CPX | CPY => "NZC",
+        BIT => "NVZ",
+        CLC | SEC => "C",
+        CLV => "V",
+        AND | EOR | ORA | LDA | LDX | LDY | INC | INX | INY | DEC | DEX | DEY | PLA | TAX | TAY
+        | TSX | TXA | TYA => "NZ",
+        _ => "",
+    }
+}
+
+// .save / .clobber / .preserve are read into the contract, the other statements are returned
+fn read_contract(
+    line: &Line,
+    statements: &[Statement],
+    contract: &mut Contract,
+) -> Result<Vec<Statement>, AssemblyError> {
+    let mut rest = vec![];
+    for statement in statements {
+        let command = statement.command()?;
+        let target = match command.as_str() {
+            ".save" => &mut contract.save,
+            ".clobber" => contract.clobber.get_or_insert_with(Vec::new),
+            ".preserve" => &mut contract.preserve,
+            _ => {
+                rest.push(statement.clone());
+                continue;
+            }
+        };
+        for register in statement.expression.traverse_comma() {
+            match register {
+                Expr::Identifier(name) if REGISTERS.contains(&name.as_str()) => target.push(name),
+                Expr::Identifier(name) if name == "P" && command == ".save" => target.push(name),
+                Expr::Identifier(name) if FLAGS.contains(&name.as_str()) && command != ".save" => {
+                    target.push(name)
+                }
+                _ => {
+                    return Err(proc_error(
+                        line,
+                        &format!(
+                            "{} takes A, X, Y (P for .save, N, V, Z, C for the others)",
+                            command
+                        ),
+                    ))
+                }
+            }
+        }
+    }
+    Ok(rest)
+}
+
+// #=! / ] / ^
+fn is_return(statement: &Statement) -> bool {
+    match (
+        statement.command().unwrap_or_default().as_str(),
+        &statement.expression,
+    ) {
+        ("#", Expr::SystemOperator(op)) => op == "!",
+        ("]" | "^", Expr::Empty) => true,
+        _ => false,
+    }
+}
+
+// the contract is complete here
+fn prologue_line(
+    line: &Line,
+    proc: &Line,
+    contract: &Contract,
+) -> Result<Option<Line>, AssemblyError> {
+    contract.validate(proc)?;
+    if contract.save.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(line.derive(None, vec![marker(".prologue", proc)])))
+}
+
+// .prologue=name / .epilogue=name
+fn marker(command: &str, proc: &Line) -> Statement {
+    let name = proc.label.clone().unwrap_or_default();
+    Statement::new(command, Expr::Identifier(name))
+}
+
+fn proc_error(line: &Line, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line.line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly_macro::{source, source_lines};
+
+    fn proc_source(lines: &[&str]) -> Result<Vec<String>, AssemblyError> {
+        let (lines, contracts) = save_restore(&source_lines(lines))?;
+        Ok(source(&emit(lines, &contracts)))
+    }
+
+    fn proc_warnings(lines: &[&str]) -> Vec<String> {
+        let (lines, contracts) = save_restore(&source_lines(lines)).unwrap();
+        check(&lines, &contracts)
+    }
+
+    #[test]
+    fn test_save_restore() {
+        assert_eq!(
+            proc_source(&[
+                "draw .proc .save=A,X,Y",
+                ".clobber=A",
+                "X=0",
+                "T=A-30 ;=<,skip #=!",
+                "skip ]",
+                ".endproc",
+            ])
+            .unwrap(),
+            vec![
+                "draw .proc",
+                " ",
+                " [=A A=X [=A A=Y [=A",
+                " X=0",
+                " T=A-30 ;=<,skip A=] Y=A A=] X=A A=] #=!",
+                "skip A=] Y=A A=] X=A A=] ]",
+                " .endproc",
+            ]
+        );
+        assert_eq!(
+            proc_source(&["f .proc", ".save=P,A", "^", ".endproc"]).unwrap(),
+            vec!["f .proc", " ", " [=P [=A", " A=] P=] ^", " .endproc"]
+        );
+        // nothing saved, nothing added
+        assert_eq!(
+            proc_source(&["f .proc .clobber=A,X", "]", ".endproc"]).unwrap(),
+            vec!["f .proc", " ]", " .endproc"]
+        );
+
+        assert!(proc_source(&["f .proc", "X=0", ".save=X", ".endproc"]).is_err());
+        assert!(proc_source(&["f .proc .save=S", ".endproc"]).is_err());
+        assert!(proc_source(&["f .proc .clobber=P", ".endproc"]).is_err());
+        assert!(proc_source(&["f .proc .save=C", ".endproc"]).is_err());
+        // X and Y are saved through A, and pulling A changes N and Z
+        assert!(proc_source(&["f .proc .save=X .clobber=Y", "]", ".endproc"]).is_err());
+        assert!(proc_source(&["f .proc .save=A .preserve=Z", "]", ".endproc"]).is_err());
+        assert!(proc_source(&["f .proc .save=P,A .preserve=Z", "]", ".endproc"]).is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        // the registers not clobbered are promised
+        assert_eq!(
+            proc_warnings(&["f .proc", ".clobber=A", "X=0", "A=0", "]", ".endproc"]),
+            vec!["line: 3 warning: f writes X (X=0)"]
+        );
+        assert_eq!(
+            proc_warnings(&[
+                "f .proc .clobber=A,X",
+                "]",
+                ".endproc",
+                "g .proc .preserve=X",
+                "!=f",
+                "]",
+                ".endproc",
+            ]),
+            vec!["line: 5 warning: g calls f that clobbers X"]
+        );
+        assert!(
+            proc_warnings(&["f .proc .save=A,X .preserve=X", "X=0", "]", ".endproc"]).is_empty()
+        );
+        // the save and restore code is not checked
+        assert!(
+            proc_warnings(&["f .proc .save=A,X .clobber=Y", "A=0", "]", ".endproc"]).is_empty()
+        );
+
+        // flags are promised by .preserve and lost by .clobber
+        assert_eq!(
+            proc_warnings(&["f .proc .preserve=C", "T=A-1", "X=0", "]", ".endproc"]),
+            vec!["line: 2 warning: f writes C (T=A-1)"]
+        );
+        assert_eq!(
+            proc_warnings(&[
+                "f .proc .clobber=A,C",
+                "]",
+                ".endproc",
+                "g .proc .preserve=C",
+                "!=f",
+                "]",
+                ".endproc",
+            ]),
+            vec!["line: 5 warning: g calls f that clobbers C"]
+        );
+        assert!(proc_warnings(&["f .proc .save=P .preserve=C", "C=1", "]", ".endproc"]).is_empty());
+    }
+}
@@ -0,0 +1,497 @@
+use std::slice::Iter;
+
+use super::{expand_lines, generate_macro_identifier, word, Loop};
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        statement::Statement,
+        Line,
+    },
+};
+
+/**
+ * SWITCH (A, X, Y の値で分岐する, case の終わりで .endswitch の次へ抜ける)
+ *          .switch=A
+ *          .case=0
+ *            ...
+ *          .case=1,2
+ *            ...
+ *          .default                どの case にも当てはまらないとき (省略可)
+ *            ...
+ *          .endswitch
+ *
+ * 比較の連鎖
+ *  #c0  T=A-0 ;=/,#c1                      #b0 ... #=#end
+ *  #c1  T=A-1 ;==,#b1 T=A-2 ;=/,#default   #b1 ... #=#end
+ *  #default ...
+ *  #end
+ *
+ * 4 個以上の定数の case が min..max の半分以上を埋めるときは表で飛ぶ (A は X を使う)
+ *  C=1 A=AC-min T=A-(max-min+1) ;=>,#default X=A   (min が 0 なら T=A-(max+1) のみ)
+ *  A=(#hi+X) [=A A=(#lo+X) [=A ]                   RTS は積んだアドレス+1 へ戻る
+ *  #hi .byte=>#b0-1,...  #lo .byte=<#b0-1,...
+ *
+ *  .switch=A,vector    A=(#lo+X) (vector)=A A=(#hi+X) (vector+1)=A #=[vector]
+ */
+pub struct Switch {
+    line: Line,
+    register: String,
+    vector: Option<Expr>,
+    cases: Vec<Case>,
+    default: Option<Vec<Line>>,
+}
+
+struct Case {
+    line: Line,
+    values: Vec<Expr>,
+    body: Vec<Line>,
+}
+
+// the lines up to the matching .endswitch, split into cases
+pub fn collect(header: &Line, lines: &mut Iter<Line>) -> Result<Switch, AssemblyError> {
+    let statement = &header.statements[0];
+    let (register, vector) = match statement.expression.traverse_comma().as_slice() {
+        [Expr::Identifier(register)] => (register.clone(), None),
+        [Expr::Identifier(register), vector] => (register.clone(), Some(vector.clone())),
+        _ => (String::new(), None),
+    };
+    if !matches!(register.as_str(), "A" | "X" | "Y") || header.statements.len() > 1 {
+        return Err(switch_error(
+            header,
+            ".switch takes A, X or Y (and a vector)",
+        ));
+    }
+    let mut switch = Switch {
+        line: header.clone(),
+        register,
+        vector,
+        cases: vec![],
+        default: None,
+    };
+    let mut depth = 0;
+    for line in lines.by_ref() {
+        let command = line
+            .statements
+            .first()
+            .and_then(|statement| statement.command().ok());
+        match (command.as_deref(), depth) {
+            (Some(".switch"), _) => depth += 1,
+            (Some(".endswitch"), 0) => return Ok(switch),
+            (Some(".endswitch"), _) => depth -= 1,
+            (Some(".case"), 0) => {
+                if switch.default.is_some() {
+                    return Err(switch_error(line, ".case after .default"));
+                }
+                let values = line.statements[0].expression.traverse_comma();
+                if values.contains(&Expr::Empty) || line.statements.len() > 1 {
+                    return Err(switch_error(line, ".case takes values: .case=1,2"));
+                }
+                switch.cases.push(Case {
+                    line: line.clone(),
+                    values,
+                    body: vec![],
+                });
+                continue;
+            }
+            (Some(".default"), 0) => {
+                if switch.default.is_some() {
+                    return Err(switch_error(line, ".default is defined twice"));
+                }
+                switch.default = Some(vec![]);
+                continue;
+            }
+            _ => {}
+        }
+        match (&mut switch.default, switch.cases.last_mut()) {
+            (Some(body), _) => body.push(line.clone()),
+            (None, Some(case)) => case.body.push(line.clone()),
+            (None, None) if line.statements.is_empty() => {}
+            (None, None) => return Err(switch_error(line, "code before the first .case")),
+        }
+    }
+    Err(switch_error(header, ".switch without .endswitch"))
+}
+
+impl Switch {
+    // constants are the := values known before assembly (.enum members etc.)
+    pub fn expand(
+        &self,
+        constants: &LabelTable,
+        stack: &mut Vec<Loop>,
+    ) -> Result<Vec<Line>, AssemblyError> {
+        let end = generate_macro_identifier();
+        let default = generate_macro_identifier();
+        let bodies: Vec<String> = self
+            .cases
+            .iter()
+            .map(|_| generate_macro_identifier())
+            .collect();
+        let miss = if self.default.is_some() {
+            &default
+        } else {
+            &end
+        };
+        let table = self.table(constants);
+        let mut result = match (&table, &self.line.label) {
+            (Some(table), _) => self.dispatch(table, &bodies, miss),
+            (None, Some(label)) => vec![self.line.new_label(label)],
+            (None, None) => vec![],
+        };
+        for (i, case) in self.cases.iter().enumerate() {
+            if table.is_none() {
+                // T=A-1 ;==,#b1 T=A-2 ;=/,#c2
+                let next = match bodies.get(i + 1) {
+                    Some(_) => format!("{}.1", bodies[i + 1]),
+                    None => miss.clone(),
+                };
+                let mut statements = vec![];
+                for (j, value) in case.values.iter().enumerate() {
+                    statements.push(compare(&self.register, value));
+                    if j + 1 < case.values.len() {
+                        statements.push(branch("=", &bodies[i]));
+                    } else {
+                        statements.push(branch("/", &next));
+                    }
+                }
+                result.push(case.line.new_label(&format!("{}.1", bodies[i])));
+                result.push(word::code_line(&case.line, statements));
+            }
+            result.push(case.line.new_label(&bodies[i]));
+            result.extend(self.body(&case.line, &case.body, constants, stack)?);
+            let last = i + 1 == self.cases.len() && self.default.is_none();
+            if !last {
+                let jump = Statement::new("#", Expr::Identifier(end.clone()));
+                result.push(word::code_line(&case.line, vec![jump]));
+            }
+        }
+        if let Some(body) = &self.default {
+            result.push(self.line.new_label(&default));
+            result.extend(self.body(&self.line, body, constants, stack)?);
+        }
+        result.push(self.line.new_label(&end));
+        Ok(result)
+    }
+
+    // (min, value of each index) when the case values are dense enough for a table
+    fn table(&self, constants: &LabelTable) -> Option<(u16, Vec<Option<usize>>)> {
+        let mut values = vec![];
+        for (i, case) in self.cases.iter().enumerate() {
+            for value in &case.values {
+                let value = value.evaluate(constants, &0).ok().filter(|v| *v <= 0xff)?;
+                values.push((value, i));
+            }
+        }
+        let min = values.iter().map(|(value, _)| *value).min()?;
+        let max = values.iter().map(|(value, _)| *value).max()?;
+        let span = (max - min + 1) as usize;
+        if values.len() < 4 || span > values.len() * 2 {
+            return None;
+        }
+        let mut table = vec![None; span];
+        for (value, i) in values {
+            table[(value - min) as usize].get_or_insert(i);
+        }
+        Some((min, table))
+    }
+
+    fn dispatch(
+        &self,
+        (min, table): &(u16, Vec<Option<usize>>),
+        bodies: &[String],
+        miss: &str,
+    ) -> Vec<Line> {
+        let hi = generate_macro_identifier();
+        let lo = generate_macro_identifier();
+        let accumulator = || Expr::Identifier("A".to_string());
+        let span = table.len() as u16;
+        let index = if self.register == "Y" { "Y" } else { "X" };
+        let mut statements = vec![];
+        // value-min, values below min wrap around and fail the range check
+        let register = if *min > 0 {
+            if self.register != "A" {
+                statements.push(Statement::new("A", Expr::Identifier(self.register.clone())));
+            }
+            statements.push(Statement::new("C", Expr::DecimalNum(1)));
+            statements.push(Statement::new("A", word::ac("-", Expr::DecimalNum(*min))));
+            "A"
+        } else {
+            self.register.as_str()
+        };
+        if span < 0x100 {
+            statements.push(compare(register, &Expr::DecimalNum(span)));
+            statements.push(branch(">", miss));
+        }
+        if register == "A" {
+            statements.push(Statement::new(index, accumulator()));
+        }
+        let entry = |table: &str| {
+            Statement::new(
+                "A",
+                Expr::Parenthesized(Box::new(Expr::BinOp(
+                    Box::new(Expr::Identifier(table.to_string())),
+                    Operator::Add,
+                    Box::new(Expr::Identifier(index.to_string())),
+                ))),
+            )
+        };
+        match &self.vector {
+            Some(vector) => {
+                let high = Expr::BinOp(
+                    Box::new(vector.clone()),
+                    Operator::Add,
+                    Box::new(Expr::DecimalNum(1)),
+                );
+                let store = |address: Expr| Statement {
+                    command: Expr::Parenthesized(Box::new(address)),
+                    expression: accumulator(),
+                };
+                statements.extend([
+                    entry(&lo),
+                    store(vector.clone()),
+                    entry(&hi),
+                    store(high),
+                    Statement::new("#", Expr::Bracketed(Box::new(vector.clone()))),
+                ]);
+            }
+            // RTS returns to the pushed address + 1
+            None => statements.extend([
+                entry(&hi),
+                Statement::new("[", accumulator()),
+                entry(&lo),
+                Statement::new("[", accumulator()),
+                Statement::new("]", Expr::Empty),
+            ]),
+        }
+        let addresses: Vec<Expr> = table
+            .iter()
+            .map(|entry| {
+                let label = match entry {
+                    Some(i) => &bodies[*i],
+                    None => miss,
+                };
+                let label = Expr::Identifier(label.to_string());
+                match self.vector {
+                    Some(_) => label,
+                    None => Expr::BinOp(
+                        Box::new(label),
+                        Operator::Sub,
+                        Box::new(Expr::DecimalNum(1)),
+                    ),
+                }
+            })
+            .collect();
+        let data = |label: &str, byte: fn(Box<Expr>) -> Expr| {
+            let values = addresses
+                .iter()
+                .map(|address| byte(Box::new(address.clone())))
+                .reduce(|list, value| Expr::BinOp(Box::new(list), Operator::Comma, Box::new(value)))
+                .unwrap();
+            let statement = Statement {
+                command: Expr::Identifier(".byte".to_string()),
+                expression: values,
+            };
+            self.line.derive(Some(label.to_string()), vec![statement])
+        };
+        let mut code = word::code_line(&self.line, statements);
+        code.label = self.line.label.clone();
+        vec![code, data(&hi, Expr::HiByte), data(&lo, Expr::LoByte)]
+    }
+
+    // a case body may not leave a loop open
+    fn body(
+        &self,
+        line: &Line,
+        body: &[Line],
+        constants: &LabelTable,
+        stack: &mut Vec<Loop>,
+    ) -> Result<Vec<Line>, AssemblyError> {
+        let depth = stack.len();
+        let lines = expand_lines(&mut body.iter(), constants, stack)?;
+        if stack.len() != depth {
+            return Err(switch_error(line, "a loop crosses .case"));
+        }
+        Ok(lines)
+    }
+}
+
+// T=A-n / T=X-n / T=Y-n
+fn compare(register: &str, value: &Expr) -> Statement {
+    Statement::new(
+        "T",
+        Expr::BinOp(
+            Box::new(Expr::Identifier(register.to_string())),
+            Operator::Sub,
+            Box::new(value.clone()),
+        ),
+    )
+}
+
+// ;=condition,label
+fn branch(condition: &str, label: &str) -> Statement {
+    Statement::new(
+        ";",
+        Expr::BinOp(
+            Box::new(Expr::SystemOperator(condition.to_string())),
+            Operator::Comma,
+            Box::new(Expr::Identifier(label.to_string())),
+        ),
+    )
+}
+
+fn switch_error(line: &Line, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line.line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::assembly_macro::{expand, known_constants, source, source_lines};
+    use crate::simulator::{Cpu, Stop};
+    use std::path::PathBuf;
+
+    // the .switch on the first line after the constants
+    fn switch_source(lines: &[&str]) -> Result<Vec<String>, AssemblyError> {
+        let lines = source_lines(lines);
+        let constants = known_constants(&lines);
+        let start = lines.iter().position(|line| line.label.is_none()).unwrap();
+        let mut rest = lines[start + 1..].iter();
+        let switch = collect(&lines[start], &mut rest)?;
+        Ok(source(&switch.expand(&constants, &mut vec![])?))
+    }
+
+    #[test]
+    fn test_compare_chain() {
+        assert_eq!(
+            switch_source(&[
+                ".switch=X",
+                ".case=1",
+                "A=1",
+                ".case=5,9",
+                "A=2",
+                ".default",
+                "A=3",
+                ".endswitch",
+            ])
+            .unwrap(),
+            vec![
+                "#m.1 ",
+                " T=X-1 ;=/,#m.1",
+                "#m ",
+                " A=1",
+                " #=#m",
+                "#m.1 ",
+                " T=X-5 ;==,#m T=X-9 ;=/,#m",
+                "#m ",
+                " A=2",
+                " #=#m",
+                "#m ",
+                " A=3",
+                "#m "
+            ]
+        );
+        // the last case falls through to the end
+        assert_eq!(
+            switch_source(&[".switch=A", ".case=0", "X=0", ".endswitch"]).unwrap(),
+            vec!["#m.1 ", " T=A-0 ;=/,#m", "#m ", " X=0", "#m "]
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        // dense values use a table of the addresses - 1 for RTS
+        assert_eq!(
+            switch_source(&[
+                "size :=3",
+                ".switch=A",
+                ".case=1,2",
+                ".case=3",
+                ".case=size+1",
+                ".endswitch",
+            ])
+            .unwrap(),
+            vec![
+                " C=1 A=AC-1 T=A-4 ;=>,#m X=A A=(#m+X) [=A A=(#m+X) [=A ]",
+                "#m .byte=>#m-1,>#m-1,>#m-1,>#m-1",
+                "#m .byte=<#m-1,<#m-1,<#m-1,<#m-1",
+                "#m ",
+                " #=#m",
+                "#m ",
+                " #=#m",
+                "#m ",
+                "#m ",
+            ]
+        );
+        assert_eq!(
+            switch_source(&[
+                ".switch=Y,vec",
+                ".case=0",
+                ".case=1",
+                ".case=2",
+                ".case=3",
+                ".endswitch",
+            ])
+            .unwrap()[..3],
+            vec![
+                " T=Y-4 ;=>,#m A=(#m+Y) (vec)=A A=(#m+Y) (vec+1)=A #=[vec]",
+                "#m .byte=>#m,>#m,>#m,>#m",
+                "#m .byte=<#m,<#m,<#m,<#m",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_errors() {
+        assert!(switch_source(&[".switch=A", ".case=1"]).is_err());
+        assert!(switch_source(&[".switch=A", "A=1", ".case=1", ".endswitch"]).is_err());
+        assert!(switch_source(&[".switch=S", ".endswitch"]).is_err());
+        assert!(switch_source(&[".switch=A", ".default", ".case=1", ".endswitch"]).is_err());
+        assert!(switch_source(&[".switch=A", ".default", ".default", ".endswitch"]).is_err());
+        assert!(switch_source(&[".switch=A", ".case=1", "@=", ".case=2", ".endswitch"]).is_err());
+    }
+
+    // assembled at $0200 and called with A, Y after the return
+    fn run_switch(header: &str, a: u8) -> u8 {
+        let mut lines = expand(&source_lines(&[
+            "*=$0200",
+            "vec :=$F0",
+            header,
+            ".case=0",
+            "Y=10",
+            ".case=1",
+            "Y=11",
+            ".case=2",
+            "Y=12",
+            ".case=3",
+            "Y=13",
+            ".default",
+            "Y=99",
+            ".endswitch",
+            "#=!",
+        ]))
+        .unwrap();
+        let mut assembler = Assembler::new(PathBuf::from("."));
+        assembler.assemble(&mut lines).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load(&lines);
+        cpu.a = a;
+        cpu.call(0x0200);
+        assert_eq!(cpu.run(1000), Ok(Stop::Return), "{}", header);
+        cpu.y
+    }
+
+    #[test]
+    fn test_switch_assembled() {
+        for header in [
+            ".switch=A",
+            ".switch=A,vec",
+            ".switch=A,$F0",
+            ".switch=A,$0300",
+        ] {
+            let results: Vec<u8> = (0..6).map(|a| run_switch(header, a)).collect();
+            assert_eq!(results, [10, 11, 12, 13, 99, 99], "{}", header);
+        }
+    }
+}
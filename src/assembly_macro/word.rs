@@ -0,0 +1,345 @@
+use super::generate_macro_identifier;
+use crate::{
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        statement::Statement,
+        Line,
+    },
+};
+
+/**
+ * 16bit 変数 (下位, 上位の順の 2 バイト) のマクロ
+ *  word(p)=$1234           A=<$1234 (p)=A A=>$1234 (p+1)=A
+ *  word(p)=word(q)         A=(q) (p)=A A=(q+1) (p+1)=A
+ *  word(p)=word(q)+n       C=0 A=(q) A=AC+<n (p)=A A=(q+1) A=AC+>n (p+1)=A
+ *  word(p)=word(p)+A       C=0 A=AC+(p) (p)=A ;=<,#skip (p+1)=+ #skip
+ *  word(p)=+               (p)=+ ;=\,#skip (p+1)=+ #skip
+ *  word(p)=-               A=(p) ;=\,#skip (p+1)=- #skip (p)=-
+ *  T=word(p)-word(q)       A=(p) T=A-(q) A=(p+1) A=AC-(q+1)   C = p>=q
+ *
+ * n は定数 / word(r) / A (符号なし 8bit)
+ */
+pub(super) enum Operand {
+    Register,
+    Word(Expr),
+    Constant(Expr),
+}
+
+// None when the statement is not a 16 bit statement
+pub fn expand(line: &Line, statement: &Statement) -> Result<Option<Vec<Line>>, AssemblyError> {
+    let expr = &statement.expression;
+    let lines = match (&statement.command, expr) {
+        (Expr::Identifier(t), Expr::BinOp(left, Operator::Sub, right)) if t == "T" => {
+            match word_address(left) {
+                Some(left) => vec![code_line(
+                    line,
+                    compare(line, &Operand::Word(left), &operand(right))?,
+                )],
+                None => return Ok(None),
+            }
+        }
+        (command, _) => match word_address(command) {
+            Some(target) => assign(line, &target, expr)?,
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(lines))
+}
+
+fn assign(line: &Line, target: &Expr, expr: &Expr) -> Result<Vec<Line>, AssemblyError> {
+    let code = |statements: Vec<Statement>| Ok(vec![code_line(line, statements)]);
+    match expr {
+        Expr::SystemOperator(op) if op == "+" => Ok(skip_high(
+            line,
+            vec![modify(target, "+"), branch("\\")],
+            vec![modify(&high(target), "+")],
+            vec![],
+        )),
+        Expr::SystemOperator(op) if op == "-" => Ok(skip_high(
+            line,
+            vec![load(target), branch("\\")],
+            vec![modify(&high(target), "-")],
+            vec![modify(target, "-")],
+        )),
+        Expr::BinOp(left, op @ (Operator::Add | Operator::Sub), right) => {
+            let source = word_address(left).ok_or_else(|| {
+                word_error(line, "the left side of + and - must be word(address)")
+            })?;
+            arithmetic(line, target, &source, op, &operand(right))
+        }
+        expr => match operand(expr) {
+            Operand::Word(source) => code(vec![
+                load(&source),
+                store(target),
+                load(&high(&source)),
+                store(&high(target)),
+            ]),
+            Operand::Constant(value) => code(vec![
+                Statement::new("A", Expr::LoByte(Box::new(value.clone()))),
+                store(target),
+                Statement::new("A", Expr::HiByte(Box::new(value))),
+                store(&high(target)),
+            ]),
+            Operand::Register => Err(word_error(line, "word(address)=A needs a high byte")),
+        },
+    }
+}
+
+fn arithmetic(
+    line: &Line,
+    target: &Expr,
+    source: &Expr,
+    op: &Operator,
+    operand: &Operand,
+) -> Result<Vec<Line>, AssemblyError> {
+    let (carry, with_carry) = match op {
+        Operator::Add => (0, "+"),
+        _ => (1, "-"),
+    };
+    let in_place = target == source && *op == Operator::Add;
+    let (low, high_byte) = match operand {
+        // p=p+A and p=p+n (n < 256) only carry into the high byte
+        Operand::Register if in_place => {
+            return Ok(skip_high(
+                line,
+                vec![
+                    Statement::new("C", Expr::DecimalNum(carry)),
+                    Statement::new(
+                        "A",
+                        ac(with_carry, Expr::Parenthesized(Box::new(source.clone()))),
+                    ),
+                    store(target),
+                    branch("<"),
+                ],
+                vec![modify(&high(target), "+")],
+                vec![],
+            ));
+        }
+        Operand::Constant(n) if in_place && is_byte(n) => {
+            return Ok(skip_high(
+                line,
+                vec![
+                    Statement::new("C", Expr::DecimalNum(carry)),
+                    load(source),
+                    Statement::new("A", ac(with_carry, n.clone())),
+                    store(target),
+                    branch("<"),
+                ],
+                vec![modify(&high(target), "+")],
+                vec![],
+            ));
+        }
+        Operand::Register => return Err(word_error(line, "only word(p)=word(p)+A is supported")),
+        operand => bytes(line, operand)?,
+    };
+    Ok(vec![code_line(
+        line,
+        vec![
+            Statement::new("C", Expr::DecimalNum(carry)),
+            load(source),
+            Statement::new("A", ac(with_carry, low)),
+            store(target),
+            load(&high(source)),
+            Statement::new("A", ac(with_carry, high_byte)),
+            store(&high(target)),
+        ],
+    )])
+}
+
+// C=1 when left >= right (unsigned), N xor V = 1 when left < right (signed)
+pub(super) fn compare(
+    line: &Line,
+    left: &Operand,
+    right: &Operand,
+) -> Result<Vec<Statement>, AssemblyError> {
+    let (left_low, left_high) = bytes(line, left)?;
+    let (right_low, right_high) = bytes(line, right)?;
+    let subtract = Expr::BinOp(
+        Box::new(Expr::Identifier("A".to_string())),
+        Operator::Sub,
+        Box::new(right_low),
+    );
+    Ok(vec![
+        Statement::new("A", left_low),
+        Statement::new("T", subtract),
+        Statement::new("A", left_high),
+        Statement::new("A", ac("-", right_high)),
+    ])
+}
+
+// operands of A=... for the low and high byte
+pub(super) fn bytes(line: &Line, operand: &Operand) -> Result<(Expr, Expr), AssemblyError> {
+    match operand {
+        Operand::Word(address) => Ok((
+            Expr::Parenthesized(Box::new(address.clone())),
+            Expr::Parenthesized(Box::new(high(address))),
+        )),
+        Operand::Constant(n) => Ok((
+            Expr::LoByte(Box::new(n.clone())),
+            Expr::HiByte(Box::new(n.clone())),
+        )),
+        Operand::Register => Err(word_error(line, "A is not a 16 bit value")),
+    }
+}
+
+pub(super) fn operand(expr: &Expr) -> Operand {
+    match word_address(expr) {
+        Some(address) => Operand::Word(address),
+        None if *expr == Expr::Identifier("A".to_string()) => Operand::Register,
+        None => Operand::Constant(expr.clone()),
+    }
+}
+
+// word(address) -> address
+pub(super) fn word_address(expr: &Expr) -> Option<Expr> {
+    match expr {
+        Expr::Function(name, args) if name == "word" => match args.as_slice() {
+            [address] => Some(address.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_byte(expr: &Expr) -> bool {
+    matches!(expr, Expr::ByteNum(_) | Expr::DecimalNum(0..=0xff))
+}
+
+fn high(address: &Expr) -> Expr {
+    Expr::BinOp(
+        Box::new(address.clone()),
+        Operator::Add,
+        Box::new(Expr::DecimalNum(1)),
+    )
+}
+
+// A=AC+n / A=AC-n
+pub(super) fn ac(op: &str, expr: Expr) -> Expr {
+    let op = if op == "+" {
+        Operator::Add
+    } else {
+        Operator::Sub
+    };
+    Expr::BinOp(
+        Box::new(Expr::Identifier("AC".to_string())),
+        op,
+        Box::new(expr),
+    )
+}
+
+fn load(address: &Expr) -> Statement {
+    Statement::new("A", Expr::Parenthesized(Box::new(address.clone())))
+}
+
+fn store(address: &Expr) -> Statement {
+    modify(address, "A")
+}
+
+// (address)=A, (address)=+, (address)=-
+fn modify(address: &Expr, value: &str) -> Statement {
+    let value = if value == "A" {
+        Expr::Identifier(value.to_string())
+    } else {
+        Expr::SystemOperator(value.to_string())
+    };
+    Statement {
+        command: Expr::Parenthesized(Box::new(address.clone())),
+        expression: value,
+    }
+}
+
+// ;=<condition>,#skip -- the label is filled in by skip_high()
+fn branch(condition: &str) -> Statement {
+    Statement::new(
+        ";",
+        Expr::BinOp(
+            Box::new(Expr::SystemOperator(condition.to_string())),
+            Operator::Comma,
+            Box::new(Expr::Empty),
+        ),
+    )
+}
+
+// before (ends with a branch over the high byte), high, skip label, after
+fn skip_high(
+    line: &Line,
+    mut before: Vec<Statement>,
+    high: Vec<Statement>,
+    after: Vec<Statement>,
+) -> Vec<Line> {
+    let label = generate_macro_identifier();
+    if let Some(Statement {
+        expression: Expr::BinOp(_, Operator::Comma, target),
+        ..
+    }) = before.last_mut()
+    {
+        **target = Expr::Identifier(label.clone());
+    }
+    let mut skip = line.new_label(&label);
+    skip.statements = after;
+    vec![code_line(line, before), code_line(line, high), skip]
+}
+
+pub(super) fn code_line(line: &Line, statements: Vec<Statement>) -> Line {
+    line.derive(None, statements)
+}
+
+fn word_error(line: &Line, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line.line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly_macro::{source, source_lines};
+
+    fn word_source(token: &str) -> Result<Option<Vec<String>>, AssemblyError> {
+        let line = &source_lines(&[token])[0];
+        Ok(expand(line, &line.statements[0])?.map(|lines| source(&lines)))
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            word_source("word(p)=$1234").unwrap().unwrap(),
+            vec![" A=<$1234 (p)=A A=>$1234 (p+1)=A"]
+        );
+        assert_eq!(
+            word_source("word(p)=word(q)").unwrap().unwrap(),
+            vec![" A=(q) (p)=A A=(q+1) (p+1)=A"]
+        );
+        assert_eq!(
+            word_source("word(p)=word(q)-word(r)").unwrap().unwrap(),
+            vec![" C=1 A=(q) A=AC-(r) (p)=A A=(q+1) A=AC-(r+1) (p+1)=A"]
+        );
+        assert_eq!(
+            word_source("T=word(p)-$1000").unwrap().unwrap(),
+            vec![" A=(p) T=A-<$1000 A=(p+1) A=AC->$1000"]
+        );
+        // only the carry goes into the high byte
+        assert_eq!(
+            word_source("word(p)=+").unwrap().unwrap(),
+            vec![" (p)=+ ;=\\,#m", " (p+1)=+", "#m "]
+        );
+        assert_eq!(
+            word_source("word(p)=-").unwrap().unwrap(),
+            vec![" A=(p) ;=\\,#m", " (p+1)=-", "#m (p)=-"]
+        );
+        assert_eq!(
+            word_source("word(p)=word(p)+A").unwrap().unwrap(),
+            vec![" C=0 A=AC+(p) (p)=A ;=<,#m", " (p+1)=+", "#m "]
+        );
+        assert_eq!(
+            word_source("word(p)=word(p)+8").unwrap().unwrap(),
+            vec![" C=0 A=(p) A=AC+8 (p)=A ;=<,#m", " (p+1)=+", "#m "]
+        );
+
+        assert_eq!(word_source("A=(p)").unwrap(), None);
+        assert_eq!(word_source("T=A-1").unwrap(), None);
+        // word() is only a 16 bit variable
+        assert!(word_source("word(p)=word(q)+A").is_err());
+        assert!(word_source("word(p)=A").is_err());
+        assert!(word_source("word(p)=A+1").is_err());
+    }
+}
@@ -0,0 +1,646 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod repeat;
+mod structure;
+
+use crate::{
+    assembler::{LabelEntry, LabelTable},
+    error::AssemblyError,
+    parser::{
+        expression::{Expr, Operator},
+        Line,
+    },
+};
+
+/**
+ * 条件アセンブル (展開前に評価される)
+ *  .if=PAL            式が 0 以外なら有効
+ *  .ifdef=DEBUG       ラベル / 定数が定義済みなら有効
+ *  .ifndef=DEBUG      未定義なら有効
+ *  .elseif=NTSC
+ *  .else
+ *  .endif
+ *  .repeat=8,i        本体を 8 回展開 (i = 0..7)
+ *  .endrepeat
+ *  .struct=Sprite     フィールドのオフセットを Sprite.x などの定数にする
+ *  .endstruct
+ *  .enum=State        State.idle = 0, State.walk = 1, ...
+ *  .endenum
+ *
+ * 式で参照できるのは -D で与えた定数と、それまでに := で定義された定数のみ
+ */
+struct Block {
+    line_number: usize,
+    // lines in the current branch are kept
+    active: bool,
+    // one of the branches has already been taken
+    taken: bool,
+    // the enclosing block is active
+    parent_active: bool,
+    seen_else: bool,
+}
+
+// names a .repeat loop variable or a -D define would hide
+const REGISTERS: [&str; 12] = ["A", "X", "Y", "S", "P", "T", "C", "Z", "I", "D", "V", "N"];
+
+pub fn resolve(
+    lines: &[Line],
+    defines: &LabelTable,
+    current_path: &Path,
+) -> Result<Vec<Line>, AssemblyError> {
+    let mut resolver = Resolver {
+        current_path: current_path.to_path_buf(),
+        constants: defines.clone(),
+        defined: defines.keys().cloned().collect(),
+        copies: 0,
+        structures: Vec::new(),
+    };
+    let lines = resolver.resolve_lines(lines)?;
+    structure::check_labels(&lines, &resolver.structures)?;
+    Ok(lines)
+}
+
+struct Resolver {
+    // filesize("file") is relative to the source file (same as &="file")
+    current_path: PathBuf,
+    constants: LabelTable,
+    defined: HashSet<String>,
+    // number of repeated bodies generated so far (makes local labels unique)
+    copies: usize,
+    // .struct / .enum names and their lines
+    structures: Vec<(String, usize)>,
+}
+
+impl Resolver {
+    fn resolve_lines(&mut self, lines: &[Line]) -> Result<Vec<Line>, AssemblyError> {
+        let mut result = Vec::new();
+        let mut stack: Vec<Block> = Vec::new();
+        let mut lines = lines.iter();
+        while let Some(line) = lines.next() {
+            let active = stack.last().is_none_or(|block| block.active);
+            let command = match line.statements.first() {
+                Some(statement) => statement.command()?,
+                None => String::new(),
+            };
+            match command.as_str() {
+                ".if" | ".ifdef" | ".ifndef" => {
+                    check_alone(line, &command)?;
+                    let condition = active && self.evaluate(line, &command)?;
+                    stack.push(Block {
+                        line_number: line.line_number,
+                        active: condition,
+                        taken: condition,
+                        parent_active: active,
+                        seen_else: false,
+                    });
+                }
+                ".elseif" | ".else" => {
+                    check_alone(line, &command)?;
+                    let block = stack.last_mut().ok_or_else(|| {
+                        conditional_error(line.line_number, &format!("{} without .if", command))
+                    })?;
+                    if block.seen_else {
+                        return Err(conditional_error(
+                            line.line_number,
+                            &format!("{} after .else", command),
+                        ));
+                    }
+                    let condition = if command == ".else" {
+                        block.seen_else = true;
+                        true
+                    } else {
+                        !block.taken && block.parent_active && self.evaluate(line, ".if")?
+                    };
+                    block.active = block.parent_active && !block.taken && condition;
+                    block.taken |= block.active;
+                }
+                ".endif" => {
+                    check_alone(line, &command)?;
+                    stack
+                        .pop()
+                        .ok_or_else(|| conditional_error(line.line_number, ".endif without .if"))?;
+                }
+                ".repeat" => {
+                    check_alone(line, &command)?;
+                    let body = repeat::collect_body(line, &mut lines)?;
+                    if active {
+                        result.extend(self.repeat(line, &body)?);
+                    }
+                }
+                ".struct" | ".enum" => {
+                    check_alone(line, &command)?;
+                    let body = structure::collect_body(line, &command, &mut lines)?;
+                    if active {
+                        result.extend(self.structure(line, &command, &body)?);
+                    }
+                }
+                ".endrepeat" | ".endstruct" | ".endenum" => {
+                    let start = command.replace(".end", ".");
+                    return Err(conditional_error(
+                        line.line_number,
+                        &format!("{} without {}", command, start),
+                    ));
+                }
+                _ if active => {
+                    let line = self.resolve_filesize(line)?;
+                    self.define_line(&line, &command);
+                    result.push(line);
+                }
+                _ => {}
+            }
+        }
+        if let Some(block) = stack.last() {
+            return Err(conditional_error(block.line_number, ".if without .endif"));
+        }
+        Ok(result)
+    }
+
+    fn repeat(&mut self, line: &Line, body: &[Line]) -> Result<Vec<Line>, AssemblyError> {
+        let (count, variable) = match &line.statements[0].expression {
+            Expr::BinOp(count, Operator::Comma, variable) => match variable.as_ref() {
+                Expr::Identifier(name) if REGISTERS.contains(&name.as_str()) => {
+                    // A=X would become A=0, A=1, ... instead of TXA
+                    return Err(conditional_error(
+                        line.line_number,
+                        &format!(".repeat loop variable can't be register or flag {}", name),
+                    ));
+                }
+                Expr::Identifier(name) if !name.starts_with('.') => (count.as_ref(), Some(name)),
+                _ => {
+                    return Err(conditional_error(
+                        line.line_number,
+                        ".repeat loop variable must be a name",
+                    ))
+                }
+            },
+            count => (count, None),
+        };
+        let count = self.evaluate_value(line, count)?;
+        let mut result = Vec::new();
+        for index in 0..count {
+            self.copies += 1;
+            let copy = repeat::unroll(body, variable, index, self.copies);
+            result.extend(self.resolve_lines(&copy)?);
+        }
+        Ok(result)
+    }
+
+    fn evaluate(&self, line: &Line, command: &str) -> Result<bool, AssemblyError> {
+        let expr = &line.statements[0].expression;
+        match (command, expr) {
+            (".ifdef", Expr::Identifier(name)) => Ok(self.defined.contains(name)),
+            (".ifndef", Expr::Identifier(name)) => Ok(!self.defined.contains(name)),
+            (".ifdef" | ".ifndef", _) => Err(conditional_error(
+                line.line_number,
+                &format!("{} needs a label name", command),
+            )),
+            _ => Ok(self.evaluate_value(line, expr)? != 0),
+        }
+    }
+
+    fn evaluate_value(&self, line: &Line, expr: &Expr) -> Result<u16, AssemblyError> {
+        // defined() also sees code labels, which have no value yet
+        let defined = |args: &[Expr]| match args {
+            [Expr::Identifier(name)] => Ok(Expr::DecimalNum(self.defined.contains(name) as u16)),
+            _ => Err(conditional_error(
+                line.line_number,
+                "defined() needs a label name",
+            )),
+        };
+        let expr = self
+            .filesize_expr(line, expr)?
+            .replace_function("defined", &defined)?;
+        expr.evaluate(&self.constants, &0).map_err(|e| {
+            conditional_error(
+                line.line_number,
+                &format!("expression is not constant: {}", e.message()),
+            )
+        })
+    }
+
+    fn resolve_filesize(&self, line: &Line) -> Result<Line, AssemblyError> {
+        let mut resolved = line.clone();
+        for statement in resolved.statements.iter_mut() {
+            statement.expression = self.filesize_expr(line, &statement.expression)?;
+        }
+        Ok(resolved)
+    }
+
+    fn filesize_expr(&self, line: &Line, expr: &Expr) -> Result<Expr, AssemblyError> {
+        let filesize = |args: &[Expr]| match args {
+            [Expr::StringLiteral(file)] => {
+                let path = self.current_path.join(file);
+                let size = fs::metadata(&path)
+                    .map_err(|e| {
+                        conditional_error(
+                            line.line_number,
+                            &format!("filesize(): {}: {}", path.display(), e),
+                        )
+                    })?
+                    .len();
+                u16::try_from(size).map(Expr::DecimalNum).map_err(|_| {
+                    conditional_error(
+                        line.line_number,
+                        &format!("filesize(): {} is larger than $FFFF", path.display()),
+                    )
+                })
+            }
+            _ => Err(conditional_error(
+                line.line_number,
+                "filesize() needs a file name",
+            )),
+        };
+        expr.replace_function("filesize", &filesize)
+    }
+
+    // remember labels and constants defined before the next condition
+    fn define_line(&mut self, line: &Line, command: &str) {
+        let Some(label) = &line.label else {
+            return;
+        };
+        if label.starts_with('.') {
+            return;
+        }
+        self.defined.insert(label.clone());
+        if command == ":" {
+            if let Ok(address) = line.statements[0]
+                .expression
+                .calculate_address(&self.constants)
+            {
+                self.constants.insert(
+                    label.clone(),
+                    LabelEntry {
+                        name: label.clone(),
+                        line: line.line_number,
+                        address,
+                    },
+                );
+            }
+        }
+    }
+}
+
+// -D NAME=value / -D NAME (= 1)
+pub fn parse_defines(defines: &[String]) -> Result<LabelTable, AssemblyError> {
+    let mut labels = LabelTable::new();
+    for define in defines {
+        let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(AssemblyError::syntax(&format!(
+                "invalid define: {}",
+                define
+            )));
+        }
+        if REGISTERS.contains(&name) {
+            return Err(AssemblyError::syntax(&format!(
+                "define can't be register or flag {}",
+                name
+            )));
+        }
+        let address = Expr::parse(value)?.calculate_address(&labels)?;
+        labels.insert(
+            name.to_string(),
+            LabelEntry {
+                name: name.to_string(),
+                line: 0,
+                address,
+            },
+        );
+    }
+    Ok(labels)
+}
+
+fn check_alone(line: &Line, command: &str) -> Result<(), AssemblyError> {
+    if line.label.is_some() || line.statements.len() > 1 {
+        return Err(conditional_error(
+            line.line_number,
+            &format!("{} must be alone on its line", command),
+        ));
+    }
+    Ok(())
+}
+
+fn conditional_error(line_number: usize, details: &str) -> AssemblyError {
+    AssemblyError::Macro(format!("line: {} {}", line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Address;
+    use crate::parser::statement::Statement;
+    use crate::test_dir::TestDir;
+
+    fn line(line_number: usize, label: Option<&str>, command: &str, operand: &str) -> Line {
+        let statements = if command.is_empty() {
+            vec![]
+        } else if operand.is_empty() {
+            vec![Statement::new(command, Expr::Empty)]
+        } else {
+            vec![Statement::new(command, Expr::parse(operand).unwrap())]
+        };
+        Line::new(
+            line_number,
+            0,
+            label.map(str::to_string),
+            statements,
+            vec![],
+        )
+    }
+
+    fn resolve_test(lines: &[Line], defines: &LabelTable) -> Result<Vec<Line>, AssemblyError> {
+        resolve(lines, defines, TestDir::new().path())
+    }
+
+    fn kept(lines: &[Line]) -> Vec<usize> {
+        lines.iter().map(|line| line.line_number).collect()
+    }
+
+    #[test]
+    fn test_if_else() {
+        let defines = parse_defines(&["PAL=1".to_string()]).unwrap();
+        let lines = vec![
+            line(1, None, ".if", "PAL"),
+            line(2, None, "A", "1"),
+            line(3, None, ".else", ""),
+            line(4, None, "A", "2"),
+            line(5, None, ".endif", ""),
+        ];
+        assert_eq!(kept(&resolve_test(&lines, &defines).unwrap()), vec![2]);
+        let defines = parse_defines(&["PAL=0".to_string()]).unwrap();
+        assert_eq!(kept(&resolve_test(&lines, &defines).unwrap()), vec![4]);
+    }
+
+    #[test]
+    fn test_elseif_and_nesting() {
+        let lines = vec![
+            line(1, Some("MODE"), ":", "2"),
+            line(2, None, ".if", "MODE=1"),
+            line(3, None, "A", "1"),
+            line(4, None, ".elseif", "MODE=2"),
+            line(5, None, ".ifdef", "DEBUG"),
+            line(6, None, "A", "3"),
+            line(7, None, ".endif", ""),
+            line(8, None, "A", "2"),
+            line(9, None, ".else", ""),
+            line(10, None, "A", "0"),
+            line(11, None, ".endif", ""),
+        ];
+        let defines = LabelTable::new();
+        assert_eq!(kept(&resolve_test(&lines, &defines).unwrap()), vec![1, 8]);
+        let defines = parse_defines(&["DEBUG".to_string()]).unwrap();
+        assert_eq!(
+            kept(&resolve_test(&lines, &defines).unwrap()),
+            vec![1, 6, 8]
+        );
+    }
+
+    #[test]
+    fn test_skipped_branch_is_not_evaluated() {
+        let lines = vec![
+            line(1, None, ".ifdef", "UNKNOWN"),
+            line(2, None, ".if", "UNKNOWN"),
+            line(3, Some("UNKNOWN"), ":", "1"),
+            line(4, None, ".endif", ""),
+            line(5, None, ".endif", ""),
+            line(6, None, ".ifndef", "UNKNOWN"),
+            line(7, Some("UNKNOWN"), ":", "1"),
+            line(8, None, ".endif", ""),
+            line(9, None, ".if", "UNKNOWN"),
+            line(10, None, ".endif", ""),
+        ];
+        let result = resolve_test(&lines, &LabelTable::new()).unwrap();
+        assert_eq!(kept(&result), vec![7]);
+    }
+
+    #[test]
+    fn test_mismatched_directives() {
+        let defines = LabelTable::new();
+        let unclosed = vec![line(1, None, ".if", "1"), line(2, None, "A", "1")];
+        assert!(resolve_test(&unclosed, &defines).is_err());
+        let stray = vec![line(1, None, ".endif", "")];
+        assert!(resolve_test(&stray, &defines).is_err());
+        let twice = vec![
+            line(1, None, ".if", "1"),
+            line(2, None, ".else", ""),
+            line(3, None, ".else", ""),
+            line(4, None, ".endif", ""),
+        ];
+        assert!(resolve_test(&twice, &defines).is_err());
+        let not_constant = vec![line(1, None, ".if", "LATER"), line(2, None, ".endif", "")];
+        assert!(resolve_test(&not_constant, &defines).is_err());
+        // at column 0 a directive name is an ordinary local label
+        for directive in [".else", ".endif", ".endpage", ".proc"] {
+            let label = vec![line(1, Some(directive), "", "")];
+            assert_eq!(kept(&resolve_test(&label, &defines).unwrap()), vec![1]);
+        }
+    }
+
+    fn source(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| {
+                let label = line.label.clone().unwrap_or_default();
+                let statements: Vec<String> =
+                    line.statements.iter().map(|s| s.to_string()).collect();
+                format!("{} {}", label, statements.join(" "))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_repeat_with_variable() {
+        let lines = vec![
+            line(1, Some("N"), ":", "3"),
+            line(2, None, ".repeat", "N,i"),
+            line(3, None, ".byte", "i*4"),
+            line(4, None, ".if", "i=1"),
+            line(5, None, "X", "i"),
+            line(6, None, ".endif", ""),
+            line(7, None, ".endrepeat", ""),
+        ];
+        let result = resolve_test(&lines, &LabelTable::new()).unwrap();
+        assert_eq!(
+            source(&result),
+            vec!["N :=3", " .byte=0*4", " .byte=1*4", " X=1", " .byte=2*4"]
+        );
+    }
+
+    #[test]
+    fn test_repeat_unique_local_labels() {
+        let lines = vec![
+            line(1, None, ".repeat", "2"),
+            line(2, Some(".wait"), "", ""),
+            line(3, None, "#", ".wait"),
+            line(4, None, "#", ".outside"),
+            line(5, None, ".repeat", "2"),
+            line(6, Some(".inner"), "", ""),
+            line(7, None, ".endrepeat", ""),
+            line(8, None, ".endrepeat", ""),
+        ];
+        let result = resolve_test(&lines, &LabelTable::new()).unwrap();
+        assert_eq!(
+            source(&result),
+            vec![
+                ".wait_1 ",
+                " #=.wait_1",
+                " #=.outside",
+                ".inner_1_2 ",
+                ".inner_1_3 ",
+                ".wait_4 ",
+                " #=.wait_4",
+                " #=.outside",
+                ".inner_4_5 ",
+                ".inner_4_6 ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repeat_errors() {
+        let defines = LabelTable::new();
+        let unclosed = vec![line(1, None, ".repeat", "2"), line(2, None, "A", "1")];
+        assert!(resolve_test(&unclosed, &defines).is_err());
+        let stray = vec![line(1, None, ".endrepeat", "")];
+        assert!(resolve_test(&stray, &defines).is_err());
+        let not_constant = vec![
+            line(1, None, ".repeat", "LATER"),
+            line(2, None, ".endrepeat", ""),
+        ];
+        assert!(resolve_test(&not_constant, &defines).is_err());
+        let split_if = vec![
+            line(1, None, ".repeat", "2"),
+            line(2, None, ".if", "1"),
+            line(3, None, ".endrepeat", ""),
+            line(4, None, ".endif", ""),
+        ];
+        assert!(resolve_test(&split_if, &defines).is_err());
+        let register = vec![
+            line(1, None, ".repeat", "2,X"),
+            line(2, None, "A", "X"),
+            line(3, None, ".endrepeat", ""),
+        ];
+        assert_eq!(
+            resolve_test(&register, &defines).err(),
+            Some(conditional_error(
+                1,
+                ".repeat loop variable can't be register or flag X"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_struct_and_enum() {
+        let lines = vec![
+            line(1, None, ".struct", "Sprite"),
+            line(2, Some("y"), ".byte", ""),
+            line(3, None, ".byte", "2"),
+            line(4, Some("pos"), ".word", "2"),
+            line(5, Some("x"), ".byte", ""),
+            line(6, None, ".endstruct", ""),
+            line(7, None, ".enum", "State"),
+            line(8, Some("idle"), "", ""),
+            line(9, Some("jump"), ":", "Sprite.size*2"),
+            line(10, Some("fall"), "", ""),
+            line(11, None, ".endenum", ""),
+            line(12, None, ".if", "State.fall=17"),
+            line(13, None, "A", "(OAM+Sprite.x+X)"),
+            line(14, None, ".endif", ""),
+        ];
+        let result = resolve_test(&lines, &LabelTable::new()).unwrap();
+        assert_eq!(
+            source(&result),
+            vec![
+                "Sprite.size :=8",
+                "Sprite.y :=0",
+                "Sprite.pos :=3",
+                "Sprite.x :=7",
+                "State.idle :=0",
+                "State.jump :=16",
+                "State.fall :=17",
+                " A=(OAM+Sprite.x+X)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_struct_errors() {
+        let defines = LabelTable::new();
+        let with = |members: Vec<Line>| {
+            let mut lines = vec![line(1, None, ".struct", "S")];
+            lines.extend(members);
+            lines.push(line(9, None, ".endstruct", ""));
+            resolve_test(&lines, &defines)
+        };
+        assert!(with(vec![line(2, Some("a"), "A", "1")]).is_err());
+        assert!(with(vec![line(2, Some("a"), "", "")]).is_err());
+        assert!(with(vec![line(2, Some("size"), ".byte", "")]).is_err());
+        assert!(with(vec![
+            line(2, Some("a"), ".byte", ""),
+            line(3, Some("a"), ".word", "")
+        ])
+        .is_err());
+        assert!(with(vec![line(2, Some("a"), ".byte", "LATER")]).is_err());
+        let unnamed = vec![
+            line(1, None, ".struct", ""),
+            line(2, None, ".endstruct", ""),
+        ];
+        assert!(resolve_test(&unnamed, &defines).is_err());
+        let unclosed = vec![line(1, None, ".enum", ""), line(2, Some("a"), "", "")];
+        assert!(resolve_test(&unclosed, &defines).is_err());
+        let stray = vec![line(1, None, ".endenum", "")];
+        assert!(resolve_test(&stray, &defines).is_err());
+        // one.loop would be both the member and a local label of one
+        let shadowed = vec![
+            line(1, None, ".struct", "one"),
+            line(2, Some("loop"), ".byte", ""),
+            line(3, None, ".endstruct", ""),
+            line(4, Some("one"), "X", "X+1"),
+        ];
+        assert!(resolve_test(&shadowed, &defines).is_err());
+        let enum_code = vec![
+            line(1, None, ".enum", ""),
+            line(2, Some("a"), "A", "1"),
+            line(3, None, ".endenum", ""),
+        ];
+        assert!(resolve_test(&enum_code, &defines).is_err());
+    }
+
+    #[test]
+    fn test_filesize_and_defined() {
+        let dir = TestDir::new();
+        dir.write("vtbase_filesize.bin", &[0u8; 300]);
+        let lines = vec![
+            line(1, Some("start"), "", ""),
+            line(2, None, ".if", "defined(start)"),
+            line(3, Some("SIZE"), ":", "filesize(\"vtbase_filesize.bin\")"),
+            line(4, None, ".endif", ""),
+            line(5, None, ".if", "SIZE=300"),
+            line(6, None, ".word", "filesize(\"vtbase_filesize.bin\")"),
+            line(7, None, ".endif", ""),
+        ];
+        let result = resolve(&lines, &LabelTable::new(), dir.path()).unwrap();
+        assert_eq!(source(&result), vec!["start ", "SIZE :=300", " .word=300"]);
+        let missing = vec![line(1, None, ".word", "filesize(\"vtbase_missing.bin\")")];
+        assert!(resolve(&missing, &LabelTable::new(), dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_parse_defines() {
+        let defines = parse_defines(&[
+            "PAL=$10".to_string(),
+            "B=$1234".to_string(),
+            "DEBUG".to_string(),
+        ])
+        .unwrap();
+        assert!(matches!(defines["PAL"].address, Address::ZeroPage(0x10)));
+        assert!(matches!(defines["B"].address, Address::Full(0x1234)));
+        assert!(matches!(defines["DEBUG"].address, Address::ZeroPage(1)));
+        assert!(parse_defines(&["=1".to_string()]).is_err());
+        // A=X would become A=$10 and C=1 the constant instead of SEC
+        assert!(parse_defines(&["A=$10".to_string()]).is_err());
+        assert!(parse_defines(&["C".to_string()]).is_err());
+    }
+}
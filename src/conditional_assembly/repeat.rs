@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::slice::Iter;
+
+use super::conditional_error;
+use crate::{
+    error::AssemblyError,
+    parser::{expression::Expr, statement::Statement, Line},
+};
+
+// lines between .repeat and the matching .endrepeat
+pub fn collect_body(header: &Line, lines: &mut Iter<Line>) -> Result<Vec<Line>, AssemblyError> {
+    let mut body = Vec::new();
+    let mut depth = 0;
+    for line in lines.by_ref() {
+        match first_command(line).as_deref() {
+            Some(".repeat") => depth += 1,
+            Some(".endrepeat") if depth == 0 => return Ok(body),
+            Some(".endrepeat") => depth -= 1,
+            _ => {}
+        }
+        body.push(line.clone());
+    }
+    Err(conditional_error(
+        header.line_number,
+        ".repeat without .endrepeat",
+    ))
+}
+
+/**
+ * 本体の 1 回分を生成する
+ *  - ループ変数を値に置き換える
+ *  - 本体で定義されたローカルラベルを .label_<copy> に付け替える
+ */
+pub fn unroll(body: &[Line], variable: Option<&String>, index: u16, copy: usize) -> Vec<Line> {
+    let local_labels: HashSet<&String> = body
+        .iter()
+        .filter_map(|line| line.label.as_ref())
+        .filter(|label| label.starts_with('.'))
+        .collect();
+    let mut replacements: Vec<(String, Expr)> = local_labels
+        .into_iter()
+        .map(|label| {
+            let unique = format!("{}_{}", label, copy);
+            (label.clone(), Expr::Identifier(unique))
+        })
+        .collect();
+    if let Some(variable) = variable {
+        replacements.push((variable.clone(), Expr::DecimalNum(index)));
+    }
+    body.iter()
+        .map(|line| {
+            let label = line.label.as_ref().map(|label| {
+                match replacements.iter().find(|(name, _)| name == label) {
+                    Some((_, Expr::Identifier(unique))) => unique.clone(),
+                    _ => label.clone(),
+                }
+            });
+            let statements = line
+                .statements
+                .iter()
+                .map(|statement| replace_statement(statement, &replacements))
+                .collect();
+            Line {
+                label,
+                statements,
+                ..line.clone()
+            }
+        })
+        .collect()
+}
+
+fn replace_statement(statement: &Statement, replacements: &[(String, Expr)]) -> Statement {
+    let replace = |expr: &Expr| -> Result<_, Infallible> {
+        Ok(match expr {
+            Expr::Identifier(id) => replacements
+                .iter()
+                .find(|(name, _)| name == id)
+                .map(|(_, with)| with.clone()),
+            _ => None,
+        })
+    };
+    let Ok(statement) = statement.rewrite(&replace);
+    statement
+}
+
+fn first_command(line: &Line) -> Option<String> {
+    line.statements
+        .first()
+        .and_then(|statement| statement.command().ok())
+}
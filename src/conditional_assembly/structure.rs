@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::slice::Iter;
+
+use super::{conditional_error, Resolver};
+use crate::{
+    error::AssemblyError,
+    parser::{expression::Expr, statement::Statement, Line},
+};
+
+// lines between .struct / .enum and the matching end directive
+pub fn collect_body(
+    header: &Line,
+    command: &str,
+    lines: &mut Iter<Line>,
+) -> Result<Vec<Line>, AssemblyError> {
+    let end = end_directive(command);
+    let mut body = Vec::new();
+    for line in lines.by_ref() {
+        match first_command(line).as_deref() {
+            Some(found) if found == end => return Ok(body),
+            Some(found @ (".struct" | ".enum")) => {
+                return Err(conditional_error(
+                    line.line_number,
+                    &format!("{} inside {}", found, command),
+                ))
+            }
+            _ => {}
+        }
+        body.push(line.clone());
+    }
+    Err(conditional_error(
+        header.line_number,
+        &format!("{} without {}", command, end),
+    ))
+}
+
+/**
+ * 構造体と列挙型 (フィールドのオフセットと値を := 定数に変換する)
+ *          .struct=Sprite
+ * y        .byte                  Sprite.y = 0
+ * tile     .byte                  Sprite.tile = 1
+ * pos      .word=2                Sprite.pos = 2 (2 words)
+ *          .endstruct             Sprite.size = 6
+ *
+ *          .enum=State
+ * idle                            State.idle = 0
+ * jump     :=10                   State.jump = 10
+ * fall                            State.fall = 11
+ *          .endenum
+ */
+impl Resolver {
+    pub(super) fn structure(
+        &mut self,
+        header: &Line,
+        command: &str,
+        body: &[Line],
+    ) -> Result<Vec<Line>, AssemblyError> {
+        let name = match (&header.statements[0].expression, command) {
+            (Expr::Identifier(name), _) if !name.starts_with('.') => Some(name.clone()),
+            // members of an unnamed enum are plain constants
+            (Expr::Empty, ".enum") => None,
+            _ => {
+                return Err(conditional_error(
+                    header.line_number,
+                    &format!("{} needs a name", command),
+                ))
+            }
+        };
+        if let Some(name) = &name {
+            self.structures.push((name.clone(), header.line_number));
+        }
+        let mut members = HashSet::new();
+        let mut result = Vec::new();
+        let mut value: u16 = 0;
+        for line in body {
+            let Some(label) = &line.label else {
+                if command == ".struct" && !line.statements.is_empty() {
+                    // padding without a name
+                    value = value.wrapping_add(self.field_size(line)?);
+                    continue;
+                }
+                if line.statements.is_empty() {
+                    continue;
+                }
+                return Err(conditional_error(
+                    line.line_number,
+                    &format!("{} member needs a name", command),
+                ));
+            };
+            if label.starts_with('.') || label == "size" && command == ".struct" {
+                return Err(conditional_error(
+                    line.line_number,
+                    &format!("invalid member name: {}", label),
+                ));
+            }
+            if !members.insert(label.clone()) {
+                return Err(conditional_error(
+                    line.line_number,
+                    &format!("{} is defined twice", label),
+                ));
+            }
+            let qualified = match &name {
+                Some(name) => format!("{}.{}", name, label),
+                None => label.clone(),
+            };
+            if command == ".struct" {
+                result.push(self.constant(line.line_number, &qualified, value));
+                value = value.wrapping_add(self.field_size(line)?);
+            } else {
+                if let Some(statement) = line.statements.first() {
+                    if line.statements.len() > 1 || statement.command()? != ":" {
+                        return Err(conditional_error(
+                            line.line_number,
+                            "enum member takes only :=value",
+                        ));
+                    }
+                    value = self.evaluate_value(line, &statement.expression)?;
+                }
+                result.push(self.constant(line.line_number, &qualified, value));
+                value = value.wrapping_add(1);
+            }
+        }
+        if let (Some(name), ".struct") = (&name, command) {
+            let size = format!("{}.size", name);
+            let size = self.constant(header.line_number, &size, value);
+            result.insert(0, size);
+        }
+        Ok(result)
+    }
+
+    // .byte / .word with an optional count
+    fn field_size(&self, line: &Line) -> Result<u16, AssemblyError> {
+        let invalid = || {
+            conditional_error(
+                line.line_number,
+                "struct field takes .byte[=count] or .word[=count]",
+            )
+        };
+        let [statement] = line.statements.as_slice() else {
+            return Err(invalid());
+        };
+        let unit = match statement.command()?.as_str() {
+            ".byte" => 1,
+            ".word" => 2,
+            _ => return Err(invalid()),
+        };
+        let count = match &statement.expression {
+            Expr::Empty => 1,
+            expr => self.evaluate_value(line, expr)?,
+        };
+        Ok(count.wrapping_mul(unit))
+    }
+
+    // label :=value, remembered for the following conditions and counts
+    fn constant(&mut self, line_number: usize, label: &str, value: u16) -> Line {
+        let statement = Statement::new(":", Expr::DecimalNum(value));
+        let line = Line::new(
+            line_number,
+            0,
+            Some(label.to_string()),
+            vec![statement],
+            vec![],
+        );
+        self.define_line(&line, ":");
+        line
+    }
+}
+
+// a label named like a struct would make its local labels (one.loop) members
+pub fn check_labels(lines: &[Line], structures: &[(String, usize)]) -> Result<(), AssemblyError> {
+    for line in lines {
+        let Some(label) = &line.label else {
+            continue;
+        };
+        if let Some((_, line_number)) = structures.iter().find(|(name, _)| name == label) {
+            return Err(conditional_error(
+                line.line_number,
+                &format!(
+                    "label {} is also a .struct/.enum name (line {})",
+                    label, line_number
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn end_directive(command: &str) -> &'static str {
+    match command {
+        ".struct" => ".endstruct",
+        _ => ".endenum",
+    }
+}
+
+fn first_command(line: &Line) -> Option<String> {
+    line.statements.first()?.command().ok()
+}
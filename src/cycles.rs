@@ -0,0 +1,495 @@
+use std::fmt;
+
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    opcode::{AddressingMode, Mnemonic, OpcodeTable},
+    parser::{
+        expression::{Expr, Operator},
+        Line,
+    },
+};
+
+/**
+ * サイクル数 (NMOS 6502)
+ *  .cycles=12          ブロックが丁度 12 サイクル
+ *  .cycles=0,100       最小,最大 (100 サイクル以内)
+ *  .loops=8            次のループを 8 回と数える
+ *  .endcycles
+ *
+ *  - 分岐は成立で +1、飛び先のページが違えば更に +1
+ *  - インデックス付きの読み込みはページを跨ぐと +1 (最大に含める)
+ *  - サブルーチン呼び出し (!=) は JSR の 6 サイクルだけ数える
+ *  - ブロックの外への分岐 / ジャンプ、#=! はそこで終わる
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cycles {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Cycles {
+    fn new(min: usize, max: usize) -> Self {
+        Self { min, max }
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.min + other.min, self.max + other.max)
+    }
+
+    fn times(self, n: usize) -> Self {
+        Self::new(self.min * n, self.max * n)
+    }
+
+    // either path may be taken
+    fn merge(self, other: Option<Self>) -> Self {
+        match other {
+            Some(other) => Self::new(self.min.min(other.min), self.max.max(other.max)),
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+    Next,
+    Branch(u16),
+    Jump(u16),
+    Exit,
+}
+
+#[derive(Debug)]
+struct Instruction {
+    line_number: usize,
+    address: u16,
+    // not taken for a branch
+    cycles: Cycles,
+    // extra cycles when the branch is taken
+    taken: usize,
+    flow: Flow,
+}
+
+// data lines have no cycles
+const DATA_COMMANDS: [&str; 9] = [
+    "?", "$", "&", ".byte", ".word", ".dbyt", ".lobytes", ".hibytes", ".align",
+];
+
+// cycles of the instructions on a line, None for data and empty lines
+pub fn line_cycles(table: &OpcodeTable, line: &Line) -> Option<Cycles> {
+    let instructions = decode(table, line)?;
+    instructions
+        .iter()
+        .map(|instruction| {
+            let taken = instruction.taken;
+            Cycles::new(instruction.cycles.min, instruction.cycles.max + taken)
+        })
+        .reduce(Cycles::add)
+}
+
+fn decode(table: &OpcodeTable, line: &Line) -> Option<Vec<Instruction>> {
+    if line.object_codes.is_empty() || line.statements.iter().any(is_data) {
+        return None;
+    }
+    let mut instructions = vec![];
+    let mut bytes = line.object_codes.as_slice();
+    let mut address = line.address;
+    while let Some(&byte) = bytes.first() {
+        let opcode = table.decode(byte)?;
+        let length = opcode.addressing_mode.length();
+        let operand = bytes.get(1..length)?;
+        let next = address.wrapping_add(length as u16);
+        let base = opcode.cycles as usize;
+        let penalty = opcode.page_penalty() as usize;
+        let (flow, taken) = match (&opcode.mnemonic, &opcode.addressing_mode) {
+            (_, AddressingMode::Relative) => {
+                let target = next.wrapping_add_signed(operand[0] as i8 as i16);
+                (
+                    Flow::Branch(target),
+                    1 + (target >> 8 != next >> 8) as usize,
+                )
+            }
+            (Mnemonic::JMP, AddressingMode::Absolute) => {
+                (Flow::Jump(u16::from_le_bytes([operand[0], operand[1]])), 0)
+            }
+            (Mnemonic::JMP | Mnemonic::RTS | Mnemonic::RTI | Mnemonic::BRK, _) => (Flow::Exit, 0),
+            _ => (Flow::Next, 0),
+        };
+        instructions.push(Instruction {
+            line_number: line.line_number,
+            address,
+            cycles: Cycles::new(base, base + penalty),
+            taken,
+            flow,
+        });
+        bytes = &bytes[length..];
+        address = next;
+    }
+    Some(instructions)
+}
+
+fn is_data(statement: &crate::parser::statement::Statement) -> bool {
+    statement.is_pseudo()
+        && DATA_COMMANDS.contains(&statement.command().unwrap_or_default().as_str())
+}
+
+// .cycles ... .endcycles blocks must keep their cycle counts
+pub fn check(
+    table: &OpcodeTable,
+    lines: &[Line],
+    labels: &LabelTable,
+) -> Result<(), AssemblyError> {
+    let mut blocks = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        for statement in &line.statements {
+            match statement.command().unwrap_or_default().as_str() {
+                ".cycles" => blocks.push((i, expected(line, &statement.expression, labels)?)),
+                ".endcycles" => {
+                    let (start, expected) = blocks.pop().ok_or(AssemblyError::program(
+                        &format!("line: {} .endcycles without .cycles", line.line_number),
+                    ))?;
+                    check_block(table, &lines[start..=i], expected, labels)?;
+                }
+                _ => {}
+            }
+        }
+    }
+    if let Some((start, _)) = blocks.last() {
+        return Err(AssemblyError::program(&format!(
+            "line: {} .cycles without .endcycles",
+            lines[*start].line_number
+        )));
+    }
+    Ok(())
+}
+
+// .cycles=n / .cycles=min,max
+fn expected(line: &Line, expression: &Expr, labels: &LabelTable) -> Result<Cycles, AssemblyError> {
+    let evaluate = |expr: &Expr| -> Result<usize, AssemblyError> {
+        expr.evaluate(labels, &line.address)
+            .map(usize::from)
+            .map_err(|e| block_error(line.line_number, &format!(".cycles {}", e.message())))
+    };
+    match expression {
+        Expr::BinOp(min, Operator::Comma, max) => Ok(Cycles::new(evaluate(min)?, evaluate(max)?)),
+        Expr::Empty => Err(block_error(line.line_number, ".cycles needs a cycle count")),
+        count => {
+            let count = evaluate(count)?;
+            Ok(Cycles::new(count, count))
+        }
+    }
+}
+
+fn check_block(
+    table: &OpcodeTable,
+    lines: &[Line],
+    expected: Cycles,
+    labels: &LabelTable,
+) -> Result<(), AssemblyError> {
+    let line_number = lines[0].line_number;
+    let mut block = Block {
+        code: vec![],
+        loops: vec![],
+        end: lines[lines.len() - 1].address,
+    };
+    for line in lines {
+        for statement in &line.statements {
+            if statement.command().unwrap_or_default() == ".loops" {
+                let count = statement.expression.evaluate(labels, &line.address)?;
+                block
+                    .loops
+                    .push((line.line_number, line.address, count as usize));
+            }
+        }
+        block.code.extend(decode(table, line).unwrap_or_default());
+    }
+    let actual = block.count()?;
+    if actual.min < expected.min || actual.max > expected.max {
+        return Err(block_error(
+            line_number,
+            &format!("block takes {} cycles, expected {}", actual, expected),
+        ));
+    }
+    Ok(())
+}
+
+struct Block {
+    code: Vec<Instruction>,
+    // line number, address, count
+    loops: Vec<(usize, u16, usize)>,
+    end: u16,
+}
+
+impl Block {
+    fn count(&self) -> Result<Cycles, AssemblyError> {
+        let mut tops: Vec<u16> = self
+            .code
+            .iter()
+            .filter_map(|instruction| match instruction.flow {
+                Flow::Branch(target) | Flow::Jump(target) if target <= instruction.address => {
+                    self.index(target).map(|_| target)
+                }
+                _ => None,
+            })
+            .collect();
+        tops.sort();
+        tops.dedup();
+        let mut counts = vec![];
+        for &(line_number, address, count) in &self.loops {
+            let top = tops
+                .iter()
+                .find(|&&top| top >= address && counts.iter().all(|(used, _)| *used != top));
+            match (top, count) {
+                (None, _) => return Err(block_error(line_number, ".loops without a loop")),
+                (_, 0) => return Err(block_error(line_number, ".loops must be 1 or more")),
+                (Some(&top), count) => counts.push((top, count)),
+            }
+        }
+        let end = self.code.len();
+        self.span(0, end, &counts)?
+            .ok_or(block_error(self.line_number(0), "block never ends"))
+    }
+
+    // index of the instruction at the address, the end of the block is code.len()
+    fn index(&self, address: u16) -> Option<usize> {
+        if address == self.end {
+            return Some(self.code.len());
+        }
+        self.code
+            .iter()
+            .position(|instruction| instruction.address == address)
+    }
+
+    fn line_number(&self, i: usize) -> usize {
+        self.code
+            .get(i)
+            .map_or(0, |instruction| instruction.line_number)
+    }
+
+    // cycles from code[from] to code[to] or out of the range, None when it can't get out
+    fn span(
+        &self,
+        from: usize,
+        to: usize,
+        counts: &[(u16, usize)],
+    ) -> Result<Option<Cycles>, AssemblyError> {
+        let mut reach: Vec<Option<Cycles>> = vec![None; to - from + 1];
+        reach[0] = Some(Cycles::new(0, 0));
+        let mut exits: Option<Cycles> = None;
+        let mut i = from;
+        while i < to {
+            let Some(now) = reach[i - from] else {
+                i += 1;
+                continue;
+            };
+            let instruction = &self.code[i];
+            if let Some(end) = self.loop_end(i, to) {
+                let total = self.repeat(i, end, counts)?;
+                reach[end + 1 - from] = Some(now.add(total).merge(reach[end + 1 - from]));
+                i = end + 1;
+                continue;
+            }
+            let taken = Cycles::new(1, 1).times(instruction.taken);
+            let target = match instruction.flow {
+                Flow::Next => {
+                    let next = now.add(instruction.cycles);
+                    reach[i + 1 - from] = Some(next.merge(reach[i + 1 - from]));
+                    None
+                }
+                Flow::Branch(target) => {
+                    let next = now.add(instruction.cycles);
+                    reach[i + 1 - from] = Some(next.merge(reach[i + 1 - from]));
+                    Some(target)
+                }
+                Flow::Jump(target) => Some(target),
+                Flow::Exit => {
+                    exits = Some(now.add(instruction.cycles).merge(exits));
+                    None
+                }
+            };
+            if let Some(target) = target {
+                let now = now.add(instruction.cycles).add(taken);
+                match self.index(target) {
+                    Some(j) if j > i && j <= to => {
+                        reach[j - from] = Some(now.merge(reach[j - from]))
+                    }
+                    Some(j) if j >= from && j <= i => {
+                        return Err(block_error(
+                            instruction.line_number,
+                            &format!("loop to ${:04X} can't be counted", target),
+                        ))
+                    }
+                    _ => exits = Some(now.merge(exits)),
+                }
+            }
+            i += 1;
+        }
+        Ok(match (reach[to - from], exits) {
+            (Some(end), exits) => Some(end.merge(exits)),
+            (None, exits) => exits,
+        })
+    }
+
+    // the last branch / jump back to code[top] before code[to]
+    fn loop_end(&self, top: usize, to: usize) -> Option<usize> {
+        let address = self.code[top].address;
+        (top..to).rev().find(|&j| {
+            matches!(self.code[j].flow, Flow::Branch(target) | Flow::Jump(target) if target == address)
+        })
+    }
+
+    // n times the body and the branch back n-1 times
+    fn repeat(
+        &self,
+        top: usize,
+        end: usize,
+        counts: &[(u16, usize)],
+    ) -> Result<Cycles, AssemblyError> {
+        let instruction = &self.code[end];
+        let line_number = self.line_number(top);
+        let count = counts
+            .iter()
+            .find(|(address, _)| *address == self.code[top].address)
+            .map(|(_, count)| *count)
+            .ok_or(block_error(line_number, "loop needs .loops=n"))?;
+        let body = self
+            .span(top, end, counts)?
+            .ok_or(block_error(line_number, "loop never ends"))?;
+        let back = instruction
+            .cycles
+            .add(Cycles::new(1, 1).times(instruction.taken));
+        let total = body.times(count).add(back.times(count - 1));
+        Ok(match instruction.flow {
+            // falls through on the last round
+            Flow::Branch(_) => total.add(instruction.cycles),
+            // leaves the loop from the body
+            _ => total,
+        })
+    }
+}
+
+fn block_error(line_number: usize, details: &str) -> AssemblyError {
+    AssemblyError::program(&format!("line: {} {}", line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_body;
+
+    fn line(line_number: usize, address: u16, label: &str, body: &str, bytes: &[u8]) -> Line {
+        let label = Some(label.to_string()).filter(|label| !label.is_empty());
+        Line::new(
+            line_number,
+            address,
+            label,
+            parse_body(body).unwrap(),
+            bytes.to_vec(),
+        )
+    }
+
+    fn check_lines(lines: &[Line]) -> Result<(), AssemblyError> {
+        check(&OpcodeTable::new(), lines, &LabelTable::new())
+    }
+
+    #[test]
+    fn test_line_cycles() {
+        let table = OpcodeTable::new();
+        let cycles = |body, bytes| line_cycles(&table, &line(1, 0x0810, "", body, bytes));
+        assert_eq!(
+            cycles("A=(hello+X)", &[0xbd, 0x1c, 0x08]),
+            Some(Cycles::new(4, 5))
+        );
+        assert_eq!(
+            cycles("(hello+X)=A", &[0x9d, 0x1c, 0x08]),
+            Some(Cycles::new(5, 5))
+        );
+        assert_eq!(
+            cycles("X=0 X=+", &[0xa2, 0x00, 0xe8]),
+            Some(Cycles::new(4, 4))
+        );
+        // the target is on the previous page
+        assert_eq!(cycles(";=\\,loop", &[0xd0, 0xe0]), Some(Cycles::new(2, 4)));
+        assert_eq!(cycles("?=1,2", &[0x01, 0x02]), None);
+        assert_eq!(Cycles::new(4, 5).to_string(), "4-5");
+    }
+
+    #[test]
+    fn test_straight_block() {
+        let lines = |expected| {
+            vec![
+                line(1, 0x0800, "", expected, &[]),
+                line(
+                    2,
+                    0x0800,
+                    "",
+                    "A=0 (PPU)=A",
+                    &[0xa9, 0x00, 0x8d, 0x00, 0x20],
+                ),
+                line(3, 0x0805, "", "(PPU)=A", &[0x8d, 0x00, 0x20]),
+                line(4, 0x0808, "", ".endcycles", &[]),
+            ]
+        };
+        assert!(check_lines(&lines(".cycles=10")).is_ok());
+        assert!(check_lines(&lines(".cycles=0,10")).is_ok());
+        assert_eq!(
+            check_lines(&lines(".cycles=0,8")),
+            Err(AssemblyError::program(
+                "line: 1 block takes 10 cycles, expected 0-8"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_forward_branch() {
+        let lines = vec![
+            line(1, 0x0800, "", ".cycles=3,4", &[]),
+            line(2, 0x0800, "", ";==,skip A=1", &[0xf0, 0x02, 0xa9, 0x01]),
+            line(3, 0x0804, "skip", ".endcycles", &[]),
+        ];
+        assert!(check_lines(&lines).is_ok());
+    }
+
+    #[test]
+    fn test_loop() {
+        let lines = |loops| {
+            vec![
+                line(1, 0x0800, "", ".cycles=57", &[]),
+                line(2, 0x0800, "", "X=0", &[0xa2, 0x00]),
+                line(3, 0x0802, "", loops, &[]),
+                line(
+                    4,
+                    0x0802,
+                    "loop",
+                    "X=+ T=X-8 ;=\\,loop",
+                    &[0xe8, 0xe0, 0x08, 0xd0, 0xfb],
+                ),
+                line(5, 0x0807, "", ".endcycles", &[]),
+            ]
+        };
+        // X=0 2, the body 8 * 4, taken 7 * 3, falls through 2
+        assert!(check_lines(&lines(".loops=8")).is_ok());
+        assert_eq!(
+            check_lines(&lines("")),
+            Err(AssemblyError::program("line: 4 loop needs .loops=n"))
+        );
+    }
+
+    #[test]
+    fn test_mismatched_block() {
+        let lines = vec![line(1, 0x0800, "", ".cycles=2", &[])];
+        assert_eq!(
+            check_lines(&lines),
+            Err(AssemblyError::program("line: 1 .cycles without .endcycles"))
+        );
+    }
+}
@@ -11,6 +11,7 @@ pub enum AssemblyError {
     Macro(String),
     Decode(String),
     Io(String),
+    User(String),
 }
 
 impl From<io::Error> for AssemblyError {
@@ -28,6 +29,7 @@ impl AssemblyError {
             AssemblyError::Macro(details) => details,
             AssemblyError::Decode(details) => details,
             AssemblyError::Io(details) => details,
+            AssemblyError::User(details) => details,
         }
     }
 
@@ -70,6 +72,11 @@ impl AssemblyError {
     pub fn decode_failed(details: &str) -> Self {
         Self::Decode(details.to_string())
     }
+
+    // location is file:line (Line::location())
+    pub fn user(location: &str, details: &str) -> Self {
+        Self::User(format!("{location} {details}"))
+    }
 }
 
 impl fmt::Display for AssemblyError {
@@ -81,6 +88,7 @@ impl fmt::Display for AssemblyError {
             AssemblyError::Macro(details) => write!(f, "syntax error: {}", details),
             AssemblyError::Decode(details) => write!(f, "decode error: {}", details),
             AssemblyError::Io(details) => write!(f, "io error: {}", details),
+            AssemblyError::User(details) => write!(f, "error: {}", details),
         }
     }
 }
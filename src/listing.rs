@@ -0,0 +1,75 @@
+use crate::cycles::line_cycles;
+use crate::opcode::OpcodeTable;
+use crate::parser::Line;
+
+// bytes shown per listing line, the rest is elided with "..."
+const BYTES_PER_LINE: usize = 8;
+
+/**
+ * アセンブルリスト
+ *     4  080e a200                2              X=0
+ *     5  0810 bd1c08              4-5   loop     A=(hello+X)
+ * 展開後の行 (マクロ、.repeat) も 1 行ずつ出力する
+ * サイクル数はページ跨ぎ / 分岐成立で増える分を範囲で示す
+ */
+pub fn render(lines: &[Line]) -> String {
+    let table = OpcodeTable::new();
+    let mut listing = String::new();
+    for line in lines {
+        let address = if line.object_codes.is_empty() {
+            String::new()
+        } else {
+            format!("{:04x}", line.address)
+        };
+        let mut bytes: String = line
+            .object_codes
+            .iter()
+            .take(BYTES_PER_LINE)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if line.object_codes.len() > BYTES_PER_LINE {
+            bytes.push_str("...");
+        }
+        let cycles = line_cycles(&table, line).map_or(String::new(), |c| c.to_string());
+        let label = line.label.as_deref().unwrap_or("");
+        let statements: Vec<String> = line.statements.iter().map(|s| s.to_string()).collect();
+        let text = format!(
+            "{:>6}  {:4} {:<19} {:<5} {:<8} {}",
+            line.line_number,
+            address,
+            bytes,
+            cycles,
+            label,
+            statements.join(" ")
+        );
+        listing.push_str(text.trim_end());
+        listing.push('\n');
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::expression::Expr;
+    use crate::parser::statement::Statement;
+
+    #[test]
+    fn test_render() {
+        let statement = Statement::new("A", Expr::parse("(hello+X)").unwrap());
+        let lines = vec![
+            Line::new(
+                5,
+                0x0810,
+                Some("loop".to_string()),
+                vec![statement],
+                vec![0xbd, 0x1c, 0x08],
+            ),
+            Line::new(6, 0x0813, Some("hello".to_string()), vec![], vec![]),
+        ];
+        assert_eq!(
+            render(&lines),
+            "     5  0810 bd1c08              4-5   loop     A=(hello+X)\n     6                                 hello\n"
+        );
+    }
+}
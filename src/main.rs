@@ -1,16 +1,29 @@
 use ihex::Record;
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 
+mod anonymous_label;
 mod assembler;
 mod assembly_macro;
+mod conditional_assembly;
+mod cycles;
 mod error;
+mod listing;
+mod module;
 mod opcode;
 mod parser;
-use assembler::Assembler;
-use parser::Line;
+mod simulator;
+#[cfg(test)]
+mod test_dir;
+mod unit_test;
+use assembler::{Assembler, LabelTable};
+use error::AssemblyError;
+use opcode::OpcodeTable;
+use parser::{expression::Expr, Line};
+use simulator::{Cpu, Stop, Stub};
+use unit_test::Test;
 
 use structopt::StructOpt;
 
@@ -23,9 +36,77 @@ struct Opt {
     /// Use Intel HEX format
     #[structopt(long)]
     ihex: bool,
+    /// Write an assembly listing
+    #[structopt(long)]
+    listing: Option<String>,
+    #[structopt(flatten)]
+    assembly: AssemblyOpt,
+}
+
+// options of the assembly, shared by every command
+#[derive(StructOpt)]
+struct AssemblyOpt {
+    /// Define a constant for conditional assembly (NAME=value or NAME)
+    #[structopt(short = "D", number_of_values = 1)]
+    defines: Vec<String>,
+    /// Don't warn about > comparisons (> was >= in IF and DO conditions before)
+    #[structopt(long)]
+    no_warn_gt: bool,
+}
+
+// stubs of the simulator, shared by run and test
+#[derive(StructOpt)]
+struct StubOpt {
+    /// Memory mapped stub ADDRESS=chrout|putc|getc|exit (default: $FFD2=chrout)
+    #[structopt(long = "stub", number_of_values = 1)]
+    stubs: Vec<String>,
+}
+
+/// Subcommands, the first argument selects them
+#[derive(StructOpt)]
+enum Command {
+    /// Assemble and run a program in the 6502 simulator
+    Run(RunOpt),
+    /// Run the .test blocks of the source in the 6502 simulator
+    Test(TestOpt),
+}
+
+#[derive(StructOpt)]
+struct RunOpt {
+    /// Source file
+    src_file: String,
+    /// Start address or label (default: the first instruction)
+    #[structopt(long)]
+    start: Option<String>,
+    #[structopt(flatten)]
+    stub: StubOpt,
+    /// Stop after this many cycles
+    #[structopt(long, default_value = "10000000")]
+    max_cycles: u64,
+    #[structopt(flatten)]
+    assembly: AssemblyOpt,
+}
+
+#[derive(StructOpt)]
+struct TestOpt {
+    /// Source file
+    src_file: String,
+    #[structopt(flatten)]
+    stub: StubOpt,
+    /// Fail a call after this many cycles
+    #[structopt(long, default_value = "1000000")]
+    max_cycles: u64,
+    #[structopt(flatten)]
+    assembly: AssemblyOpt,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if matches!(std::env::args().nth(1).as_deref(), Some("run" | "test")) {
+        return match Command::from_args() {
+            Command::Run(opt) => simulate(opt),
+            Command::Test(opt) => test(opt),
+        };
+    }
     let opt = Opt::from_args();
 
     // open source file
@@ -50,11 +131,18 @@ fn run(
     output_file: File,
     opt: Opt,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let lines = parser::parse_from_file(source_file, source_file_path.to_path_buf())?;
-    let mut lines = assembly_macro::expand(&lines)?;
-    let mut assembler = Assembler::new(source_file_path.to_path_buf());
-    let obj_size = assembler.assemble(&mut lines)?;
-    eprintln!("assemble done. object size = {} bytes", obj_size);
+    let label_file = Some(PathBuf::from("labels.txt"));
+    let (lines, _, _) = assemble(
+        source_file,
+        source_file_path,
+        &opt.src_file,
+        &opt.assembly,
+        label_file,
+    )?;
+
+    if let Some(listing_file) = &opt.listing {
+        std::fs::write(listing_file, listing::render(&lines))?;
+    }
 
     if opt.ihex {
         output_ihex(output_file, lines)
@@ -63,6 +151,125 @@ fn run(
     }
 }
 
+// assembled lines, labels and the .test blocks taken out of the source
+type Assembled = (Vec<Line>, Assembler, Vec<Test>);
+
+fn assemble(
+    source_file: &File,
+    source_file_path: &Path,
+    source_file_name: &str,
+    opt: &AssemblyOpt,
+    label_file: Option<PathBuf>,
+) -> Result<Assembled, Box<dyn std::error::Error>> {
+    let defines = conditional_assembly::parse_defines(&opt.defines)?;
+    let lines = parser::parse_from_file(
+        source_file,
+        source_file_path.to_path_buf(),
+        source_file_name,
+    )?;
+    if !opt.no_warn_gt {
+        for warning in assembly_macro::greater_warnings(&lines) {
+            eprintln!("{}", warning);
+        }
+    }
+    let lines = conditional_assembly::resolve(&lines, &defines, source_file_path)?;
+    let lines = anonymous_label::resolve(&lines)?;
+    let lines = module::resolve(&lines)?;
+    let (lines, tests) = unit_test::extract(&lines)?;
+    let mut lines = assembly_macro::expand(&lines)?;
+    let mut assembler = Assembler::new(source_file_path.to_path_buf());
+    assembler.labels.extend(defines);
+    assembler.label_file = label_file;
+    let obj_size = assembler.assemble(&mut lines)?;
+    eprintln!("assemble done. object size = {} bytes", obj_size);
+    Ok((lines, assembler, tests))
+}
+
+// vtbase run: the program is called from outside and runs until it stops
+fn simulate(opt: RunOpt) -> Result<(), Box<dyn std::error::Error>> {
+    let source_file_path = Path::new(&opt.src_file);
+    let source_file = File::open(source_file_path).expect("can't open source file");
+    let current_file_path = source_file_path.parent().unwrap();
+    let (lines, assembler, _) = assemble(
+        &source_file,
+        current_file_path,
+        &opt.src_file,
+        &opt.assembly,
+        None,
+    )?;
+    let labels = &assembler.labels;
+
+    let mut cpu = Cpu::new();
+    cpu.load(&lines);
+    for (address, stub) in stubs(&opt.stub.stubs, labels)? {
+        cpu.add_stub(address, stub);
+    }
+    let start = match &opt.start {
+        Some(start) => Expr::parse(start)?.evaluate(labels, &0)?,
+        None => {
+            let table = OpcodeTable::new();
+            lines
+                .iter()
+                .find(|line| cycles::line_cycles(&table, line).is_some())
+                .ok_or(AssemblyError::program("no code to run"))?
+                .address
+        }
+    };
+    cpu.call(start);
+    let stop = cpu.run(opt.max_cycles)?;
+    std::io::stdout().write_all(&cpu.output)?;
+    eprintln!("{}: {}", stop, cpu);
+    if stop == Stop::Limit {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// vtbase test: every .test block runs on a fresh copy of the program
+fn test(opt: TestOpt) -> Result<(), Box<dyn std::error::Error>> {
+    let source_file_path = Path::new(&opt.src_file);
+    let source_file = File::open(source_file_path).expect("can't open source file");
+    let current_file_path = source_file_path.parent().unwrap();
+    let (lines, assembler, tests) = assemble(
+        &source_file,
+        current_file_path,
+        &opt.src_file,
+        &opt.assembly,
+        None,
+    )?;
+    let stubs = stubs(&opt.stub.stubs, &assembler.labels)?;
+
+    let mut failed = 0;
+    for test in &tests {
+        let failures = unit_test::run(test, &lines, &assembler.labels, &stubs, opt.max_cycles)?;
+        if failures.is_empty() {
+            println!("ok     {} {}", test.location, test.name);
+            continue;
+        }
+        failed += 1;
+        println!("FAILED {} {}", test.location, test.name);
+        for failure in failures {
+            println!("    {}", failure);
+        }
+    }
+    println!("{} tests, {} failed", tests.len(), failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// $FFD2=chrout when no stub is given
+fn stubs(specs: &[String], labels: &LabelTable) -> Result<Vec<(u16, Stub)>, AssemblyError> {
+    if specs.is_empty() {
+        return Ok(vec![(0xFFD2, Stub::Chrout)]);
+    }
+    specs
+        .iter()
+        .map(|spec| simulator::parse_stub(spec, labels))
+        .collect()
+}
+
 fn output_bin(output_file: File, lines: Vec<Line>) -> Result<(), Box<dyn std::error::Error>> {
     let mut writer = BufWriter::new(output_file);
     for line in lines {
@@ -100,7 +307,7 @@ fn render_ihex(objects: Vec<u8>, start_address: u16) -> std::io::Result<String>
 
     records.push(Record::EndOfFile);
 
-    let object = ihex::create_object_file_representation(&records)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let object =
+        ihex::create_object_file_representation(&records).map_err(std::io::Error::other)?;
     Ok(object)
 }
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    error::AssemblyError,
+    parser::{expression::Expr, Line},
+};
+
+/**
+ * モジュール (名前空間)
+ *          .module=stack
+ *          .export=push_false,dup
+ * push_false ...                  外からは stack::push_false
+ * helper   ...                    外からは参照できない
+ *          .endmodule
+ *
+ *          .import=stack::dup     このスコープ全体で dup が stack::dup を指す
+ *
+ * モジュール内のラベルは stack::name に付け替えてから展開・アセンブルする
+ */
+#[derive(Default)]
+struct Module {
+    symbols: HashSet<String>,
+    exports: HashSet<String>,
+}
+
+// None = outside of any module
+type Scope = Option<String>;
+
+pub fn resolve(lines: &[Line]) -> Result<Vec<Line>, AssemblyError> {
+    let modules = collect_modules(lines)?;
+    let imports = collect_imports(lines, &modules)?;
+    let mut result = Vec::new();
+    let mut scope: Scope = None;
+    for line in lines {
+        match directive(line).as_deref() {
+            Some(".module") => scope = module_name(line).ok(),
+            Some(".endmodule") => scope = None,
+            Some(_) => {}
+            None => result.push(qualify_line(line, &scope, &modules, &imports)?),
+        }
+    }
+    Ok(result)
+}
+
+// symbols and exports of every module
+fn collect_modules(lines: &[Line]) -> Result<HashMap<String, Module>, AssemblyError> {
+    let mut modules: HashMap<String, Module> = HashMap::new();
+    let mut scope: Option<(usize, String)> = None;
+    for line in lines {
+        let command = directive(line);
+        if let Some(command) = &command {
+            if line.label.is_some() || line.statements.len() > 1 {
+                return Err(module_error(
+                    line.line_number,
+                    &format!("{} must be alone on its line", command),
+                ));
+            }
+        }
+        match command.as_deref() {
+            Some(".module") => {
+                if let Some((line_number, name)) = &scope {
+                    return Err(module_error(
+                        line.line_number,
+                        &format!("nested .module (.module={} at line {})", name, line_number),
+                    ));
+                }
+                let name = module_name(line)?;
+                modules.entry(name.clone()).or_default();
+                scope = Some((line.line_number, name));
+            }
+            Some(".endmodule") => {
+                scope
+                    .take()
+                    .ok_or_else(|| module_error(line.line_number, ".endmodule without .module"))?;
+            }
+            Some(".export") => {
+                let (_, name) = scope
+                    .as_ref()
+                    .ok_or_else(|| module_error(line.line_number, ".export outside of .module"))?;
+                let names = symbol_list(line)?;
+                modules.get_mut(name).unwrap().exports.extend(names);
+            }
+            Some(_) => {}
+            None => {
+                if let (Some((_, name)), Some(label)) = (&scope, &line.label) {
+                    if is_global(label) {
+                        modules.get_mut(name).unwrap().symbols.insert(label.clone());
+                    }
+                }
+            }
+        }
+    }
+    if let Some((line_number, _)) = scope {
+        return Err(module_error(line_number, ".module without .endmodule"));
+    }
+    for (name, module) in &modules {
+        if let Some(symbol) = module.exports.difference(&module.symbols).next() {
+            return Err(AssemblyError::program(&format!(
+                "{}::{} is exported but not defined",
+                name, symbol
+            )));
+        }
+    }
+    Ok(modules)
+}
+
+// imported name -> qualified name, for each scope
+fn collect_imports(
+    lines: &[Line],
+    modules: &HashMap<String, Module>,
+) -> Result<HashMap<Scope, HashMap<String, String>>, AssemblyError> {
+    let top_level = top_level_symbols(lines);
+    let mut imports: HashMap<Scope, HashMap<String, String>> = HashMap::new();
+    let mut scope: Scope = None;
+    for line in lines {
+        match directive(line).as_deref() {
+            Some(".module") => scope = module_name(line).ok(),
+            Some(".endmodule") => scope = None,
+            Some(".import") => {
+                for qualified in symbol_list(line)? {
+                    check_visible(line.line_number, &qualified, &scope, modules)?;
+                    let (_, name) = qualified.split_once("::").ok_or_else(|| {
+                        module_error(
+                            line.line_number,
+                            &format!(".import needs module::name, not {}", qualified),
+                        )
+                    })?;
+                    let defined_here = match &scope {
+                        Some(module) => modules[module].symbols.contains(name),
+                        None => top_level.contains(name),
+                    };
+                    if defined_here {
+                        return Err(module_error(
+                            line.line_number,
+                            &format!("import {} conflicts with a label", qualified),
+                        ));
+                    }
+                    imports
+                        .entry(scope.clone())
+                        .or_default()
+                        .insert(name.to_string(), qualified.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(imports)
+}
+
+// global labels outside of any module
+fn top_level_symbols(lines: &[Line]) -> HashSet<String> {
+    let mut symbols = HashSet::new();
+    let mut in_module = false;
+    for line in lines {
+        match directive(line).as_deref() {
+            Some(".module") => in_module = true,
+            Some(".endmodule") => in_module = false,
+            Some(_) => {}
+            None => {
+                if let Some(label) = line
+                    .label
+                    .as_ref()
+                    .filter(|label| !in_module && is_global(label))
+                {
+                    symbols.insert(label.clone());
+                }
+            }
+        }
+    }
+    symbols
+}
+
+fn qualify_line(
+    line: &Line,
+    scope: &Scope,
+    modules: &HashMap<String, Module>,
+    imports: &HashMap<Scope, HashMap<String, String>>,
+) -> Result<Line, AssemblyError> {
+    let imported = imports.get(scope);
+    let qualify = |expr: &Expr| match expr {
+        Expr::Identifier(name) if name.contains("::") => {
+            check_visible(line.line_number, name, scope, modules).map(|_| None)
+        }
+        Expr::Identifier(name) => {
+            let own = scope
+                .as_ref()
+                .filter(|module| modules[*module].symbols.contains(name))
+                .map(|module| format!("{}::{}", module, name));
+            let import = imported.and_then(|imported| imported.get(name).cloned());
+            Ok(own.or(import).map(Expr::Identifier))
+        }
+        _ => Ok(None),
+    };
+    let mut result = line.clone();
+    if let (Some(module), Some(label)) = (scope, &line.label) {
+        if is_global(label) {
+            result.label = Some(format!("{}::{}", module, label));
+        }
+    }
+    result.statements = line
+        .statements
+        .iter()
+        .map(|statement| statement.rewrite(&qualify))
+        .collect::<Result<_, _>>()?;
+    Ok(result)
+}
+
+// module::name is visible inside the module, or when exported
+fn check_visible(
+    line_number: usize,
+    qualified: &str,
+    scope: &Scope,
+    modules: &HashMap<String, Module>,
+) -> Result<(), AssemblyError> {
+    let Some((module_name, name)) = qualified.split_once("::") else {
+        return Ok(());
+    };
+    let module = modules
+        .get(module_name)
+        .ok_or_else(|| module_error(line_number, &format!("unknown module {}", module_name)))?;
+    if scope.as_deref() == Some(module_name) || module.exports.contains(name) {
+        Ok(())
+    } else if module.symbols.contains(name) {
+        Err(module_error(
+            line_number,
+            &format!("{} is not exported", qualified),
+        ))
+    } else {
+        Err(module_error(
+            line_number,
+            &format!("{} is not defined", qualified),
+        ))
+    }
+}
+
+fn directive(line: &Line) -> Option<String> {
+    let command = line.statements.first()?.command().ok()?;
+    matches!(
+        command.as_str(),
+        ".module" | ".endmodule" | ".export" | ".import"
+    )
+    .then_some(command)
+}
+
+fn module_name(line: &Line) -> Result<String, AssemblyError> {
+    match &line.statements[0].expression {
+        Expr::Identifier(name) if is_global(name) && !name.contains("::") => Ok(name.clone()),
+        _ => Err(module_error(line.line_number, ".module needs a name")),
+    }
+}
+
+fn symbol_list(line: &Line) -> Result<Vec<String>, AssemblyError> {
+    line.statements[0]
+        .expression
+        .traverse_comma()
+        .into_iter()
+        .map(|expr| match expr {
+            Expr::Identifier(name) if is_global(&name) => Ok(name),
+            expr => Err(module_error(
+                line.line_number,
+                &format!("invalid symbol: {}", expr),
+            )),
+        })
+        .collect()
+}
+
+fn is_global(label: &str) -> bool {
+    !label.starts_with('.') && !label.starts_with('#') && label != ":"
+}
+
+fn module_error(line_number: usize, details: &str) -> AssemblyError {
+    AssemblyError::program(&format!("line: {} {}", line_number, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::statement::Statement;
+
+    fn line(label: Option<&str>, command: &str, operand: &str) -> Line {
+        let expression = if operand.is_empty() {
+            Expr::Empty
+        } else {
+            Expr::parse(operand).unwrap()
+        };
+        let statement = Statement::new(command, expression);
+        Line::new(0, 0, label.map(str::to_string), vec![statement], vec![])
+    }
+
+    fn stack_module() -> Vec<Line> {
+        vec![
+            line(None, ".module", "stack"),
+            line(None, ".export", "push_false,dup"),
+            line(Some("N"), ":", "$01"),
+            line(Some("push_false"), "A", "N"),
+            line(Some("dup"), "!", "push_false"),
+            line(Some("helper"), "#", "!"),
+            line(None, ".endmodule", ""),
+        ]
+    }
+
+    fn source(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| {
+                let label = line.label.clone().unwrap_or_default();
+                format!("{} {}", label, line.statements[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_qualify_module_symbols() {
+        let mut lines = stack_module();
+        lines.push(line(Some("dup"), "!", "stack::dup"));
+        lines.push(line(None, ".import", "stack::push_false"));
+        lines.push(line(None, "!", "push_false"));
+        let result = resolve(&lines).unwrap();
+        assert_eq!(
+            source(&result),
+            vec![
+                "stack::N :=$01",
+                "stack::push_false A=stack::N",
+                "stack::dup !=stack::push_false",
+                "stack::helper #=!",
+                "dup !=stack::dup",
+                " !=stack::push_false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let with = |extra: Vec<Line>| {
+            let mut lines = stack_module();
+            lines.extend(extra);
+            resolve(&lines)
+        };
+        // not exported
+        assert!(with(vec![line(None, "!", "stack::helper")]).is_err());
+        assert!(with(vec![line(None, ".import", "stack::helper")]).is_err());
+        // unknown module / symbol
+        assert!(with(vec![line(None, "!", "heap::dup")]).is_err());
+        assert!(with(vec![line(None, "!", "stack::nothing")]).is_err());
+        // exported but not defined
+        assert!(with(vec![
+            line(None, ".module", "heap"),
+            line(None, ".export", "alloc"),
+            line(None, ".endmodule", ""),
+        ])
+        .is_err());
+        // unclosed / unbalanced
+        assert!(with(vec![line(None, ".module", "heap")]).is_err());
+        assert!(with(vec![line(None, ".endmodule", "")]).is_err());
+        assert!(with(vec![line(None, ".export", "dup")]).is_err());
+        // the import would hide the global dup
+        assert!(with(vec![
+            line(Some("dup"), "#", "!"),
+            line(None, ".import", "stack::dup"),
+        ])
+        .is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::vec;
 
 use crate::error::AssemblyError;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Mnemonic {
     ADC,
@@ -62,7 +63,7 @@ pub enum Mnemonic {
     TYA,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AddressingMode {
     Immediate,
     ZeroPage,
@@ -138,18 +139,47 @@ pub struct Opcode {
     pub mnemonic: Mnemonic,
     pub addressing_mode: AddressingMode,
     pub opcode: u8,
+    pub cycles: u8, // NMOS 6502 のサイクル数 (ページ跨ぎ / 分岐成立の加算なし)
 }
 
 impl Opcode {
-    pub fn new(mnemonic: Mnemonic, addressing_mode: AddressingMode, opcode: u8) -> Self {
+    pub fn new(
+        mnemonic: Mnemonic,
+        addressing_mode: AddressingMode,
+        opcode: u8,
+        cycles: u8,
+    ) -> Self {
         Self {
             mnemonic,
             addressing_mode,
             opcode,
+            cycles,
         }
     }
+
+    // +1 when the indexed address crosses a page (reads only)
+    pub fn page_penalty(&self) -> bool {
+        matches!(
+            self.addressing_mode,
+            Mode::AbsoluteX | Mode::AbsoluteY | Mode::IndirectY
+        ) && matches!(
+            self.mnemonic,
+            Mnemonic::ADC
+                | Mnemonic::AND
+                | Mnemonic::CMP
+                | Mnemonic::EOR
+                | Mnemonic::LDA
+                | Mnemonic::LDX
+                | Mnemonic::LDY
+                | Mnemonic::ORA
+                | Mnemonic::SBC
+        )
+    }
 }
 
+// addressing mode, opcode, cycles
+type Entry = (AddressingMode, i32, u8);
+
 pub struct OpcodeTable {
     opcode_table: Vec<Opcode>,
 }
@@ -158,8 +188,13 @@ impl OpcodeTable {
     pub fn new() -> Self {
         let mut opcode_table = Vec::new();
         for (mnemonic, modes) in Self::mnemonics() {
-            for (addressing_mode, opcode) in modes {
-                opcode_table.push(Opcode::new(mnemonic.clone(), addressing_mode, opcode as u8));
+            for (addressing_mode, opcode, cycles) in modes {
+                opcode_table.push(Opcode::new(
+                    mnemonic.clone(),
+                    addressing_mode,
+                    opcode as u8,
+                    cycles,
+                ));
             }
         }
         Self { opcode_table }
@@ -178,263 +213,270 @@ impl OpcodeTable {
         Err(AssemblyError::opcode_not_found(mnemonic, addressing_mode))
     }
 
-    fn mnemonics() -> Vec<(Mnemonic, Vec<(AddressingMode, i32)>)> {
+    // decode an object code byte
+    pub fn decode(&self, opcode: u8) -> Option<&Opcode> {
+        self.opcode_table
+            .iter()
+            .find(|entry| entry.opcode == opcode)
+    }
+
+    fn mnemonics() -> Vec<(Mnemonic, Vec<Entry>)> {
         vec![
             (
                 Mnemonic::ADC,
                 vec![
-                    (Mode::Immediate, 0x69),
-                    (Mode::ZeroPage, 0x65),
-                    (Mode::ZeroPageX, 0x75),
-                    (Mode::Absolute, 0x6D),
-                    (Mode::AbsoluteX, 0x7D),
-                    (Mode::AbsoluteY, 0x79),
-                    (Mode::IndirectX, 0x61),
-                    (Mode::IndirectY, 0x71),
+                    (Mode::Immediate, 0x69, 2),
+                    (Mode::ZeroPage, 0x65, 3),
+                    (Mode::ZeroPageX, 0x75, 4),
+                    (Mode::Absolute, 0x6D, 4),
+                    (Mode::AbsoluteX, 0x7D, 4),
+                    (Mode::AbsoluteY, 0x79, 4),
+                    (Mode::IndirectX, 0x61, 6),
+                    (Mode::IndirectY, 0x71, 5),
                 ],
             ),
             (
                 Mnemonic::AND,
                 vec![
-                    (Mode::Immediate, 0x29),
-                    (Mode::ZeroPage, 0x25),
-                    (Mode::ZeroPageX, 0x35),
-                    (Mode::Absolute, 0x2D),
-                    (Mode::AbsoluteX, 0x3D),
-                    (Mode::AbsoluteY, 0x39),
-                    (Mode::IndirectX, 0x21),
-                    (Mode::IndirectY, 0x31),
+                    (Mode::Immediate, 0x29, 2),
+                    (Mode::ZeroPage, 0x25, 3),
+                    (Mode::ZeroPageX, 0x35, 4),
+                    (Mode::Absolute, 0x2D, 4),
+                    (Mode::AbsoluteX, 0x3D, 4),
+                    (Mode::AbsoluteY, 0x39, 4),
+                    (Mode::IndirectX, 0x21, 6),
+                    (Mode::IndirectY, 0x31, 5),
                 ],
             ),
             (
                 Mnemonic::ASL,
                 vec![
-                    (Mode::Accumulator, 0x0A),
-                    (Mode::ZeroPage, 0x06),
-                    (Mode::ZeroPageX, 0x16),
-                    (Mode::Absolute, 0x0E),
-                    (Mode::AbsoluteX, 0x1E),
+                    (Mode::Accumulator, 0x0A, 2),
+                    (Mode::ZeroPage, 0x06, 5),
+                    (Mode::ZeroPageX, 0x16, 6),
+                    (Mode::Absolute, 0x0E, 6),
+                    (Mode::AbsoluteX, 0x1E, 7),
                 ],
             ),
-            (Mnemonic::BCC, vec![(Mode::Relative, 0x90)]),
-            (Mnemonic::BCS, vec![(Mode::Relative, 0xB0)]),
-            (Mnemonic::BEQ, vec![(Mode::Relative, 0xF0)]),
+            (Mnemonic::BCC, vec![(Mode::Relative, 0x90, 2)]),
+            (Mnemonic::BCS, vec![(Mode::Relative, 0xB0, 2)]),
+            (Mnemonic::BEQ, vec![(Mode::Relative, 0xF0, 2)]),
             (
                 Mnemonic::BIT,
-                vec![(Mode::ZeroPage, 0x24), (Mode::Absolute, 0x2C)],
+                vec![(Mode::ZeroPage, 0x24, 3), (Mode::Absolute, 0x2C, 4)],
             ),
-            (Mnemonic::BMI, vec![(Mode::Relative, 0x30)]),
-            (Mnemonic::BNE, vec![(Mode::Relative, 0xD0)]),
-            (Mnemonic::BPL, vec![(Mode::Relative, 0x10)]),
-            (Mnemonic::BRK, vec![(Mode::Implied, 0x00)]),
-            (Mnemonic::BVC, vec![(Mode::Relative, 0x50)]),
-            (Mnemonic::BVS, vec![(Mode::Relative, 0x70)]),
-            (Mnemonic::CLC, vec![(Mode::Implied, 0x18)]),
-            (Mnemonic::CLD, vec![(Mode::Implied, 0xD8)]),
-            (Mnemonic::CLI, vec![(Mode::Implied, 0x58)]),
-            (Mnemonic::CLV, vec![(Mode::Implied, 0xB8)]),
+            (Mnemonic::BMI, vec![(Mode::Relative, 0x30, 2)]),
+            (Mnemonic::BNE, vec![(Mode::Relative, 0xD0, 2)]),
+            (Mnemonic::BPL, vec![(Mode::Relative, 0x10, 2)]),
+            (Mnemonic::BRK, vec![(Mode::Implied, 0x00, 7)]),
+            (Mnemonic::BVC, vec![(Mode::Relative, 0x50, 2)]),
+            (Mnemonic::BVS, vec![(Mode::Relative, 0x70, 2)]),
+            (Mnemonic::CLC, vec![(Mode::Implied, 0x18, 2)]),
+            (Mnemonic::CLD, vec![(Mode::Implied, 0xD8, 2)]),
+            (Mnemonic::CLI, vec![(Mode::Implied, 0x58, 2)]),
+            (Mnemonic::CLV, vec![(Mode::Implied, 0xB8, 2)]),
             (
                 Mnemonic::CMP,
                 vec![
-                    (Mode::Immediate, 0xC9),
-                    (Mode::ZeroPage, 0xC5),
-                    (Mode::ZeroPageX, 0xD5),
-                    (Mode::Absolute, 0xCD),
-                    (Mode::AbsoluteX, 0xDD),
-                    (Mode::AbsoluteY, 0xD9),
-                    (Mode::IndirectX, 0xC1),
-                    (Mode::IndirectY, 0xD1),
+                    (Mode::Immediate, 0xC9, 2),
+                    (Mode::ZeroPage, 0xC5, 3),
+                    (Mode::ZeroPageX, 0xD5, 4),
+                    (Mode::Absolute, 0xCD, 4),
+                    (Mode::AbsoluteX, 0xDD, 4),
+                    (Mode::AbsoluteY, 0xD9, 4),
+                    (Mode::IndirectX, 0xC1, 6),
+                    (Mode::IndirectY, 0xD1, 5),
                 ],
             ),
             (
                 Mnemonic::CPX,
                 vec![
-                    (Mode::Immediate, 0xE0),
-                    (Mode::ZeroPage, 0xE4),
-                    (Mode::Absolute, 0xEC),
+                    (Mode::Immediate, 0xE0, 2),
+                    (Mode::ZeroPage, 0xE4, 3),
+                    (Mode::Absolute, 0xEC, 4),
                 ],
             ),
             (
                 Mnemonic::CPY,
                 vec![
-                    (Mode::Immediate, 0xC0),
-                    (Mode::ZeroPage, 0xC4),
-                    (Mode::Absolute, 0xCC),
+                    (Mode::Immediate, 0xC0, 2),
+                    (Mode::ZeroPage, 0xC4, 3),
+                    (Mode::Absolute, 0xCC, 4),
                 ],
             ),
             (
                 Mnemonic::DEC,
                 vec![
-                    (Mode::ZeroPage, 0xC6),
-                    (Mode::ZeroPageX, 0xD6),
-                    (Mode::Absolute, 0xCE),
-                    (Mode::AbsoluteX, 0xDE),
+                    (Mode::ZeroPage, 0xC6, 5),
+                    (Mode::ZeroPageX, 0xD6, 6),
+                    (Mode::Absolute, 0xCE, 6),
+                    (Mode::AbsoluteX, 0xDE, 7),
                 ],
             ),
-            (Mnemonic::DEX, vec![(Mode::Implied, 0xCA)]),
-            (Mnemonic::DEY, vec![(Mode::Implied, 0x88)]),
+            (Mnemonic::DEX, vec![(Mode::Implied, 0xCA, 2)]),
+            (Mnemonic::DEY, vec![(Mode::Implied, 0x88, 2)]),
             (
                 Mnemonic::EOR,
                 vec![
-                    (Mode::Immediate, 0x49),
-                    (Mode::ZeroPage, 0x45),
-                    (Mode::ZeroPageX, 0x55),
-                    (Mode::Absolute, 0x4D),
-                    (Mode::AbsoluteX, 0x5D),
-                    (Mode::AbsoluteY, 0x59),
-                    (Mode::IndirectX, 0x41),
-                    (Mode::IndirectY, 0x51),
+                    (Mode::Immediate, 0x49, 2),
+                    (Mode::ZeroPage, 0x45, 3),
+                    (Mode::ZeroPageX, 0x55, 4),
+                    (Mode::Absolute, 0x4D, 4),
+                    (Mode::AbsoluteX, 0x5D, 4),
+                    (Mode::AbsoluteY, 0x59, 4),
+                    (Mode::IndirectX, 0x41, 6),
+                    (Mode::IndirectY, 0x51, 5),
                 ],
             ),
             (
                 Mnemonic::INC,
                 vec![
-                    (Mode::ZeroPage, 0xE6),
-                    (Mode::ZeroPageX, 0xF6),
-                    (Mode::Absolute, 0xEE),
-                    (Mode::AbsoluteX, 0xFE),
+                    (Mode::ZeroPage, 0xE6, 5),
+                    (Mode::ZeroPageX, 0xF6, 6),
+                    (Mode::Absolute, 0xEE, 6),
+                    (Mode::AbsoluteX, 0xFE, 7),
                 ],
             ),
-            (Mnemonic::INX, vec![(Mode::Implied, 0xE8)]),
-            (Mnemonic::INY, vec![(Mode::Implied, 0xC8)]),
+            (Mnemonic::INX, vec![(Mode::Implied, 0xE8, 2)]),
+            (Mnemonic::INY, vec![(Mode::Implied, 0xC8, 2)]),
             (
                 Mnemonic::JMP,
-                vec![(Mode::Absolute, 0x4C), (Mode::Indirect, 0x6C)],
+                vec![(Mode::Absolute, 0x4C, 3), (Mode::Indirect, 0x6C, 5)],
             ),
-            (Mnemonic::JSR, vec![(Mode::Absolute, 0x20)]),
+            (Mnemonic::JSR, vec![(Mode::Absolute, 0x20, 6)]),
             (
                 Mnemonic::LDA,
                 vec![
-                    (Mode::Immediate, 0xA9),
-                    (Mode::ZeroPage, 0xA5),
-                    (Mode::ZeroPageX, 0xB5),
-                    (Mode::Absolute, 0xAD),
-                    (Mode::AbsoluteX, 0xBD),
-                    (Mode::AbsoluteY, 0xB9),
-                    (Mode::IndirectX, 0xA1),
-                    (Mode::IndirectY, 0xB1),
+                    (Mode::Immediate, 0xA9, 2),
+                    (Mode::ZeroPage, 0xA5, 3),
+                    (Mode::ZeroPageX, 0xB5, 4),
+                    (Mode::Absolute, 0xAD, 4),
+                    (Mode::AbsoluteX, 0xBD, 4),
+                    (Mode::AbsoluteY, 0xB9, 4),
+                    (Mode::IndirectX, 0xA1, 6),
+                    (Mode::IndirectY, 0xB1, 5),
                 ],
             ),
             (
                 Mnemonic::LDX,
                 vec![
-                    (Mode::Immediate, 0xA2),
-                    (Mode::ZeroPage, 0xA6),
-                    (Mode::ZeroPageY, 0xB6),
-                    (Mode::Absolute, 0xAE),
-                    (Mode::AbsoluteY, 0xBE),
+                    (Mode::Immediate, 0xA2, 2),
+                    (Mode::ZeroPage, 0xA6, 3),
+                    (Mode::ZeroPageY, 0xB6, 4),
+                    (Mode::Absolute, 0xAE, 4),
+                    (Mode::AbsoluteY, 0xBE, 4),
                 ],
             ),
             (
                 Mnemonic::LDY,
                 vec![
-                    (Mode::Immediate, 0xA0),
-                    (Mode::ZeroPage, 0xA4),
-                    (Mode::ZeroPageX, 0xB4),
-                    (Mode::Absolute, 0xAC),
-                    (Mode::AbsoluteX, 0xBC),
+                    (Mode::Immediate, 0xA0, 2),
+                    (Mode::ZeroPage, 0xA4, 3),
+                    (Mode::ZeroPageX, 0xB4, 4),
+                    (Mode::Absolute, 0xAC, 4),
+                    (Mode::AbsoluteX, 0xBC, 4),
                 ],
             ),
             (
                 Mnemonic::LSR,
                 vec![
-                    (Mode::Accumulator, 0x4A),
-                    (Mode::ZeroPage, 0x46),
-                    (Mode::ZeroPageX, 0x56),
-                    (Mode::Absolute, 0x4E),
-                    (Mode::AbsoluteX, 0x5E),
+                    (Mode::Accumulator, 0x4A, 2),
+                    (Mode::ZeroPage, 0x46, 5),
+                    (Mode::ZeroPageX, 0x56, 6),
+                    (Mode::Absolute, 0x4E, 6),
+                    (Mode::AbsoluteX, 0x5E, 7),
                 ],
             ),
-            (Mnemonic::NOP, vec![(Mode::Implied, 0xEA)]),
+            (Mnemonic::NOP, vec![(Mode::Implied, 0xEA, 2)]),
             (
                 Mnemonic::ORA,
                 vec![
-                    (Mode::Immediate, 0x09),
-                    (Mode::ZeroPage, 0x05),
-                    (Mode::ZeroPageX, 0x15),
-                    (Mode::Absolute, 0x0D),
-                    (Mode::AbsoluteX, 0x1D),
-                    (Mode::AbsoluteY, 0x19),
-                    (Mode::IndirectX, 0x01),
-                    (Mode::IndirectY, 0x11),
+                    (Mode::Immediate, 0x09, 2),
+                    (Mode::ZeroPage, 0x05, 3),
+                    (Mode::ZeroPageX, 0x15, 4),
+                    (Mode::Absolute, 0x0D, 4),
+                    (Mode::AbsoluteX, 0x1D, 4),
+                    (Mode::AbsoluteY, 0x19, 4),
+                    (Mode::IndirectX, 0x01, 6),
+                    (Mode::IndirectY, 0x11, 5),
                 ],
             ),
-            (Mnemonic::PHA, vec![(Mode::Implied, 0x48)]),
-            (Mnemonic::PHP, vec![(Mode::Implied, 0x08)]),
-            (Mnemonic::PLA, vec![(Mode::Implied, 0x68)]),
-            (Mnemonic::PLP, vec![(Mode::Implied, 0x28)]),
+            (Mnemonic::PHA, vec![(Mode::Implied, 0x48, 3)]),
+            (Mnemonic::PHP, vec![(Mode::Implied, 0x08, 3)]),
+            (Mnemonic::PLA, vec![(Mode::Implied, 0x68, 4)]),
+            (Mnemonic::PLP, vec![(Mode::Implied, 0x28, 4)]),
             (
                 Mnemonic::ROL,
                 vec![
-                    (Mode::Accumulator, 0x2A),
-                    (Mode::ZeroPage, 0x26),
-                    (Mode::ZeroPageX, 0x36),
-                    (Mode::Absolute, 0x2E),
-                    (Mode::AbsoluteX, 0x3E),
+                    (Mode::Accumulator, 0x2A, 2),
+                    (Mode::ZeroPage, 0x26, 5),
+                    (Mode::ZeroPageX, 0x36, 6),
+                    (Mode::Absolute, 0x2E, 6),
+                    (Mode::AbsoluteX, 0x3E, 7),
                 ],
             ),
             (
                 Mnemonic::ROR,
                 vec![
-                    (Mode::Accumulator, 0x6A),
-                    (Mode::ZeroPage, 0x66),
-                    (Mode::ZeroPageX, 0x76),
-                    (Mode::Absolute, 0x6E),
-                    (Mode::AbsoluteX, 0x7E),
+                    (Mode::Accumulator, 0x6A, 2),
+                    (Mode::ZeroPage, 0x66, 5),
+                    (Mode::ZeroPageX, 0x76, 6),
+                    (Mode::Absolute, 0x6E, 6),
+                    (Mode::AbsoluteX, 0x7E, 7),
                 ],
             ),
-            (Mnemonic::RTI, vec![(Mode::Implied, 0x40)]),
-            (Mnemonic::RTS, vec![(Mode::Implied, 0x60)]),
+            (Mnemonic::RTI, vec![(Mode::Implied, 0x40, 6)]),
+            (Mnemonic::RTS, vec![(Mode::Implied, 0x60, 6)]),
             (
                 Mnemonic::SBC,
                 vec![
-                    (Mode::Immediate, 0xE9),
-                    (Mode::ZeroPage, 0xE5),
-                    (Mode::ZeroPageX, 0xF5),
-                    (Mode::Absolute, 0xED),
-                    (Mode::AbsoluteX, 0xFD),
-                    (Mode::AbsoluteY, 0xF9),
-                    (Mode::IndirectX, 0xE1),
-                    (Mode::IndirectY, 0xF1),
+                    (Mode::Immediate, 0xE9, 2),
+                    (Mode::ZeroPage, 0xE5, 3),
+                    (Mode::ZeroPageX, 0xF5, 4),
+                    (Mode::Absolute, 0xED, 4),
+                    (Mode::AbsoluteX, 0xFD, 4),
+                    (Mode::AbsoluteY, 0xF9, 4),
+                    (Mode::IndirectX, 0xE1, 6),
+                    (Mode::IndirectY, 0xF1, 5),
                 ],
             ),
-            (Mnemonic::SEC, vec![(Mode::Implied, 0x38)]),
-            (Mnemonic::SED, vec![(Mode::Implied, 0xF8)]),
-            (Mnemonic::SEI, vec![(Mode::Implied, 0x78)]),
+            (Mnemonic::SEC, vec![(Mode::Implied, 0x38, 2)]),
+            (Mnemonic::SED, vec![(Mode::Implied, 0xF8, 2)]),
+            (Mnemonic::SEI, vec![(Mode::Implied, 0x78, 2)]),
             (
                 Mnemonic::STA,
                 vec![
-                    (Mode::ZeroPage, 0x85),
-                    (Mode::ZeroPageX, 0x95),
-                    (Mode::Absolute, 0x8D),
-                    (Mode::AbsoluteX, 0x9D),
-                    (Mode::AbsoluteY, 0x99),
-                    (Mode::IndirectX, 0x81),
-                    (Mode::IndirectY, 0x91),
+                    (Mode::ZeroPage, 0x85, 3),
+                    (Mode::ZeroPageX, 0x95, 4),
+                    (Mode::Absolute, 0x8D, 4),
+                    (Mode::AbsoluteX, 0x9D, 5),
+                    (Mode::AbsoluteY, 0x99, 5),
+                    (Mode::IndirectX, 0x81, 6),
+                    (Mode::IndirectY, 0x91, 6),
                 ],
             ),
             (
                 Mnemonic::STX,
                 vec![
-                    (Mode::ZeroPage, 0x86),
-                    (Mode::ZeroPageY, 0x96),
-                    (Mode::Absolute, 0x8E),
+                    (Mode::ZeroPage, 0x86, 3),
+                    (Mode::ZeroPageY, 0x96, 4),
+                    (Mode::Absolute, 0x8E, 4),
                 ],
             ),
             (
                 Mnemonic::STY,
                 vec![
-                    (Mode::ZeroPage, 0x84),
-                    (Mode::ZeroPageX, 0x94),
-                    (Mode::Absolute, 0x8C),
+                    (Mode::ZeroPage, 0x84, 3),
+                    (Mode::ZeroPageX, 0x94, 4),
+                    (Mode::Absolute, 0x8C, 4),
                 ],
             ),
-            (Mnemonic::TAX, vec![(Mode::Implied, 0xAA)]),
-            (Mnemonic::TAY, vec![(Mode::Implied, 0xA8)]),
-            (Mnemonic::TSX, vec![(Mode::Implied, 0xBA)]),
-            (Mnemonic::TXA, vec![(Mode::Implied, 0x8A)]),
-            (Mnemonic::TXS, vec![(Mode::Implied, 0x9A)]),
-            (Mnemonic::TYA, vec![(Mode::Implied, 0x98)]),
+            (Mnemonic::TAX, vec![(Mode::Implied, 0xAA, 2)]),
+            (Mnemonic::TAY, vec![(Mode::Implied, 0xA8, 2)]),
+            (Mnemonic::TSX, vec![(Mode::Implied, 0xBA, 2)]),
+            (Mnemonic::TXA, vec![(Mode::Implied, 0x8A, 2)]),
+            (Mnemonic::TXS, vec![(Mode::Implied, 0x9A, 2)]),
+            (Mnemonic::TYA, vec![(Mode::Implied, 0x98, 2)]),
         ]
     }
 }
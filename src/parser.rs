@@ -23,6 +23,15 @@ pub struct Line {
     pub label: Option<String>,
     pub statements: Vec<Statement>,
     pub object_codes: Vec<u8>,
+    // where the line was read from, None for generated lines
+    pub origin: Option<Origin>,
+}
+
+// file and line in that file (line_number also counts the included lines)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
 }
 
 impl Line {
@@ -39,6 +48,7 @@ impl Line {
             label,
             statements,
             object_codes,
+            origin: None,
         }
     }
 
@@ -49,19 +59,45 @@ impl Line {
             label: Some(label.to_string()),
             statements: vec![],
             object_codes: vec![],
+            origin: self.origin.clone(),
+        }
+    }
+
+    // a line of code generated for this line (macro expansion), keeps its number and origin
+    pub fn derive(&self, label: Option<String>, statements: Vec<Statement>) -> Self {
+        Self {
+            line_number: self.line_number,
+            address: self.address,
+            label,
+            statements,
+            object_codes: vec![],
+            origin: self.origin.clone(),
+        }
+    }
+
+    // file:line when the origin is known, line: N otherwise
+    pub fn location(&self) -> String {
+        match &self.origin {
+            Some(origin) => format!("{}:{}", origin.file, origin.line),
+            None => format!("line: {}", self.line_number),
         }
     }
 }
 
 // make abstract syntax tree from input file
-pub fn parse_from_file(file: &File, file_path: PathBuf) -> Result<Vec<Line>, AssemblyError> {
+pub fn parse_from_file(
+    file: &File,
+    file_path: PathBuf,
+    file_name: &str,
+) -> Result<Vec<Line>, AssemblyError> {
     let reader = BufReader::new(file);
-    let include_reader = IncludeReader::new(reader, file_path);
+    let include_reader = IncludeReader::new(reader, file_path, file_name);
     let mut lines = Vec::new();
 
     for (num, res) in include_reader.lines().enumerate() {
-        if let Ok(line) = res {
-            let line = parse_line(line, num + 1)?;
+        if let Ok((origin, line)) = res {
+            let mut line = parse_line(line, num + 1)?;
+            line.origin = Some(origin);
             lines.push(line);
         }
     }
@@ -86,9 +122,15 @@ fn parse_line(line: String, line_num: usize) -> Result<Line, AssemblyError> {
     ))
 }
 
+// statements of code written by the macros (A=0 X=8 ...)
+pub fn parse_body(body: &str) -> Result<Vec<Statement>, AssemblyError> {
+    parse_statements(tokenize(&remove_comment(body)))
+}
+
 // source line format
-fn match_line(line: &str, line_num: usize) -> Result<Captures, AssemblyError> {
-    let re = Regex::new(r"^(?<label>[.a-zA-Z][a-zA-Z0-9_]*)?(?<body>\s+.*)?").unwrap();
+fn match_line(line: &str, line_num: usize) -> Result<Captures<'_>, AssemblyError> {
+    // ':' alone is an anonymous label
+    let re = Regex::new(r"^(?<label>[.a-zA-Z][a-zA-Z0-9_]*|:)?(?<body>\s+.*)?").unwrap();
     re.captures(line).ok_or(AssemblyError::line(line_num, line))
 }
 
@@ -104,7 +146,7 @@ fn parse_statements(tokens: Vec<String>) -> Result<Vec<Statement>, AssemblyError
 fn parse_token(token: &str) -> Result<Statement, AssemblyError> {
     // regex to match assignment or single character
     let assignment_pattern = Regex::new(r"^(?P<command>[^=]+)=(?P<operand>.+)$").unwrap();
-    let single_pattern = Regex::new(r"(?P<command>\S)").unwrap();
+    let single_pattern = Regex::new(r"^(?P<command>\.[a-zA-Z][a-zA-Z0-9_]*$|\S)").unwrap();
     let cap = assignment_pattern
         .captures(token)
         .or_else(|| single_pattern.captures(token))
@@ -221,6 +263,13 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_directive_without_operand() {
+        let statement = parse_token(".endpage").unwrap();
+        assert_eq!(statement.command, Expr::Identifier(".endpage".to_string()));
+        assert_eq!(statement.expression, Expr::Empty);
+    }
+
     #[test]
     fn test_tokenize() {
         let tokens = tokenize("@ A=1 B=2 C=\"hello world\",0 (0)=A");
@@ -5,13 +5,14 @@ use nom::{
     bytes::complete::tag,
     bytes::complete::take_while_m_n,
     character::complete::{alpha1, alphanumeric1, digit1, none_of, one_of},
-    combinator::{map, verify},
+    combinator::{map, not, opt, verify},
     combinator::{map_res, recognize},
-    multi::{many0, many1},
+    multi::{many0, many1, separated_list0},
     sequence::delimited,
-    sequence::{preceded, tuple},
+    sequence::{preceded, terminated, tuple},
     IResult,
 };
+use std::convert::Infallible;
 use std::num::ParseIntError;
 use std::str::FromStr;
 
@@ -20,6 +21,7 @@ use crate::{
     error::AssemblyError::{self},
 };
 
+mod function;
 pub mod matcher;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -34,6 +36,8 @@ pub enum Operator {
     Comma,
     Greater,
     Less,
+    GreaterEqual,
+    LessEqual,
     Equal,
     NotEqual,
 }
@@ -53,6 +57,8 @@ impl fmt::Display for Operator {
             Operator::Comma => ",",
             Operator::Greater => ">",
             Operator::Less => "<",
+            Operator::GreaterEqual => ">=",
+            Operator::LessEqual => "<=",
             Operator::Equal => "=",
             Operator::NotEqual => "\\",
         };
@@ -60,6 +66,13 @@ impl fmt::Display for Operator {
     }
 }
 
+// (a:$10) forces absolute, (z:label) forces zero page addressing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    ZeroPage,
+    Absolute,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     DecimalNum(u16),
@@ -73,9 +86,37 @@ pub enum Expr {
     Parenthesized(Box<Expr>),
     Bracketed(Box<Expr>),
     SystemOperator(String),
+    Function(String, Vec<Expr>),
+    Forced(Width, Box<Expr>),
     Empty,
 }
 
+// source form of the expression (used by the listing)
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::DecimalNum(n) => write!(f, "{}", n),
+            Expr::ByteNum(n) => write!(f, "${:02X}", n),
+            Expr::WordNum(n) => write!(f, "${:04X}", n),
+            Expr::HiByte(expr) => write!(f, ">{}", expr),
+            Expr::LoByte(expr) => write!(f, "<{}", expr),
+            Expr::StringLiteral(s) => write!(f, "\"{}\"", s),
+            Expr::Identifier(name) => write!(f, "{}", name),
+            Expr::BinOp(left, op, right) => write!(f, "{}{}{}", left, op, right),
+            Expr::Parenthesized(expr) => write!(f, "({})", expr),
+            Expr::Bracketed(expr) => write!(f, "[{}]", expr),
+            Expr::SystemOperator(s) => write!(f, "{}", s),
+            Expr::Function(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(","))
+            }
+            Expr::Forced(Width::ZeroPage, expr) => write!(f, "z:{}", expr),
+            Expr::Forced(Width::Absolute, expr) => write!(f, "a:{}", expr),
+            Expr::Empty => Ok(()),
+        }
+    }
+}
+
 impl Expr {
     pub fn parse(input: &str) -> Result<Expr, AssemblyError> {
         if input.is_empty() {
@@ -103,9 +144,60 @@ impl Expr {
         }
     }
 
+    // copy of the expression, sub-expressions for which `f` returns Some(..) are replaced
+    pub fn rewrite<E>(
+        self: &Expr,
+        f: &dyn Fn(&Expr) -> Result<Option<Expr>, E>,
+    ) -> Result<Expr, E> {
+        if let Some(expr) = f(self)? {
+            return Ok(expr);
+        }
+        let rewrite = |expr: &Expr| expr.rewrite(f).map(Box::new);
+        Ok(match self {
+            Expr::HiByte(expr) => Expr::HiByte(rewrite(expr)?),
+            Expr::LoByte(expr) => Expr::LoByte(rewrite(expr)?),
+            Expr::BinOp(left, op, right) => {
+                Expr::BinOp(rewrite(left)?, op.clone(), rewrite(right)?)
+            }
+            Expr::Parenthesized(expr) => Expr::Parenthesized(rewrite(expr)?),
+            Expr::Bracketed(expr) => Expr::Bracketed(rewrite(expr)?),
+            Expr::Function(name, args) => Expr::Function(
+                name.clone(),
+                args.iter()
+                    .map(|arg| arg.rewrite(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Expr::Forced(width, expr) => Expr::Forced(*width, rewrite(expr)?),
+            _ => self.clone(),
+        })
+    }
+
+    // copy of the expression with '*' replaced by the address
+    pub fn replace_current_address(self: &Expr, address: u16) -> Expr {
+        let replace = |expr: &Expr| -> Result<_, Infallible> {
+            Ok(matches!(expr, Expr::SystemOperator(s) if s == "*")
+                .then_some(Expr::WordNum(address)))
+        };
+        let Ok(expr) = self.rewrite(&replace);
+        expr
+    }
+
+    // copy of the expression with every call of function `name` replaced by `f(args)`
+    pub fn replace_function(
+        self: &Expr,
+        name: &str,
+        f: &dyn Fn(&[Expr]) -> Result<Expr, AssemblyError>,
+    ) -> Result<Expr, AssemblyError> {
+        self.rewrite(&|expr| match expr {
+            Expr::Function(function, args) if function == name => f(args).map(Some),
+            _ => Ok(None),
+        })
+    }
+
     pub fn calculate_address(self: &Expr, labels: &LabelTable) -> Result<Address, AssemblyError> {
         match self {
-            Expr::DecimalNum(n) => Ok(Address::ZeroPage(*n as u8)),
+            Expr::DecimalNum(n @ 0..=0xff) => Ok(Address::ZeroPage(*n as u8)),
+            Expr::DecimalNum(n) => Ok(Address::Full(*n)),
             Expr::ByteNum(n) => Ok(Address::ZeroPage(*n)),
             Expr::WordNum(n) => Ok(Address::Full(*n)),
             Expr::Identifier(name) => {
@@ -119,7 +211,13 @@ impl Expr {
                 let right = right.calculate_address(labels)?;
                 left.calculate_with(&right, op)
             }
-            Expr::Parenthesized(expr) => expr.calculate_address(labels),
+            Expr::Parenthesized(expr) | Expr::Forced(_, expr) => expr.calculate_address(labels),
+            Expr::HiByte(_) | Expr::LoByte(_) | Expr::Function(_, _) => {
+                match self.evaluate(labels, &0)? {
+                    n @ 0..=0xff => Ok(Address::ZeroPage(n as u8)),
+                    n => Ok(Address::Full(n)),
+                }
+            }
             _ => Err(AssemblyError::program(
                 "calculate_address(): invalid label address",
             )),
@@ -151,15 +249,19 @@ impl Expr {
                 let left = left.evaluate(labels, current_address)?;
                 let right = right.evaluate(labels, current_address)?;
                 match op {
-                    Operator::Add => Ok(left + right),
-                    Operator::Sub => Ok(left - right),
-                    Operator::Mul => Ok(left * right),
-                    Operator::Div => Ok(left / right),
+                    Operator::Add => Ok(left.wrapping_add(right)),
+                    Operator::Sub => Ok(left.wrapping_sub(right)),
+                    Operator::Mul => Ok(left.wrapping_mul(right)),
+                    Operator::Div => left
+                        .checked_div(right)
+                        .ok_or(AssemblyError::expression("division by zero")),
                     Operator::And => Ok(left & right),
                     Operator::Or => Ok(left | right),
                     Operator::Eor => Ok(left ^ right),
-                    Operator::Greater => Ok(if left >= right { 1 } else { 0 }),
+                    Operator::Greater => Ok(if left > right { 1 } else { 0 }),
                     Operator::Less => Ok(if left < right { 1 } else { 0 }),
+                    Operator::GreaterEqual => Ok(if left >= right { 1 } else { 0 }),
+                    Operator::LessEqual => Ok(if left <= right { 1 } else { 0 }),
                     Operator::Equal => Ok(if left == right { 1 } else { 0 }),
                     Operator::NotEqual => Ok(if left != right { 1 } else { 0 }),
                     _ => Err(AssemblyError::program("evaluate(): invalid operator")),
@@ -169,6 +271,10 @@ impl Expr {
                 let address = expr.calculate_address(labels)?;
                 Self::address_to_u16(&address)
             }
+            Expr::HiByte(expr) => Ok(expr.evaluate(labels, current_address)? >> 8),
+            Expr::LoByte(expr) => Ok(expr.evaluate(labels, current_address)? & 0xff),
+            Expr::Function(name, args) => function::evaluate(name, args, labels, current_address),
+            Expr::Forced(_, expr) => expr.evaluate(labels, current_address),
             _ => Err(AssemblyError::program("evaluate(): invalid label address")),
         }
     }
@@ -190,6 +296,7 @@ fn parse_expr(input: &str) -> IResult<&str, Expr> {
         parse_char,
         parse_hibyte,
         parse_lobyte,
+        parse_function,
         parse_identifier,
         parse_parenthesized,
         parse_bracketed,
@@ -200,12 +307,14 @@ fn parse_expr(input: &str) -> IResult<&str, Expr> {
 
 fn parse_term(input: &str) -> IResult<&str, Expr> {
     alt((
+        parse_current_address,
         parse_decimal,
         parse_hex,
         parse_bin,
         parse_char,
         parse_hibyte,
         parse_lobyte,
+        parse_function,
         parse_identifier,
         parse_parenthesized,
         parse_bracketed,
@@ -214,12 +323,34 @@ fn parse_term(input: &str) -> IResult<&str, Expr> {
     ))(input)
 }
 
+// comma has the lowest precedence: a+1,b-1 == (a+1),(b-1)
 fn parse_bin_op(input: &str) -> IResult<&str, Expr> {
-    let (input, (left, op, right)) = tuple((parse_term, parse_operator, parse_expr))(input)?;
+    alt((parse_comma_list, parse_arithmetic))(input)
+}
+
+fn parse_comma_list(input: &str) -> IResult<&str, Expr> {
+    let (input, first) = parse_operand(input)?;
+    let (input, mut rest) = many1(preceded(tag(","), parse_operand))(input)?;
+    let mut expr = rest.pop().unwrap();
+    while let Some(left) = rest.pop() {
+        expr = Expr::BinOp(Box::new(left), Operator::Comma, Box::new(expr));
+    }
+    Ok((
+        input,
+        Expr::BinOp(Box::new(first), Operator::Comma, Box::new(expr)),
+    ))
+}
+
+fn parse_arithmetic(input: &str) -> IResult<&str, Expr> {
+    let (input, (left, op, right)) = tuple((parse_term, parse_operator, parse_operand))(input)?;
 
     Ok((input, Expr::BinOp(Box::new(left), op, Box::new(right))))
 }
 
+fn parse_operand(input: &str) -> IResult<&str, Expr> {
+    alt((parse_arithmetic, parse_term))(input)
+}
+
 fn parse_operator(input: &str) -> IResult<&str, Operator> {
     alt((
         map(tag("+"), |_| Operator::Add),
@@ -229,7 +360,8 @@ fn parse_operator(input: &str) -> IResult<&str, Operator> {
         map(tag("&"), |_| Operator::And),
         map(tag("|"), |_| Operator::Or),
         map(tag("^"), |_| Operator::Eor),
-        map(tag(","), |_| Operator::Comma),
+        map(tag(">="), |_| Operator::GreaterEqual),
+        map(tag("<="), |_| Operator::LessEqual),
         map(tag(">"), |_| Operator::Greater),
         map(tag("<"), |_| Operator::Less),
         map(tag("="), |_| Operator::Equal),
@@ -302,21 +434,64 @@ pub fn parse_lobyte(input: &str) -> IResult<&str, Expr> {
     )(input)
 }
 
+// min(a,b)  sin(i,40)  len("text") ...
+fn parse_function(input: &str) -> IResult<&str, Expr> {
+    let (rest, name) = alpha1(input)?;
+    if !function::NAMES.contains(&name) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+    let (rest, args) =
+        delimited(tag("("), separated_list0(tag(","), parse_operand), tag(")"))(rest)?;
+    let expr = match (name, args.as_slice()) {
+        ("hi", [arg]) => Expr::HiByte(Box::new(arg.clone())),
+        ("lo", [arg]) => Expr::LoByte(Box::new(arg.clone())),
+        _ => Expr::Function(name.to_string(), args),
+    };
+    Ok((rest, expr))
+}
+
+// label, .local, module::label
 fn parse_identifier(input: &str) -> IResult<&str, Expr> {
     map(
         recognize(tuple((
             alt((alpha1, tag("_"), tag("."))),
             many0(alt((alphanumeric1, tag("_")))),
+            opt(tuple((
+                tag("::"),
+                alt((alpha1, tag("_"))),
+                many0(alt((alphanumeric1, tag("_")))),
+            ))),
+            // Sprite.x (struct field / enum member)
+            many0(tuple((
+                tag("."),
+                alt((alpha1, tag("_"))),
+                many0(alt((alphanumeric1, tag("_")))),
+            ))),
         ))),
         |id_str: &str| Expr::Identifier(id_str.to_string()),
     )(input)
 }
 
 fn parse_parenthesized(input: &str) -> IResult<&str, Expr> {
-    delimited(tag("("), parse_expr, tag(")"))(input)
+    delimited(tag("("), alt((parse_forced, parse_expr)), tag(")"))(input)
         .map(|(remaining_input, expr)| (remaining_input, Expr::Parenthesized(Box::new(expr))))
 }
 
+// a:$10+X (not a::name)
+fn parse_forced(input: &str) -> IResult<&str, Expr> {
+    let width = alt((
+        map(tag("a:"), |_| Width::Absolute),
+        map(tag("z:"), |_| Width::ZeroPage),
+    ));
+    map(
+        tuple((terminated(width, not(tag(":"))), parse_expr)),
+        |(width, expr)| Expr::Forced(width, Box::new(expr)),
+    )(input)
+}
+
 fn parse_bracketed(input: &str) -> IResult<&str, Expr> {
     delimited(tag("["), parse_expr, tag("]"))(input)
         .map(|(remaining_input, expr)| (remaining_input, Expr::Bracketed(Box::new(expr))))
@@ -332,6 +507,11 @@ fn parse_sysop(input: &str) -> IResult<&str, Expr> {
     )(input)
 }
 
+// '*' as the left operand of a binary operation (*+2, *-label)
+fn parse_current_address(input: &str) -> IResult<&str, Expr> {
+    map(tag("*"), |s: &str| Expr::SystemOperator(s.to_string()))(input)
+}
+
 fn parse_escaped_string(input: &str) -> IResult<&str, &str> {
     recognize(many0(alt((
         alphanumeric1,
@@ -395,6 +575,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_identifier_with_field() {
+        assert_eq!(
+            parse_identifier("Sprite.x+X"),
+            Ok(("+X", Expr::Identifier("Sprite.x".to_string())))
+        );
+        assert_eq!(
+            parse_identifier("gfx::Sprite.x"),
+            Ok(("", Expr::Identifier("gfx::Sprite.x".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_parse_forced_width() {
+        assert_eq!(
+            Expr::parse("(a:$10+X)").unwrap().to_string(),
+            "(a:$10+X)".to_string()
+        );
+        assert_eq!(
+            Expr::parse("(z:label)"),
+            Ok(Expr::Parenthesized(Box::new(Expr::Forced(
+                Width::ZeroPage,
+                Box::new(Expr::Identifier("label".to_string()))
+            ))))
+        );
+        // module::name is not a width
+        assert_eq!(
+            Expr::parse("(a::name)"),
+            Ok(Expr::Parenthesized(Box::new(Expr::Identifier(
+                "a::name".to_string()
+            ))))
+        );
+    }
+
     #[test]
     fn test_parse_identifier_with_unexpected_character() {
         assert_eq!(
@@ -438,6 +652,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_comma_lowest_precedence() {
+        assert_eq!(
+            Expr::parse("a-1,b").unwrap().traverse_comma(),
+            vec![
+                Expr::BinOp(
+                    Box::new(Expr::Identifier("a".to_string())),
+                    Operator::Sub,
+                    Box::new(Expr::DecimalNum(1))
+                ),
+                Expr::Identifier("b".to_string()),
+            ]
+        );
+    }
+
+    // forms written before comma had the lowest precedence keep the same tree
+    #[test]
+    fn test_parse_comma_existing_forms() {
+        let comma =
+            |left: Expr, right: Expr| Expr::BinOp(Box::new(left), Operator::Comma, Box::new(right));
+        assert_eq!(
+            Expr::parse("EQ,.skip").unwrap(),
+            comma(
+                Expr::Identifier("EQ".to_string()),
+                Expr::Identifier(".skip".to_string())
+            )
+        );
+        assert_eq!(
+            Expr::parse("$0f,$d0a0-*").unwrap(),
+            comma(
+                Expr::ByteNum(0x0f),
+                Expr::BinOp(
+                    Box::new(Expr::WordNum(0xd0a0)),
+                    Operator::Sub,
+                    Box::new(Expr::SystemOperator("*".to_string()))
+                )
+            )
+        );
+        assert_eq!(
+            Expr::parse("$0F,$20,\"*\",41").unwrap(),
+            comma(
+                Expr::ByteNum(0x0f),
+                comma(
+                    Expr::ByteNum(0x20),
+                    comma(Expr::StringLiteral("*".to_string()), Expr::DecimalNum(41))
+                )
+            )
+        );
+        assert_eq!(
+            Expr::parse("(hello+X)").unwrap(),
+            Expr::Parenthesized(Box::new(Expr::BinOp(
+                Box::new(Expr::Identifier("hello".to_string())),
+                Operator::Add,
+                Box::new(Expr::Identifier("X".to_string()))
+            )))
+        );
+    }
+
     #[test]
     fn test_parse_expr() {
         assert_eq!(
@@ -451,6 +723,17 @@ mod tests {
                 )
             ))
         );
+        assert_eq!(
+            parse_expr("X<=10"),
+            Ok((
+                "",
+                Expr::BinOp(
+                    Box::new(Expr::Identifier("X".to_string())),
+                    Operator::LessEqual,
+                    Box::new(Expr::DecimalNum(10))
+                )
+            ))
+        );
         assert_eq!(
             parse_expr(".skip"),
             Ok(("", Expr::Identifier(".skip".to_string())))
@@ -481,6 +764,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_current_address_offset() {
+        assert_eq!(
+            parse_expr("*+2"),
+            Ok((
+                "",
+                Expr::BinOp(
+                    Box::new(Expr::SystemOperator("*".to_string())),
+                    Operator::Add,
+                    Box::new(Expr::DecimalNum(2))
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_hibyte_lobyte() {
+        let mut labels = LabelTable::new();
+        labels.insert(
+            "label".to_string(),
+            crate::assembler::LabelEntry {
+                name: "label".to_string(),
+                line: 0,
+                address: Address::Full(0x1234),
+            },
+        );
+        let expr = Expr::parse(">label").unwrap();
+        assert_eq!(expr.evaluate(&labels, &0), Ok(0x12));
+        let expr = Expr::parse("<label").unwrap();
+        assert_eq!(expr.evaluate(&labels, &0), Ok(0x34));
+    }
+
+    #[test]
+    fn test_evaluate_division_by_zero() {
+        let expr = Expr::parse("4/0").unwrap();
+        assert_eq!(
+            expr.evaluate(&LabelTable::new(), &0),
+            Err(AssemblyError::expression("division by zero"))
+        );
+        let expr = Expr::parse("4/2").unwrap();
+        assert_eq!(expr.evaluate(&LabelTable::new(), &0), Ok(2));
+    }
+
     #[test]
     fn test_absolute_x_0x0000() {
         assert_eq!(
@@ -0,0 +1,122 @@
+use std::f64::consts::PI;
+
+use super::Expr;
+use crate::{assembler::LabelTable, error::AssemblyError};
+
+// built-in functions (hi and lo are parsed as HiByte / LoByte)
+pub const NAMES: [&str; 13] = [
+    "hi", "lo", "min", "max", "abs", "sin", "cos", "len", "filesize", "defined", "bank", "word",
+    "signed",
+];
+
+// bank size used by bank(address) when no size is given (16KB PRG bank)
+const DEFAULT_BANK_SIZE: u16 = 0x4000;
+
+/**
+ * min(a,b,...)   max(a,b,...)
+ * abs(n)         n を符号付き 16bit として絶対値を返す
+ * sin(n) cos(n)  1 周 = 256, 128 を中心に 1..255 の値を返す
+ * sin(n,amp)     -amp..amp (符号付き 16bit)
+ * len("text")    文字列の長さ
+ * defined(name)  ラベルが定義済みなら 1
+ * bank(addr)     addr / $4000, bank(addr,size) = addr / size
+ * filesize("f")  アセンブル前 (条件アセンブルの段階) にファイルサイズに置き換えられる
+ * word(p)        16bit マクロ専用 (assembly_macro::word)
+ * signed(x)      IF / DO マクロの符号付き比較専用 (assembly_macro::condition)
+ */
+pub fn evaluate(
+    name: &str,
+    args: &[Expr],
+    labels: &LabelTable,
+    current_address: &u16,
+) -> Result<u16, AssemblyError> {
+    let values = || -> Result<Vec<u16>, AssemblyError> {
+        args.iter()
+            .map(|arg| arg.evaluate(labels, current_address))
+            .collect()
+    };
+    match (name, args) {
+        ("min", [_, ..]) => Ok(values()?.into_iter().min().unwrap()),
+        ("max", [_, ..]) => Ok(values()?.into_iter().max().unwrap()),
+        ("abs", [_]) => Ok((values()?[0] as i16).unsigned_abs()),
+        ("sin" | "cos", [_] | [_, _]) => {
+            let values = values()?;
+            let angle = values[0] as f64 * 2.0 * PI / 256.0;
+            let ratio = if name == "sin" {
+                angle.sin()
+            } else {
+                angle.cos()
+            };
+            match values.get(1) {
+                Some(amplitude) => Ok(((*amplitude as i16 as f64) * ratio).round() as i16 as u16),
+                None => Ok((128.0 + 127.0 * ratio).round() as u16),
+            }
+        }
+        ("len", [Expr::StringLiteral(s)]) => Ok(s.len() as u16),
+        ("defined", [Expr::Identifier(label)]) => Ok(labels.contains_key(label) as u16),
+        ("bank", [_] | [_, _]) => {
+            let values = values()?;
+            let size = values.get(1).copied().unwrap_or(DEFAULT_BANK_SIZE);
+            if size == 0 {
+                return Err(AssemblyError::program("bank(): bank size is 0"));
+            }
+            Ok(values[0] / size)
+        }
+        ("filesize", _) => Err(AssemblyError::program(
+            "filesize(): must be resolved before assembly",
+        )),
+        ("word", _) => Err(AssemblyError::program(
+            "word(): only in 16 bit statements (word(p)=word(p)+1 etc.)",
+        )),
+        ("signed", _) => Err(AssemblyError::program(
+            "signed(): only in IF / DO conditions (;=signed(A)<0 etc.)",
+        )),
+        _ => Err(AssemblyError::program(&format!(
+            "invalid arguments: {}",
+            Expr::Function(name.to_string(), args.to_vec())
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(input: &str) -> Result<u16, AssemblyError> {
+        Expr::parse(input)?.evaluate(&LabelTable::new(), &0)
+    }
+
+    #[test]
+    fn test_min_max_abs() {
+        assert_eq!(eval("min(3,1,2)"), Ok(1));
+        assert_eq!(eval("max(3,$100,2)"), Ok(0x100));
+        assert_eq!(eval("abs(0-5)"), Ok(5));
+        assert_eq!(eval("abs(5)"), Ok(5));
+        assert!(eval("min()").is_err());
+    }
+
+    #[test]
+    fn test_sin_cos() {
+        assert_eq!(eval("sin(0)"), Ok(128));
+        assert_eq!(eval("sin(64)"), Ok(255));
+        assert_eq!(eval("sin(192)"), Ok(1));
+        assert_eq!(eval("cos(0)"), Ok(255));
+        assert_eq!(eval("sin(64,40)"), Ok(40));
+        assert_eq!(eval("sin(192,40)"), Ok(0u16.wrapping_sub(40)));
+    }
+
+    #[test]
+    fn test_len_defined_bank() {
+        assert_eq!(eval("len(\"HELLO\")"), Ok(5));
+        assert_eq!(eval("defined(nothing)"), Ok(0));
+        assert_eq!(eval("bank($C123)"), Ok(3));
+        assert_eq!(eval("bank($C123,$2000)"), Ok(6));
+        assert!(eval("filesize(\"a.bin\")").is_err());
+    }
+
+    #[test]
+    fn test_hi_lo_of_expression() {
+        assert_eq!(eval("hi($1234+$10)"), Ok(0x12));
+        assert_eq!(eval("lo($1234+$10)"), Ok(0x44));
+    }
+}
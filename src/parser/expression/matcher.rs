@@ -32,7 +32,7 @@ pub fn num16bit(expr: &Expr) -> Result<u16, AssemblyError> {
 }
 
 fn decimal8bit(expr: &Expr) -> Result<u8, AssemblyError> {
-    decimal(&expr).and_then(|num| {
+    decimal(expr).and_then(|num| {
         if num > 255 {
             Err(AssemblyError::syntax("operand must be 8bit"))
         } else {
@@ -62,6 +62,21 @@ pub fn register_x(expr: &Expr) -> Result<(), AssemblyError> {
     }
 }
 
+pub fn register_s(expr: &Expr) -> Result<(), AssemblyError> {
+    match expr {
+        Expr::Identifier(s) if s == "S" => Ok(()),
+        _ => syntax_error("invalid register S"),
+    }
+}
+
+// processor status
+pub fn register_p(expr: &Expr) -> Result<(), AssemblyError> {
+    match expr {
+        Expr::Identifier(s) if s == "P" => Ok(()),
+        _ => syntax_error("invalid register P"),
+    }
+}
+
 pub fn register_a(expr: &Expr) -> Result<(), AssemblyError> {
     match expr {
         Expr::Identifier(s) if s == "A" => Ok(()),
@@ -97,15 +112,11 @@ pub fn lo(expr: &Expr) -> Result<Expr, AssemblyError> {
     }
 }
 
-fn ok2(a: &Box<Expr>, c: &Box<Expr>) -> Result<(Expr, Expr), AssemblyError> {
-    Ok((*a.clone(), *c.clone()))
+fn ok2(a: &Expr, c: &Expr) -> Result<(Expr, Expr), AssemblyError> {
+    Ok((a.clone(), c.clone()))
 }
-fn ok3(
-    a: &Box<Expr>,
-    b: &Operator,
-    c: &Box<Expr>,
-) -> Result<(Expr, Operator, Expr), AssemblyError> {
-    Ok((*a.clone(), b.clone(), *c.clone()))
+fn ok3(a: &Expr, b: &Operator, c: &Expr) -> Result<(Expr, Operator, Expr), AssemblyError> {
+    Ok((a.clone(), b.clone(), c.clone()))
 }
 
 pub fn comma(expr: &Expr) -> Result<(Expr, Expr), AssemblyError> {
@@ -122,6 +133,18 @@ pub fn plus(expr: &Expr) -> Result<(Expr, Expr), AssemblyError> {
     }
 }
 
+// a+b+X is parsed as a+(b+X): split off the last term of the sum
+pub fn plus_last(expr: &Expr) -> Result<(Expr, Expr), AssemblyError> {
+    let (left, right) = plus(expr)?;
+    match plus_last(&right) {
+        Ok((middle, last)) => Ok((
+            Expr::BinOp(Box::new(left), Operator::Add, Box::new(middle)),
+            last,
+        )),
+        Err(_) => Ok((left, right)),
+    }
+}
+
 pub fn minus(expr: &Expr) -> Result<(Expr, Expr), AssemblyError> {
     match expr {
         Expr::BinOp(left, Operator::Sub, right) => ok2(left, right),
@@ -171,6 +194,14 @@ pub fn sysop(expr: &Expr) -> Result<String, AssemblyError> {
     }
 }
 
+// command without operand (! or ] alone)
+pub fn empty(expr: &Expr) -> Result<(), AssemblyError> {
+    match expr {
+        Expr::Empty => Ok(()),
+        _ => syntax_error("operand not expected"),
+    }
+}
+
 pub fn sysop_or_identifier(expr: &Expr) -> Result<String, AssemblyError> {
     match expr {
         Expr::SystemOperator(c) => Ok(c.to_string()),
@@ -1,35 +1,47 @@
+use super::Origin;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 
+type NumberedLines = Box<dyn Iterator<Item = (usize, io::Result<String>)>>;
+
 pub struct IncludeReader<R> {
     reader: R,
-    include_stack: Vec<Box<dyn Iterator<Item = io::Result<String>>>>,
+    // name and line number of the source file
+    name: String,
+    line_number: usize,
+    include_stack: Vec<(String, NumberedLines)>,
     current_path: PathBuf,
 }
 
 impl<R: BufRead> IncludeReader<R> {
-    pub fn new(reader: R, current_path: PathBuf) -> Self {
+    pub fn new(reader: R, current_path: PathBuf, name: &str) -> Self {
         IncludeReader {
             reader,
+            name: name.to_string(),
+            line_number: 0,
             include_stack: Vec::new(),
             current_path,
         }
     }
 
-    pub fn lines(self) -> impl Iterator<Item = io::Result<String>> {
+    pub fn lines(self) -> impl Iterator<Item = io::Result<(Origin, String)>> {
         self
     }
 }
 
 impl<R: BufRead> Iterator for IncludeReader<R> {
-    type Item = io::Result<String>;
+    type Item = io::Result<(Origin, String)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if let Some(include_reader) = self.include_stack.last_mut() {
-                if let Some(line) = include_reader.next() {
-                    return Some(line);
+            if let Some((file, include_reader)) = self.include_stack.last_mut() {
+                if let Some((index, line)) = include_reader.next() {
+                    let origin = Origin {
+                        file: file.clone(),
+                        line: index + 1,
+                    };
+                    return Some(line.map(|line| (origin, line)));
                 } else {
                     self.include_stack.pop();
                 }
@@ -38,11 +50,13 @@ impl<R: BufRead> Iterator for IncludeReader<R> {
                 match self.reader.read_line(&mut line) {
                     Ok(0) => return None,
                     Ok(_) => {
+                        self.line_number += 1;
                         if let Some(filename) = detect_include_directive(&line) {
                             let path = self.current_path.join(&filename);
                             if let Ok(file) = File::open(Path::new(&path)) {
                                 let reader = BufReader::new(file);
-                                self.include_stack.push(Box::new(reader.lines()));
+                                let lines = Box::new(reader.lines().enumerate());
+                                self.include_stack.push((path.display().to_string(), lines));
                             } else {
                                 return Some(Err(io::Error::new(
                                     io::ErrorKind::NotFound,
@@ -50,7 +64,11 @@ impl<R: BufRead> Iterator for IncludeReader<R> {
                                 )));
                             }
                         } else {
-                            return Some(Ok(line));
+                            let origin = Origin {
+                                file: self.name.clone(),
+                                line: self.line_number,
+                            };
+                            return Some(Ok((origin, line)));
                         }
                     }
                     Err(e) => return Some(Err(e)),
@@ -69,6 +87,22 @@ fn detect_include_directive(s: &str) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_origin() {
+        let source = "top\n        +=\"stack.vtl\"\nbottom\n";
+        let reader = IncludeReader::new(Cursor::new(source), PathBuf::from("nesprj"), "main.vtl");
+        let origins: Vec<_> = reader.lines().map(|line| line.unwrap().0).collect();
+        let origin = |file: &str, line| Origin {
+            file: file.to_string(),
+            line,
+        };
+        assert_eq!(origins.first(), Some(&origin("main.vtl", 1)));
+        assert_eq!(origins.get(1), Some(&origin("nesprj/stack.vtl", 1)));
+        assert_eq!(origins.last(), Some(&origin("main.vtl", 3)));
+    }
+
     #[test]
     fn test_detect_include_directive() {
         let s = "    +=\"foo.inc\"";
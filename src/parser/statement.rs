@@ -1,17 +1,57 @@
-use super::expression::Operator;
-use crate::assembler::{Address, LabelTable};
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+
+use super::expression::{Operator, Width};
+use crate::assembler::{Address, LabelEntry, LabelTable};
 use crate::error::AssemblyError;
 use crate::opcode::{AddressingMode, AssemblyInstruction, OpcodeTable, OperandValue};
 use crate::parser::expression::Expr;
 pub mod decoder;
 use decoder::*;
 
+// identifiers in operands that are never labels
+const REGISTERS: [&str; 6] = ["A", "X", "Y", "S", "P", "T"];
+
+const PSEUDO_COMMANDS: [&str; 22] = [
+    "*",
+    ":",
+    "?",
+    "$",
+    "&",
+    ".byte",
+    ".word",
+    ".dbyt",
+    ".lobytes",
+    ".hibytes",
+    ".assert",
+    ".error",
+    ".warning",
+    ".print",
+    ".align",
+    ".page",
+    ".endpage",
+    ".proc",
+    ".endproc",
+    ".cycles",
+    ".endcycles",
+    ".loops",
+];
+
 // statement in a line of source code
 #[derive(Debug, Clone)]
 pub struct Statement {
     pub command: Expr,
     pub expression: Expr,
 }
+impl std::fmt::Display for Statement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.expression {
+            Expr::Empty => write!(f, "{}", self.command),
+            _ => write!(f, "{}={}", self.command, self.expression),
+        }
+    }
+}
+
 impl Statement {
     pub fn new(command: &str, expression: Expr) -> Self {
         let command = if command.chars().all(|c| c.is_alphanumeric()) {
@@ -25,6 +65,19 @@ impl Statement {
         }
     }
 
+    // rewrite the operand and (label)= style targets,
+    // register commands (A=, X=, ...) are names too and are kept as is
+    pub fn rewrite<E>(&self, f: &dyn Fn(&Expr) -> Result<Option<Expr>, E>) -> Result<Statement, E> {
+        let command = match &self.command {
+            Expr::Identifier(_) | Expr::SystemOperator(_) => self.command.clone(),
+            command => command.rewrite(f)?,
+        };
+        Ok(Statement {
+            command,
+            expression: self.expression.rewrite(f)?,
+        })
+    }
+
     pub fn command(&self) -> Result<String, AssemblyError> {
         match &self.command {
             Expr::Identifier(command) => Ok(command.clone()),
@@ -32,13 +85,14 @@ impl Statement {
             Expr::Parenthesized(_) => Ok("(#<Expr>)".to_string()),
             Expr::Bracketed(_) => Ok("[#<Expr>]".to_string()),
             Expr::BinOp(_, Operator::Add, _) => Ok("#<Expr>+#<Expr>".to_string()),
+            Expr::Function(name, _) => Ok(format!("{}()", name)),
             _ => Err(AssemblyError::syntax("must be identifier")),
         }
     }
 
     pub fn is_pseudo(&self) -> bool {
         if let Ok(command) = self.command() {
-            return ["*", ":", "?", "$", "&"].contains(&command.as_str());
+            return PSEUDO_COMMANDS.contains(&command.as_str());
         }
         false
     }
@@ -50,6 +104,73 @@ impl Statement {
     }
 
     pub fn decode(&self, labels: &LabelTable) -> Result<AssemblyInstruction, AssemblyError> {
+        match self.strip_width() {
+            (statement, Some(width)) => force_width(statement.decode_operand(labels)?, width),
+            (_, None) => self.decode_operand(labels),
+        }
+    }
+
+    /**
+     * decode with the labels not in `labels` taken as absolute $0000
+     *  - the instruction and the missing labels (forward references in pass1)
+     */
+    pub fn decode_forward(
+        &self,
+        labels: &LabelTable,
+    ) -> Result<(AssemblyInstruction, Vec<String>), AssemblyError> {
+        let error = match self.decode(labels) {
+            Ok(instruction) => return Ok((instruction, vec![])),
+            Err(error) => error,
+        };
+        let missing = self.missing_labels(labels);
+        if missing.is_empty() {
+            return Err(error);
+        }
+        let mut labels = labels.clone();
+        for name in &missing {
+            let entry = LabelEntry {
+                name: name.clone(),
+                line: 0,
+                address: Address::Full(0),
+            };
+            labels.insert(name.clone(), entry);
+        }
+        let instruction = self.decode(&labels).map_err(|_| error)?;
+        Ok((instruction, missing))
+    }
+
+    // identifiers of the statement that are not registers or known labels
+    fn missing_labels(&self, labels: &LabelTable) -> Vec<String> {
+        let missing = RefCell::new(vec![]);
+        let collect = |expr: &Expr| -> Result<_, Infallible> {
+            if let Expr::Identifier(name) = expr {
+                if !REGISTERS.contains(&name.as_str()) && !labels.contains_key(name) {
+                    missing.borrow_mut().push(name.clone());
+                }
+            }
+            Ok(None)
+        };
+        let Ok(_) = self.rewrite(&collect);
+        missing.into_inner()
+    }
+
+    // (a:addr) / (z:addr) removed from the statement
+    fn strip_width(&self) -> (Statement, Option<Width>) {
+        let found = Cell::new(None);
+        let strip = |expr: &Expr| -> Result<_, Infallible> {
+            Ok(match expr {
+                Expr::Forced(width, expr) => {
+                    found.set(Some(*width));
+                    Some(*expr.clone())
+                }
+                _ => None,
+            })
+        };
+        let Ok(statement) = self.rewrite(&strip);
+        (statement, found.get())
+    }
+
+    fn decode_operand(&self, labels: &LabelTable) -> Result<AssemblyInstruction, AssemblyError> {
         let expr = &self.expression;
         match &self.command {
             Expr::Identifier(sym) if sym == "X" => decode_x(expr, labels),
@@ -60,7 +181,8 @@ impl Statement {
                 decode_flags(&self.command, expr, labels)
             }
             Expr::Identifier(sym) if sym == "S" => decode_stack(expr, labels),
-            Expr::Identifier(sym) if sym == "_" => decode_nop(expr),
+            Expr::Identifier(sym) if sym == "P" => decode_status(expr),
+            Expr::Identifier(sym) if sym == "_" || sym == "." => decode_nop(expr),
             Expr::SystemOperator(sym) if sym == "!" => decode_call(expr, labels),
             Expr::SystemOperator(sym) if sym == "#" => decode_goto(expr, labels),
             Expr::SystemOperator(sym) if sym == ";" => decode_if(expr, labels),
@@ -68,6 +190,9 @@ impl Statement {
                 decode_shift(&self.command, expr, labels)
             }
             Expr::SystemOperator(sym) if sym == "[" => decode_push(expr),
+            Expr::SystemOperator(sym) if matches!(sym.as_str(), "]" | "^" | "~") => {
+                decode_return(&self.command, expr)
+            }
             _ => decode_address(&self.command, expr, labels),
         }
     }
@@ -116,7 +241,7 @@ impl Statement {
                 current_label,
                 pc,
             )?,
-            OperandValue::UnresolvedRelative(addr) => Self::absolute_to_relative(addr, pc),
+            OperandValue::UnresolvedRelative(addr) => Self::absolute_to_relative(addr, pc + 2)?,
         };
         Ok(operand)
     }
@@ -133,7 +258,7 @@ impl Statement {
         if let Some(entry) = labels.get(&name) {
             if let Address::Full(absolute_address) = entry.address {
                 if mode == &AddressingMode::Relative {
-                    return Ok(Self::absolute_to_relative(absolute_address, pc + 2));
+                    return Self::absolute_to_relative(absolute_address, pc + 2);
                 } else {
                     return Ok(vec![absolute_address as u8, (absolute_address >> 8) as u8]);
                 }
@@ -160,9 +285,16 @@ impl Statement {
         }
     }
 
-    fn absolute_to_relative(address: u16, pc: u16) -> Vec<u8> {
-        let diff = address.wrapping_sub(pc) as u8;
-        vec![diff]
+    // a branch reaches -128..127 bytes from the next instruction
+    fn absolute_to_relative(address: u16, pc: u16) -> Result<Vec<u8>, AssemblyError> {
+        let diff = address.wrapping_sub(pc) as i16;
+        if !(-128..=127).contains(&diff) {
+            return Err(AssemblyError::program(&format!(
+                "branch to ${:04X} is out of range ({} bytes)",
+                address, diff
+            )));
+        }
+        Ok(vec![diff as u8])
     }
 }
 
@@ -199,6 +331,146 @@ mod tests {
         );
     }
 
+    // every row of the README mnemonic table (except the A=A+n / A=A-n macros), all bytes
+    #[test]
+    fn test_mnemonic_table() {
+        let table: [(&str, &[u8]); 71] = [
+            ("A=AC+1", &[0x69, 0x01]),
+            ("A=A&1", &[0x29, 0x01]),
+            ("A=<", &[0x0A]),
+            (";=<,$8010", &[0x90, 0x0E]),
+            (";=>,$8010", &[0xB0, 0x0E]),
+            (";==,$8010", &[0xF0, 0x0E]),
+            (";=-,$8010", &[0x30, 0x0E]),
+            (";=\\,$8010", &[0xD0, 0x0E]),
+            (";=+,$8010", &[0x10, 0x0E]),
+            (";=_,$8010", &[0x50, 0x0E]),
+            (";=^,$8010", &[0x70, 0x0E]),
+            ("T=A&($1234)", &[0x2C, 0x34, 0x12]),
+            ("!", &[0x00]),
+            ("C=0", &[0x18]),
+            ("D=0", &[0xD8]),
+            ("I=0", &[0x58]),
+            ("V=0", &[0xB8]),
+            ("T=A-1", &[0xC9, 0x01]),
+            ("T=X-1", &[0xE0, 0x01]),
+            ("T=Y-1", &[0xC0, 0x01]),
+            ("($1234)=-", &[0xCE, 0x34, 0x12]),
+            ("X=-", &[0xCA]),
+            ("Y=-", &[0x88]),
+            ("A=A^1", &[0x49, 0x01]),
+            ("($1234)=+", &[0xEE, 0x34, 0x12]),
+            ("X=+", &[0xE8]),
+            ("Y=+", &[0xC8]),
+            ("#=$1234", &[0x4C, 0x34, 0x12]),
+            ("#=[$1234]", &[0x6C, 0x34, 0x12]),
+            ("!=$1234", &[0x20, 0x34, 0x12]),
+            ("A=1", &[0xA9, 0x01]),
+            ("A=($1F0A)", &[0xAD, 0x0A, 0x1F]),
+            ("A=($0A)", &[0xA5, 0x0A]),
+            ("A=[$0A+X]", &[0xA1, 0x0A]),
+            ("A=[$0A]+Y", &[0xB1, 0x0A]),
+            ("X=1", &[0xA2, 0x01]),
+            ("Y=1", &[0xA0, 0x01]),
+            ("A=>", &[0x4A]),
+            (".", &[0xEA]),
+            ("_", &[0xEA]),
+            ("A=A|1", &[0x09, 0x01]),
+            ("[=A", &[0x48]),
+            ("[=P", &[0x08]),
+            ("A=]", &[0x68]),
+            ("P=]", &[0x28]),
+            ("A=(", &[0x2A]),
+            ("A=)", &[0x6A]),
+            ("~", &[0x40]),
+            ("#=~", &[0x40]),
+            ("#=!", &[0x60]),
+            ("^", &[0x60]),
+            ("]", &[0x60]),
+            ("A=AC-1", &[0xE9, 0x01]),
+            ("C=1", &[0x38]),
+            ("D=1", &[0xF8]),
+            ("I=1", &[0x78]),
+            ("($1234)=A", &[0x8D, 0x34, 0x12]),
+            ("($1234)=X", &[0x8E, 0x34, 0x12]),
+            ("($1234)=Y", &[0x8C, 0x34, 0x12]),
+            ("X=A", &[0xAA]),
+            ("Y=A", &[0xA8]),
+            ("X=S", &[0xBA]),
+            ("A=X", &[0x8A]),
+            ("S=X", &[0x9A]),
+            ("A=Y", &[0x98]),
+            // absolute,y of the accumulator instructions
+            ("A=AC+($1234+Y)", &[0x79, 0x34, 0x12]),
+            ("A=AC-($1234+Y)", &[0xF9, 0x34, 0x12]),
+            ("A=A|($1234+Y)", &[0x19, 0x34, 0x12]),
+            ("A=A&($1234+Y)", &[0x39, 0x34, 0x12]),
+            ("A=A^($1234+Y)", &[0x59, 0x34, 0x12]),
+            ("T=A-($1234+Y)", &[0xD9, 0x34, 0x12]),
+        ];
+        let opcode_table = OpcodeTable::new();
+        let labels = LabelTable::new();
+        for (source, expected) in table {
+            let statement = parse_token(source).unwrap();
+            let objects = statement
+                .compile(&opcode_table, &labels, "", 0x8000)
+                .unwrap_or_else(|e| panic!("{}: {}", source, e.message()));
+            assert_eq!(objects, expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_branch_out_of_range() {
+        let opcode_table = OpcodeTable::new();
+        let mut labels = LabelTable::new();
+        for (name, address) in [("back", 0x8000), ("far", 0x7FFF)] {
+            let entry = LabelEntry {
+                name: name.to_string(),
+                address: Address::Full(address),
+                line: 0,
+            };
+            labels.insert(name.to_string(), entry);
+        }
+        let statement = parse_token(";=\\,back").unwrap();
+        assert_eq!(
+            statement.compile(&opcode_table, &labels, "", 0x807E),
+            Ok(vec![0xD0, 0x80])
+        );
+        let statement = parse_token(";=\\,far").unwrap();
+        assert!(statement
+            .compile(&opcode_table, &labels, "", 0x807E)
+            .is_err());
+    }
+
+    #[test]
+    fn test_forced_width() {
+        let mut labels = LabelTable::new();
+        labels.insert(
+            "vec".to_string(),
+            LabelEntry {
+                name: "vec".to_string(),
+                line: 0,
+                address: Address::Full(0x0080),
+            },
+        );
+        let opcode_table = OpcodeTable::new();
+        let compile = |source: &str| {
+            parse_token(source)
+                .unwrap()
+                .compile(&opcode_table, &labels, "", 0x8000)
+        };
+        assert_eq!(compile("A=(a:$10)"), Ok(vec![0xAD, 0x10, 0x00]));
+        assert_eq!(compile("(a:$10+X)=A"), Ok(vec![0x9D, 0x10, 0x00]));
+        assert_eq!(compile("X=(a:$10+Y)"), Ok(vec![0xBE, 0x10, 0x00]));
+        assert_eq!(compile("A=(z:vec)"), Ok(vec![0xA5, 0x80]));
+        assert_eq!(compile("(z:$0080+X)=+"), Ok(vec![0xF6, 0x80]));
+        // not a zero page address / no such mode
+        assert!(compile("A=(z:$1234)").is_err());
+        assert!(compile("A=(z:$0012+Y)").is_err());
+        assert!(compile("A=(a:5)").is_ok());
+        assert!(compile("T=X-(a:$10+X)").is_err());
+    }
+
     #[test]
     fn test_cmp_immediate() {
         let statement = parse_token("T=A-2").unwrap();
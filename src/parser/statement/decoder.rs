@@ -4,7 +4,7 @@ use crate::{
     assembler::{Address, LabelEntry},
     error::AssemblyError,
     parser::expression::matcher::*,
-    parser::expression::{matcher::parenthesized, Expr, Operator},
+    parser::expression::{matcher::parenthesized, Expr, Operator, Width},
 };
 
 use crate::opcode::AddressingMode::*;
@@ -22,6 +22,7 @@ pub fn decode_x(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction,
         .or_else(|_| increment(expr, "X").and_then(|_| ok_none(&INX, Implied)))
         .or_else(|_| decrement(expr, "X").and_then(|_| ok_none(&DEX, Implied)))
         .or_else(|_| register_a(expr).and_then(|_| ok_none(&TAX, Implied)))
+        .or_else(|_| register_s(expr).and_then(|_| ok_none(&TSX, Implied)))
         .or_else(|_| decode_error(expr))
 }
 
@@ -98,7 +99,7 @@ fn decode_adc(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, A
                     absolute_x(&right, labels).and_then(|num| ok_word(&ADC, AbsoluteX, num))
                 })
                 .or_else(|_| {
-                    absolute_y(&right, labels).and_then(|num| ok_word(&ADC, AbsoluteX, num))
+                    absolute_y(&right, labels).and_then(|num| ok_word(&ADC, AbsoluteY, num))
                 })
                 .or_else(|_| {
                     indirect_x(&right, labels).and_then(|num| ok_byte(&ADC, IndirectX, num))
@@ -134,7 +135,7 @@ fn decode_sbc(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, A
                     absolute_x(&right, labels).and_then(|num| ok_word(&SBC, AbsoluteX, num))
                 })
                 .or_else(|_| {
-                    absolute_y(&right, labels).and_then(|num| ok_word(&SBC, AbsoluteX, num))
+                    absolute_y(&right, labels).and_then(|num| ok_word(&SBC, AbsoluteY, num))
                 })
                 .or_else(|_| {
                     indirect_x(&right, labels).and_then(|num| ok_byte(&SBC, IndirectX, num))
@@ -170,7 +171,7 @@ fn decode_ora(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, A
                     absolute_x(&right, labels).and_then(|num| ok_word(&ORA, AbsoluteX, num))
                 })
                 .or_else(|_| {
-                    absolute_y(&right, labels).and_then(|num| ok_word(&ORA, AbsoluteX, num))
+                    absolute_y(&right, labels).and_then(|num| ok_word(&ORA, AbsoluteY, num))
                 })
                 .or_else(|_| {
                     indirect_x(&right, labels).and_then(|num| ok_byte(&ORA, IndirectX, num))
@@ -206,7 +207,7 @@ fn decode_and(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, A
                     absolute_x(&right, labels).and_then(|num| ok_word(&AND, AbsoluteX, num))
                 })
                 .or_else(|_| {
-                    absolute_y(&right, labels).and_then(|num| ok_word(&AND, AbsoluteX, num))
+                    absolute_y(&right, labels).and_then(|num| ok_word(&AND, AbsoluteY, num))
                 })
                 .or_else(|_| {
                     indirect_x(&right, labels).and_then(|num| ok_byte(&AND, IndirectX, num))
@@ -232,7 +233,7 @@ fn decode_eor(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, A
                     absolute_x(&right, labels).and_then(|num| ok_word(&EOR, AbsoluteX, num))
                 })
                 .or_else(|_| {
-                    absolute_y(&right, labels).and_then(|num| ok_word(&EOR, AbsoluteX, num))
+                    absolute_y(&right, labels).and_then(|num| ok_word(&EOR, AbsoluteY, num))
                 })
                 .or_else(|_| {
                     indirect_x(&right, labels).and_then(|num| ok_byte(&EOR, IndirectX, num))
@@ -257,6 +258,10 @@ fn decode_pop(expr: &Expr, _labels: &LabelTable) -> Result<AssemblyInstruction,
 }
 
 /**
+ * A=< -> ASL A
+ * A=> -> LSR A
+ * A=( -> ROL A
+ * A=) -> ROR A
  */
 fn decode_shift_a(expr: &Expr) -> Result<AssemblyInstruction, AssemblyError> {
     sysop(expr)
@@ -317,7 +322,7 @@ fn decode_cmp(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, A
         .or_else(|_| zeropage_x(expr, labels).and_then(|num| ok_byte(&CMP, ZeroPageX, num)))
         .or_else(|_| absolute(expr, labels).and_then(|num| ok_word(&CMP, Absolute, num)))
         .or_else(|_| absolute_x(expr, labels).and_then(|num| ok_word(&CMP, AbsoluteX, num)))
-        .or_else(|_| absolute_y(expr, labels).and_then(|num| ok_word(&CMP, AbsoluteX, num)))
+        .or_else(|_| absolute_y(expr, labels).and_then(|num| ok_word(&CMP, AbsoluteY, num)))
         .or_else(|_| indirect_x(expr, labels).and_then(|num| ok_byte(&CMP, IndirectX, num)))
         .or_else(|_| indirect_y(expr, labels).and_then(|num| ok_byte(&CMP, IndirectY, num)))
 }
@@ -394,13 +399,15 @@ pub fn decode_nop(expr: &Expr) -> Result<AssemblyInstruction, AssemblyError> {
 /**
  * JSR label -> !=label
  * JSR $12df -> !=$12df
+ * BRK       -> !
  */
 pub fn decode_call(
     expr: &Expr,
     _labels: &LabelTable,
 ) -> Result<AssemblyInstruction, AssemblyError> {
-    identifier(expr)
-        .and_then(|name| ok_unresolved_label(JSR, Absolute, &name))
+    empty(expr)
+        .and_then(|_| ok_none(&BRK, Implied))
+        .or_else(|_| identifier(expr).and_then(|name| ok_unresolved_label(JSR, Absolute, &name)))
         .or_else(|_| num16bit(expr).and_then(|num| ok_word(&JSR, Absolute, num)))
         .or_else(|_| decode_error(expr))
 }
@@ -423,13 +430,17 @@ fn sysop_tilda(expr: &Expr) -> Result<(), AssemblyError> {
     decode_error(expr)
 }
 
-pub fn decode_goto(
-    expr: &Expr,
-    _labels: &LabelTable,
-) -> Result<AssemblyInstruction, AssemblyError> {
+/**
+ * JMP label     -> #=label
+ * JMP ($1234)   -> #=[$1234] or #=[vector]
+ * RTS           -> #=!
+ * RTI           -> #=~
+ */
+pub fn decode_goto(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstruction, AssemblyError> {
     identifier(expr)
         .and_then(|name| ok_unresolved_label(JMP, Absolute, &name))
         .or_else(|_| num16bit(expr).and_then(|num| ok_word(&JMP, Absolute, num)))
+        .or_else(|_| indirect(expr, labels).and_then(|num| ok_word(&JMP, Indirect, num)))
         .or_else(|_| sysop_bang(expr).and_then(|_| ok_none(&RTS, Implied)))
         .or_else(|_| sysop_tilda(expr).and_then(|_| ok_none(&RTI, Implied)))
         .or_else(|_| decode_error(expr))
@@ -483,9 +494,41 @@ pub fn decode_shift(
         .or_else(|_| decode_error(command))
 }
 
+/**
+ * [=A -> PHA
+ * [=P -> PHP
+ */
 pub fn decode_push(expr: &Expr) -> Result<AssemblyInstruction, AssemblyError> {
     register_a(expr)
         .and_then(|_| ok_none(&PHA, Implied))
+        .or_else(|_| register_p(expr).and_then(|_| ok_none(&PHP, Implied)))
+        .or_else(|_| decode_error(expr))
+}
+
+/**
+ * P=] -> PLP
+ */
+pub fn decode_status(expr: &Expr) -> Result<AssemblyInstruction, AssemblyError> {
+    sysop(expr)
+        .and_then(|symbol| match symbol.as_str() {
+            "]" => ok_none(&PLP, Implied),
+            _ => decode_error(expr),
+        })
+        .or_else(|_| decode_error(expr))
+}
+
+/**
+ * ] or ^ -> RTS
+ * ~      -> RTI
+ */
+pub fn decode_return(command: &Expr, expr: &Expr) -> Result<AssemblyInstruction, AssemblyError> {
+    empty(expr)
+        .and_then(|_| sysop(command))
+        .and_then(|symbol| match symbol.as_str() {
+            "]" | "^" => ok_none(&RTS, Implied),
+            "~" => ok_none(&RTI, Implied),
+            _ => decode_error(command),
+        })
         .or_else(|_| decode_error(expr))
 }
 
@@ -622,6 +665,52 @@ pub fn decode_sty(expr: &Expr, labels: &LabelTable) -> Result<AssemblyInstructio
         .or_else(|_| absolute(expr, labels).and_then(|num| ok_word(&STY, Absolute, num)))
 }
 
+/**
+ * A=(a:$10)     LDA $0010     zero page address as absolute
+ * A=(z:label)   LDA label     absolute address (< $100) as zero page
+ */
+pub fn force_width(
+    instruction: AssemblyInstruction,
+    width: Width,
+) -> Result<AssemblyInstruction, AssemblyError> {
+    let AssemblyInstruction {
+        mnemonic,
+        addressing_mode,
+        value,
+    } = instruction;
+    let (mode, value) = match (width, addressing_mode, value) {
+        (Width::Absolute, ZeroPage, OperandValue::Byte(n)) => {
+            (Absolute, OperandValue::Word(n as u16))
+        }
+        (Width::Absolute, ZeroPageX, OperandValue::Byte(n)) => {
+            (AbsoluteX, OperandValue::Word(n as u16))
+        }
+        (Width::Absolute, ZeroPageY, OperandValue::Byte(n)) => {
+            (AbsoluteY, OperandValue::Word(n as u16))
+        }
+        (Width::ZeroPage, mode @ (Absolute | AbsoluteX | AbsoluteY), OperandValue::Word(n)) => {
+            let n = u8::try_from(n).map_err(|_| {
+                AssemblyError::syntax(&format!("z: ${:04X} is not a zero page address", n))
+            })?;
+            let mode = match mode {
+                Absolute => ZeroPage,
+                AbsoluteX => ZeroPageX,
+                _ => ZeroPageY,
+            };
+            (mode, OperandValue::Byte(n))
+        }
+        (Width::Absolute, mode @ (Absolute | AbsoluteX | AbsoluteY), value)
+        | (Width::ZeroPage, mode @ (ZeroPage | ZeroPageX | ZeroPageY), value) => (mode, value),
+        (_, mode, _) => {
+            return Err(AssemblyError::syntax(&format!(
+                "a: / z: can't be used with {:?} {:?}",
+                mnemonic, mode
+            )))
+        }
+    };
+    Ok(AssemblyInstruction::new(mnemonic, mode, value))
+}
+
 fn ok_byte(mnemonic: &Mnemonic, mode: Mode, num: u8) -> Result<AssemblyInstruction, AssemblyError> {
     Ok(AssemblyInstruction::new(
         mnemonic.clone(),
@@ -687,25 +776,34 @@ pub fn bracketed_within<T>(expr: &Expr, decoder: Decoder<T>) -> Result<T, Assemb
 }
 
 /**
- * A=1 or A=$10 or A=label or A=<label or A=>label
+ * A=1 or A=$10 or A=label or A=<label or A=>label or A=hi(label+1) or A=min(a,b)
  */
 pub fn immediate(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
     num8bit(expr)
         .or_else(|_| zeropage_label(expr, labels))
         .or_else(|_| hi_label(expr, labels))
         .or_else(|_| lo_label(expr, labels))
+        .or_else(|_| function_value(expr, labels))
         .or_else(|_| decode_error(expr))
 }
 
+fn function_value(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
+    match expr {
+        Expr::HiByte(_) | Expr::LoByte(_) | Expr::Function(_, _) => {
+            let value = expr.evaluate(labels, &0)?;
+            u8::try_from(value).or_else(|_| decode_error(expr))
+        }
+        _ => decode_error(expr),
+    }
+}
+
 fn hi_label(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
     hi(expr).and_then(|label| {
         identifier(&label).and_then(|name| {
-            lookup(&name, labels)
-                .and_then(|entry| match entry.address {
-                    Address::Full(addr) => Ok((addr >> 8) as u8),
-                    _ => decode_error(expr),
-                })
-                .or(Ok(0_u8))
+            lookup(&name, labels).and_then(|entry| match entry.address {
+                Address::Full(addr) => Ok((addr >> 8) as u8),
+                _ => decode_error(expr),
+            })
         })
     })
 }
@@ -713,12 +811,10 @@ fn hi_label(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
 fn lo_label(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
     lo(expr).and_then(|label| {
         identifier(&label).and_then(|name| {
-            lookup(&name, labels)
-                .and_then(|entry| match entry.address {
-                    Address::Full(addr) => Ok((addr & 0xff) as u8),
-                    _ => decode_error(expr),
-                })
-                .or(Ok(0_u8))
+            lookup(&name, labels).and_then(|entry| match entry.address {
+                Address::Full(addr) => Ok((addr & 0xff) as u8),
+                _ => decode_error(expr),
+            })
         })
     })
 }
@@ -740,7 +836,9 @@ pub fn zeropage(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
 }
 
 fn zeropage_label(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
-    offset_zeropage_label(expr, labels).or_else(|_| normal_zeropage_label(expr, labels))
+    offset_zeropage_label(expr, labels)
+        .or_else(|_| normal_zeropage_label(expr, labels))
+        .or_else(|_| zeropage_sum(expr, labels))
 }
 
 fn normal_zeropage_label(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
@@ -759,6 +857,16 @@ fn offset_zeropage_label(expr: &Expr, labels: &LabelTable) -> Result<u8, Assembl
     })
 }
 
+// zp+Actor.hp
+fn zeropage_sum(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
+    plus(expr)
+        .and_then(|_| expr.calculate_address(labels))
+        .and_then(|addr| match addr {
+            Address::ZeroPage(addr) => Ok(addr),
+            _ => decode_error(expr),
+        })
+}
+
 pub fn absolute(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError> {
     parenthesized_within::<u16>(expr, num16bit)
         .or_else(|_| parenthesized(expr).and_then(|expr| absolute_label(&expr, labels)))
@@ -770,12 +878,10 @@ fn absolute_label(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError
 
 fn full_label(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError> {
     identifier(expr).and_then(|name| {
-        lookup(&name, labels)
-            .and_then(|entry| match entry.address {
-                Address::Full(addr) => Ok(addr),
-                _ => decode_error(expr),
-            })
-            .or(Ok(0_u16))
+        lookup(&name, labels).and_then(|entry| match entry.address {
+            Address::Full(addr) => Ok(addr),
+            _ => decode_error(expr),
+        })
     })
 }
 
@@ -791,32 +897,42 @@ fn offset_label(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError>
  * X=($1F+Y) or X=(31+Y) or X=(label+Y)
  */
 pub fn zeropage_y(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
-    parenthesized_within(expr, plus).and_then(|(left, right)| {
+    parenthesized_within(expr, plus_last).and_then(|(left, right)| {
         register_y(&right).and_then(|_| num8bit(&left).or_else(|_| zeropage_label(&left, labels)))
     })
 }
 
 pub fn zeropage_x(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
-    parenthesized_within(expr, plus).and_then(|(left, right)| {
+    parenthesized_within(expr, plus_last).and_then(|(left, right)| {
         register_x(&right).and_then(|_| num8bit(&left).or_else(|_| zeropage_label(&left, labels)))
     })
 }
 
 pub fn absolute_y(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError> {
-    parenthesized_within(expr, plus).and_then(|(left, right)| {
+    parenthesized_within(expr, plus_last).and_then(|(left, right)| {
         register_y(&right).and_then(|_| num16bit(&left).or_else(|_| absolute_label(&left, labels)))
     })
 }
 
 pub fn absolute_x(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError> {
-    parenthesized_within(expr, plus).and_then(|(left, right)| {
+    parenthesized_within(expr, plus_last).and_then(|(left, right)| {
         register_x(&right).and_then(|_| num16bit(&left).or_else(|_| absolute_label(&left, labels)))
     })
 }
 
+// Indirect      JMP ($4400)   #=[$4400], #=[$F0] and #=[zp] read the pointer at $00F0
+pub fn indirect(expr: &Expr, labels: &LabelTable) -> Result<u16, AssemblyError> {
+    bracketed(expr)
+        .and_then(|expr| expr.calculate_address(labels))
+        .map(|address| match address {
+            Address::Full(addr) => addr,
+            Address::ZeroPage(addr) => addr as u16,
+        })
+}
+
 // Indirect,X    LDA ($44,X)   A=[$44+X]
 pub fn indirect_x(expr: &Expr, labels: &LabelTable) -> Result<u8, AssemblyError> {
-    bracketed_within(expr, plus).and_then(|(left, right)| {
+    bracketed_within(expr, plus_last).and_then(|(left, right)| {
         register_x(&right).and_then(|_| num8bit(&left).or_else(|_| zeropage_label(&left, labels)))
     })
 }
@@ -928,6 +1044,31 @@ mod tests {
         assert_eq!(absolute_x(&expr, &labels), Ok(0x1234));
     }
 
+    #[test]
+    fn test_offset_indexed() {
+        let mut labels = LabelTable::new();
+        for (name, address) in [
+            ("OAM", Address::Full(0x0200)),
+            ("Sprite.x", Address::ZeroPage(3)),
+            ("actors", Address::ZeroPage(0x40)),
+        ] {
+            labels.insert(
+                name.to_string(),
+                LabelEntry {
+                    name: name.to_string(),
+                    address,
+                    line: 0,
+                },
+            );
+        }
+        let expr = Expr::parse("(OAM+Sprite.x+X)").unwrap();
+        assert_eq!(absolute_x(&expr, &labels), Ok(0x0203));
+        let expr = Expr::parse("(actors+Sprite.x+1+Y)").unwrap();
+        assert_eq!(zeropage_y(&expr, &labels), Ok(0x44));
+        let expr = Expr::parse("[actors+Sprite.x+X]").unwrap();
+        assert_eq!(indirect_x(&expr, &labels), Ok(0x43));
+    }
+
     #[test]
     fn test_address_absolute_x_0x0000() {
         let labels = LabelTable::new();
@@ -939,6 +1080,14 @@ mod tests {
         assert_eq!(absolute_x(&expr, &labels), Ok(0x0000));
     }
 
+    #[test]
+    fn test_undefined_offset_label() {
+        // pass1 decodes forward labels with a placeholder address
+        let labels = LabelTable::new();
+        assert!(absolute(&Expr::parse("(later+1)").unwrap(), &labels).is_err());
+        assert!(absolute(&Expr::parse("(later+A)").unwrap(), &labels).is_err());
+    }
+
     #[test]
     fn test_indirect_y() {
         let mut labels = LabelTable::new();
@@ -960,6 +1109,24 @@ mod tests {
         assert_eq!(indirect_y(&expr, &labels), Ok(0x12));
     }
 
+    #[test]
+    fn test_indirect() {
+        let mut labels = LabelTable::new();
+        labels.insert(
+            "vec".to_string(),
+            LabelEntry {
+                name: "vec".to_string(),
+                address: Address::ZeroPage(0xF0),
+                line: 0,
+            },
+        );
+        let indirect = |source: &str| indirect(&Expr::parse(source).unwrap(), &labels);
+        assert_eq!(indirect("[$4400]"), Ok(0x4400));
+        assert_eq!(indirect("[$F0]"), Ok(0x00F0));
+        assert_eq!(indirect("[vec]"), Ok(0x00F0));
+        assert!(indirect("[nowhere]").is_err());
+    }
+
     #[test]
     fn test_hi_label() {
         let mut labels = LabelTable::new();
@@ -975,6 +1142,17 @@ mod tests {
         assert_eq!(hi_label(&expr, &labels), Ok(0x12));
     }
 
+    #[test]
+    fn test_immediate_function() {
+        let labels = LabelTable::new();
+        let expr = Expr::parse("hi($1234+$100)").unwrap();
+        assert_eq!(immediate(&expr, &labels), Ok(0x13));
+        let expr = Expr::parse("max(3,7)").unwrap();
+        assert_eq!(immediate(&expr, &labels), Ok(7));
+        let expr = Expr::parse("max(3,$107)").unwrap();
+        assert!(immediate(&expr, &labels).is_err());
+    }
+
     #[test]
     fn test_lo_label() {
         let mut labels = LabelTable::new();
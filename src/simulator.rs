@@ -0,0 +1,674 @@
+use std::fmt;
+use std::io::Read;
+
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    opcode::{AddressingMode, Mnemonic, OpcodeTable},
+    parser::{expression::Expr, Line},
+};
+
+/**
+ * NMOS 6502 シミュレータ
+ *  アセンブルした行をメモリに置いて実行する
+ *  サイクル数は opcode.rs の表 + ページ跨ぎ / 分岐成立
+ *
+ *  スタブ (アドレス=種類)
+ *  $FFD2=chrout    JSR すると A を 1 文字出力して戻る
+ *  $D012=putc      書き込んだ値を出力する
+ *  $D011=getc      読むと標準入力の 1 文字 (終わりは 0)
+ *  $FFE1=exit      JSR / JMP すると終了する
+ *
+ *  終了: 最初の呼び出しからの RTS, 自分自身への JMP / 分岐 (トラップ),
+ *        exit スタブ, IRQ ベクタ ($FFFE) が 0 の BRK
+ */
+const C: u8 = 0x01;
+const Z: u8 = 0x02;
+const I: u8 = 0x04;
+const D: u8 = 0x08;
+const B: u8 = 0x10;
+const U: u8 = 0x20;
+const V: u8 = 0x40;
+const N: u8 = 0x80;
+
+// RTS from the first call lands here
+const RETURN: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stub {
+    Chrout,
+    Putc,
+    Getc,
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Return,
+    Trap(u16),
+    Exit,
+    Break(u16),
+    Limit,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Return => write!(f, "returned"),
+            Stop::Trap(address) => write!(f, "trapped at ${:04X}", address),
+            Stop::Exit => write!(f, "exited"),
+            Stop::Break(address) => write!(f, "BRK at ${:04X}", address),
+            Stop::Limit => write!(f, "cycle limit"),
+        }
+    }
+}
+
+// ADDRESS=kind, the address may be a label
+pub fn parse_stub(spec: &str, labels: &LabelTable) -> Result<(u16, Stub), AssemblyError> {
+    let (address, kind) = spec
+        .rsplit_once('=')
+        .ok_or(AssemblyError::program(&format!(
+            "stub {} needs ADDRESS=kind",
+            spec
+        )))?;
+    let address = Expr::parse(address)?.evaluate(labels, &0)?;
+    let stub = match kind {
+        "chrout" => Stub::Chrout,
+        "putc" => Stub::Putc,
+        "getc" => Stub::Getc,
+        "exit" => Stub::Exit,
+        _ => return Err(AssemblyError::program(&format!("unknown stub {}", kind))),
+    };
+    Ok((address, stub))
+}
+
+#[derive(Clone)]
+struct Decoded {
+    mnemonic: Mnemonic,
+    mode: AddressingMode,
+    cycles: u8,
+    page_penalty: bool,
+}
+
+pub struct Cpu {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
+    pub cycles: u64,
+    pub memory: Vec<u8>,
+    pub output: Vec<u8>,
+    stubs: Vec<(u16, Stub)>,
+    decoded: Vec<Option<Decoded>>,
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        let table = OpcodeTable::new();
+        let decoded = (0..=255u8)
+            .map(|byte| {
+                table.decode(byte).map(|opcode| Decoded {
+                    mnemonic: opcode.mnemonic.clone(),
+                    mode: opcode.addressing_mode,
+                    cycles: opcode.cycles,
+                    page_penalty: opcode.page_penalty(),
+                })
+            })
+            .collect();
+        Self {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0xFF,
+            p: U | I,
+            pc: 0,
+            cycles: 0,
+            memory: vec![0; 0x10000],
+            output: vec![],
+            stubs: vec![],
+            decoded,
+        }
+    }
+
+    // put the object codes of the lines in memory
+    pub fn load(&mut self, lines: &[Line]) {
+        for line in lines {
+            for (i, byte) in line.object_codes.iter().enumerate() {
+                self.memory[line.address.wrapping_add(i as u16) as usize] = *byte;
+            }
+        }
+    }
+
+    pub fn add_stub(&mut self, address: u16, stub: Stub) {
+        self.stubs.push((address, stub));
+    }
+
+    // JSR to the address from outside, the last RTS stops the run
+    pub fn call(&mut self, address: u16) {
+        self.push_word(RETURN.wrapping_sub(1));
+        self.pc = address;
+    }
+
+    pub fn run(&mut self, max_cycles: u64) -> Result<Stop, AssemblyError> {
+        while self.cycles < max_cycles {
+            if let Some(stop) = self.step()? {
+                return Ok(stop);
+            }
+        }
+        Ok(Stop::Limit)
+    }
+
+    pub fn step(&mut self) -> Result<Option<Stop>, AssemblyError> {
+        let start = self.pc;
+        if start == RETURN {
+            return Ok(Some(Stop::Return));
+        }
+        match self.stub(start) {
+            Some(Stub::Chrout) => {
+                self.output.push(self.a);
+                self.pc = self.pull_word().wrapping_add(1);
+                self.cycles += 6;
+                return Ok(None);
+            }
+            Some(Stub::Exit) => return Ok(Some(Stop::Exit)),
+            _ => {}
+        }
+        let opcode = self.fetch();
+        let decoded = self.decoded[opcode as usize]
+            .clone()
+            .ok_or(AssemblyError::program(&format!(
+                "illegal opcode ${:02X} at ${:04X}",
+                opcode, start
+            )))?;
+        let (address, crossed) = self.operand(decoded.mode);
+        self.cycles += decoded.cycles as u64 + (decoded.page_penalty && crossed) as u64;
+        if let Some(stop) = self.execute(&decoded, address, start)? {
+            return Ok(Some(stop));
+        }
+        if self.pc == start {
+            return Ok(Some(Stop::Trap(start)));
+        }
+        Ok(None)
+    }
+
+    fn execute(
+        &mut self,
+        decoded: &Decoded,
+        address: u16,
+        start: u16,
+    ) -> Result<Option<Stop>, AssemblyError> {
+        let accumulator = decoded.mode == AddressingMode::Accumulator;
+        match decoded.mnemonic {
+            Mnemonic::LDA => {
+                let value = self.read(address);
+                self.a = self.set_nz(value);
+            }
+            Mnemonic::LDX => {
+                let value = self.read(address);
+                self.x = self.set_nz(value);
+            }
+            Mnemonic::LDY => {
+                let value = self.read(address);
+                self.y = self.set_nz(value);
+            }
+            Mnemonic::STA => self.write(address, self.a),
+            Mnemonic::STX => self.write(address, self.x),
+            Mnemonic::STY => self.write(address, self.y),
+            Mnemonic::TAX => self.x = self.set_nz(self.a),
+            Mnemonic::TAY => self.y = self.set_nz(self.a),
+            Mnemonic::TXA => self.a = self.set_nz(self.x),
+            Mnemonic::TYA => self.a = self.set_nz(self.y),
+            Mnemonic::TSX => self.x = self.set_nz(self.s),
+            Mnemonic::TXS => self.s = self.x,
+            Mnemonic::INX => self.x = self.set_nz(self.x.wrapping_add(1)),
+            Mnemonic::INY => self.y = self.set_nz(self.y.wrapping_add(1)),
+            Mnemonic::DEX => self.x = self.set_nz(self.x.wrapping_sub(1)),
+            Mnemonic::DEY => self.y = self.set_nz(self.y.wrapping_sub(1)),
+            Mnemonic::INC => {
+                let value = self.read(address).wrapping_add(1);
+                self.set_nz(value);
+                self.write(address, value);
+            }
+            Mnemonic::DEC => {
+                let value = self.read(address).wrapping_sub(1);
+                self.set_nz(value);
+                self.write(address, value);
+            }
+            Mnemonic::AND => {
+                let value = self.a & self.read(address);
+                self.a = self.set_nz(value);
+            }
+            Mnemonic::ORA => {
+                let value = self.a | self.read(address);
+                self.a = self.set_nz(value);
+            }
+            Mnemonic::EOR => {
+                let value = self.a ^ self.read(address);
+                self.a = self.set_nz(value);
+            }
+            Mnemonic::ADC => {
+                let value = self.read(address);
+                self.adc(value);
+            }
+            Mnemonic::SBC => {
+                let value = self.read(address);
+                self.sbc(value);
+            }
+            Mnemonic::CMP => self.compare(self.a, address),
+            Mnemonic::CPX => self.compare(self.x, address),
+            Mnemonic::CPY => self.compare(self.y, address),
+            Mnemonic::BIT => {
+                let value = self.read(address);
+                self.set_flag(Z, self.a & value == 0);
+                self.p = (self.p & !(N | V)) | (value & (N | V));
+            }
+            Mnemonic::ASL | Mnemonic::LSR | Mnemonic::ROL | Mnemonic::ROR => {
+                let value = if accumulator {
+                    self.a
+                } else {
+                    self.read(address)
+                };
+                let carry = self.p & C;
+                let (result, out) = match decoded.mnemonic {
+                    Mnemonic::ASL => (value << 1, value >> 7),
+                    Mnemonic::LSR => (value >> 1, value & 1),
+                    Mnemonic::ROL => (value << 1 | carry, value >> 7),
+                    _ => (value >> 1 | carry << 7, value & 1),
+                };
+                self.set_flag(C, out != 0);
+                let result = self.set_nz(result);
+                if accumulator {
+                    self.a = result;
+                } else {
+                    self.write(address, result);
+                }
+            }
+            Mnemonic::BCC => self.branch(self.p & C == 0, address),
+            Mnemonic::BCS => self.branch(self.p & C != 0, address),
+            Mnemonic::BNE => self.branch(self.p & Z == 0, address),
+            Mnemonic::BEQ => self.branch(self.p & Z != 0, address),
+            Mnemonic::BPL => self.branch(self.p & N == 0, address),
+            Mnemonic::BMI => self.branch(self.p & N != 0, address),
+            Mnemonic::BVC => self.branch(self.p & V == 0, address),
+            Mnemonic::BVS => self.branch(self.p & V != 0, address),
+            Mnemonic::JMP => self.pc = address,
+            Mnemonic::JSR => {
+                self.push_word(self.pc.wrapping_sub(1));
+                self.pc = address;
+            }
+            Mnemonic::RTS => self.pc = self.pull_word().wrapping_add(1),
+            Mnemonic::RTI => {
+                let p = self.pull();
+                self.p = (p & !B) | U;
+                self.pc = self.pull_word();
+            }
+            Mnemonic::BRK => {
+                let vector = self.read_word(0xFFFE);
+                if vector == 0 {
+                    return Ok(Some(Stop::Break(start)));
+                }
+                self.push_word(self.pc.wrapping_add(1));
+                self.push(self.p | B | U);
+                self.p |= I;
+                self.pc = vector;
+            }
+            Mnemonic::PHA => self.push(self.a),
+            Mnemonic::PHP => self.push(self.p | B | U),
+            Mnemonic::PLA => {
+                let value = self.pull();
+                self.a = self.set_nz(value);
+            }
+            Mnemonic::PLP => {
+                let p = self.pull();
+                self.p = (p & !B) | U;
+            }
+            Mnemonic::CLC => self.p &= !C,
+            Mnemonic::SEC => self.p |= C,
+            Mnemonic::CLI => self.p &= !I,
+            Mnemonic::SEI => self.p |= I,
+            Mnemonic::CLD => self.p &= !D,
+            Mnemonic::SED => self.p |= D,
+            Mnemonic::CLV => self.p &= !V,
+            Mnemonic::NOP => {}
+        }
+        Ok(None)
+    }
+
+    // effective address and whether the index crossed a page
+    fn operand(&mut self, mode: AddressingMode) -> (u16, bool) {
+        let indexed = |base: u16, index: u8| {
+            let address = base.wrapping_add(index as u16);
+            (address, address >> 8 != base >> 8)
+        };
+        match mode {
+            AddressingMode::Immediate => {
+                let address = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                (address, false)
+            }
+            AddressingMode::ZeroPage => (self.fetch() as u16, false),
+            AddressingMode::ZeroPageX => (self.fetch().wrapping_add(self.x) as u16, false),
+            AddressingMode::ZeroPageY => (self.fetch().wrapping_add(self.y) as u16, false),
+            AddressingMode::Absolute => (self.fetch_word(), false),
+            AddressingMode::AbsoluteX => indexed(self.fetch_word(), self.x),
+            AddressingMode::AbsoluteY => indexed(self.fetch_word(), self.y),
+            AddressingMode::Relative => {
+                let offset = self.fetch() as i8;
+                (self.pc.wrapping_add_signed(offset as i16), false)
+            }
+            AddressingMode::Indirect => {
+                // NMOS: JMP ($xxFF) reads the high byte from $xx00
+                let pointer = self.fetch_word();
+                let high = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+                let address = u16::from_le_bytes([self.read(pointer), self.read(high)]);
+                (address, false)
+            }
+            AddressingMode::IndirectX => {
+                let pointer = self.fetch().wrapping_add(self.x);
+                (self.read_zero_page_word(pointer), false)
+            }
+            AddressingMode::IndirectY => {
+                let pointer = self.fetch();
+                indexed(self.read_zero_page_word(pointer), self.y)
+            }
+            AddressingMode::Implied | AddressingMode::Accumulator => (0, false),
+        }
+    }
+
+    fn branch(&mut self, condition: bool, target: u16) {
+        if condition {
+            self.cycles += 1 + (target >> 8 != self.pc >> 8) as u64;
+            self.pc = target;
+        }
+    }
+
+    fn adc(&mut self, value: u8) {
+        let carry = (self.p & C) as u16;
+        let binary = self.a as u16 + value as u16 + carry;
+        if self.p & D == 0 {
+            self.set_flag(C, binary > 0xFF);
+            self.set_flag(V, (!(self.a ^ value) & (self.a ^ binary as u8) & 0x80) != 0);
+            self.a = self.set_nz(binary as u8);
+            return;
+        }
+        // NMOS: Z from the binary sum, N and V before the high digit is adjusted
+        let mut low = (self.a & 0x0F) as u16 + (value & 0x0F) as u16 + carry;
+        if low > 9 {
+            low += 6;
+        }
+        let mut high = (self.a >> 4) as u16 + (value >> 4) as u16 + (low > 0x0F) as u16;
+        self.set_flag(Z, binary & 0xFF == 0);
+        self.set_flag(N, high & 0x08 != 0);
+        self.set_flag(
+            V,
+            (!(self.a ^ value) & (self.a ^ (high << 4) as u8) & 0x80) != 0,
+        );
+        if high > 9 {
+            high += 6;
+        }
+        self.set_flag(C, high > 0x0F);
+        self.a = ((high << 4) | (low & 0x0F)) as u8;
+    }
+
+    fn sbc(&mut self, value: u8) {
+        let borrow = (self.p & C == 0) as i16;
+        let binary = self.a as i16 - value as i16 - borrow;
+        let result = binary as u8;
+        let overflow = ((self.a ^ value) & (self.a ^ result) & 0x80) != 0;
+        if self.p & D != 0 {
+            // NMOS: the flags come from the binary difference
+            let mut low = (self.a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+            let mut high = (self.a >> 4) as i16 - (value >> 4) as i16;
+            if low < 0 {
+                low -= 6;
+                high -= 1;
+            }
+            if high < 0 {
+                high -= 6;
+            }
+            self.set_nz(result);
+            self.a = ((high << 4) | (low & 0x0F)) as u8;
+        } else {
+            self.a = self.set_nz(result);
+        }
+        self.set_flag(C, binary >= 0);
+        self.set_flag(V, overflow);
+    }
+
+    fn compare(&mut self, register: u8, address: u16) {
+        let value = self.read(address);
+        self.set_flag(C, register >= value);
+        self.set_nz(register.wrapping_sub(value));
+    }
+
+    fn set_nz(&mut self, value: u8) -> u8 {
+        self.set_flag(Z, value == 0);
+        self.set_flag(N, value & 0x80 != 0);
+        value
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn stub(&self, address: u16) -> Option<Stub> {
+        self.stubs
+            .iter()
+            .find(|(stub, _)| *stub == address)
+            .map(|(_, stub)| *stub)
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        if self.stub(address) == Some(Stub::Getc) {
+            let mut byte = [0];
+            return match std::io::stdin().read(&mut byte) {
+                Ok(1) => byte[0],
+                _ => 0,
+            };
+        }
+        self.memory[address as usize]
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if self.stub(address) == Some(Stub::Putc) {
+            self.output.push(value);
+            return;
+        }
+        self.memory[address as usize] = value;
+    }
+
+    fn read_word(&mut self, address: u16) -> u16 {
+        u16::from_le_bytes([self.read(address), self.read(address.wrapping_add(1))])
+    }
+
+    fn read_zero_page_word(&mut self, pointer: u8) -> u16 {
+        u16::from_le_bytes([
+            self.read(pointer as u16),
+            self.read(pointer.wrapping_add(1) as u16),
+        ])
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let byte = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let word = self.read_word(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        word
+    }
+
+    fn push(&mut self, value: u8) {
+        self.write(0x0100 | self.s as u16, value);
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        self.read(0x0100 | self.s as u16)
+    }
+
+    fn push_word(&mut self, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.push(high);
+        self.push(low);
+    }
+
+    fn pull_word(&mut self) -> u16 {
+        let low = self.pull();
+        let high = self.pull();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, flag)| {
+                if self.p & (0x80 >> i) != 0 {
+                    flag
+                } else {
+                    flag.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        write!(
+            f,
+            "PC=${:04X} A=${:02X} X=${:02X} Y=${:02X} S=${:02X} P={} cycles={}",
+            self.pc, self.a, self.x, self.y, self.s, flags, self.cycles
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // code at $0200, called from outside
+    fn run(code: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.memory[0x0200..0x0200 + code.len()].copy_from_slice(code);
+        cpu.call(0x0200);
+        assert_eq!(cpu.run(1000), Ok(Stop::Return));
+        cpu
+    }
+
+    #[test]
+    fn test_load_and_store() {
+        // LDX #3 LDA #$42 STA $10,X INC $13 LDY $13 RTS
+        let cpu = run(&[
+            0xa2, 0x03, 0xa9, 0x42, 0x95, 0x10, 0xe6, 0x13, 0xa4, 0x13, 0x60,
+        ]);
+        assert_eq!(cpu.memory[0x13], 0x43);
+        assert_eq!(cpu.y, 0x43);
+        // 2 + 2 + 4 + 5 + 3 + 6
+        assert_eq!(cpu.cycles, 22);
+    }
+
+    #[test]
+    fn test_binary_flags() {
+        // LDA #$7F ADC #1 (V, N) then CMP #$80 (Z, C)
+        let cpu = run(&[0x18, 0xa9, 0x7f, 0x69, 0x01, 0x60]);
+        assert_eq!((cpu.a, cpu.p & (N | V | C)), (0x80, N | V));
+        let cpu = run(&[0xa9, 0x80, 0xc9, 0x80, 0x60]);
+        assert_eq!(cpu.p & (Z | C), Z | C);
+        // SEC LDA #0 SBC #1 -> $FF, borrow
+        let cpu = run(&[0x38, 0xa9, 0x00, 0xe9, 0x01, 0x60]);
+        assert_eq!((cpu.a, cpu.p & (N | C)), (0xff, N));
+    }
+
+    #[test]
+    fn test_decimal() {
+        // SED CLC LDA #$58 ADC #$46 -> $04 carry
+        let cpu = run(&[0xf8, 0x18, 0xa9, 0x58, 0x69, 0x46, 0x60]);
+        assert_eq!((cpu.a, cpu.p & C), (0x04, C));
+        // SED SEC LDA #$12 SBC #$21 -> $91 borrow
+        let cpu = run(&[0xf8, 0x38, 0xa9, 0x12, 0xe9, 0x21, 0x60]);
+        assert_eq!((cpu.a, cpu.p & C), (0x91, 0));
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // LDX #3 loop: DEX BNE loop RTS
+        let cpu = run(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x60]);
+        assert_eq!(cpu.x, 0);
+        // LDX 2, DEX 3 * 2, BNE taken 2 * 3 and not taken 2, RTS 6
+        assert_eq!(cpu.cycles, 22);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_bug() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0x02ff] = 0x00;
+        cpu.memory[0x0200] = 0x03; // high byte comes from $0200, not $0300
+        cpu.memory[0x0300] = 0x04;
+        cpu.memory[0x0400] = 0x6c; // JMP ($02FF)
+        cpu.memory[0x0401] = 0xff;
+        cpu.memory[0x0402] = 0x02;
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn test_brk_and_rti() {
+        let mut cpu = Cpu::new();
+        cpu.memory[0xfffe] = 0x00;
+        cpu.memory[0xffff] = 0x03;
+        cpu.memory[0x0300] = 0x40; // RTI
+        cpu.memory[0x0200..0x0204].copy_from_slice(&[0x00, 0xea, 0xe8, 0x60]);
+        cpu.call(0x0200);
+        assert_eq!(cpu.run(1000), Ok(Stop::Return));
+        assert_eq!(cpu.x, 1);
+        // BRK 7, RTI 6, INX 2, RTS 6
+        assert_eq!(cpu.cycles, 21);
+    }
+
+    #[test]
+    fn test_stubs() {
+        let mut cpu = Cpu::new();
+        cpu.add_stub(0xffd2, Stub::Chrout);
+        cpu.add_stub(0xd012, Stub::Putc);
+        // LDA #'H' JSR $FFD2 LDA #'I' STA $D012 loop: JMP loop
+        let code = [
+            0xa9, 0x48, 0x20, 0xd2, 0xff, 0xa9, 0x49, 0x8d, 0x12, 0xd0, 0x4c, 0x0a, 0x02,
+        ];
+        cpu.memory[0x0200..0x0200 + code.len()].copy_from_slice(&code);
+        cpu.pc = 0x0200;
+        assert_eq!(cpu.run(1000), Ok(Stop::Trap(0x020a)));
+        assert_eq!(cpu.output, b"HI");
+        assert_eq!(cpu.run(0), Ok(Stop::Limit));
+    }
+
+    /**
+     * Klaus Dormann's 6502_functional_test.bin (64KB image, decimal tests on)
+     *  VTBASE_FUNCTIONAL_TEST=path/to/6502_functional_test.bin cargo test functional
+     *  success traps at $3469, a failed test traps at its own address
+     * skipped when the ROM is not there, it is not part of the repository
+     */
+    #[test]
+    fn test_functional_rom() {
+        let path = std::env::var("VTBASE_FUNCTIONAL_TEST")
+            .unwrap_or("6502_functional_test.bin".to_string());
+        let Ok(image) = std::fs::read(&path) else {
+            eprintln!("{} not found, functional test skipped", path);
+            return;
+        };
+        let mut cpu = Cpu::new();
+        cpu.memory.copy_from_slice(&image);
+        cpu.pc = 0x0400;
+        let stop = cpu.run(200_000_000).unwrap();
+        assert_eq!(stop, Stop::Trap(0x3469), "{}", cpu);
+        eprintln!("functional test passed in {} cycles", cpu.cycles);
+    }
+}
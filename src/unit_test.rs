@@ -0,0 +1,381 @@
+use crate::{
+    assembler::LabelTable,
+    error::AssemblyError,
+    parser::{expression::Expr, statement::Statement, Line},
+    simulator::{Cpu, Stop, Stub},
+};
+
+/**
+ * ユニットテスト (vtbase test)
+ *          .test="rot moves the third cell to the top"
+ *          .set=X,$FA                  レジスタ A X Y S P / フラグ C Z I D V N
+ *          .set=($FA),$11,0,$22,0      メモリ (続けて置くバイト、文字列も可)
+ *          .call=rot                   呼び出して #=! で戻るまで実行する
+ *          .expect=X,$FA
+ *          .expect=($FA),$33,0,$11,0
+ *          .endtest
+ *
+ * .set / .call / .expect は書いた順に実行する
+ * テストブロックはアセンブルの前に取り除くので出力には入らない
+ */
+pub struct Test {
+    // file:line of .test
+    pub location: String,
+    pub name: String,
+    steps: Vec<(String, Statement)>,
+}
+
+const STEPS: [&str; 3] = [".set", ".call", ".expect"];
+
+// the lines without test blocks, and the tests
+pub fn extract(lines: &[Line]) -> Result<(Vec<Line>, Vec<Test>), AssemblyError> {
+    let mut result = vec![];
+    let mut tests = vec![];
+    let mut test: Option<Test> = None;
+    for line in lines {
+        let Some(command) = directive(line) else {
+            if test.is_some() && (line.label.is_some() || !line.statements.is_empty()) {
+                return Err(test_error(&line.location(), "a test block can't have code"));
+            }
+            if test.is_none() {
+                result.push(line.clone());
+            }
+            continue;
+        };
+        if line.label.is_some() {
+            return Err(test_error(
+                &line.location(),
+                &format!("{} can't have a label", command),
+            ));
+        }
+        match (command.as_str(), &mut test) {
+            (".test", Some(_)) => return Err(test_error(&line.location(), "nested .test")),
+            (".test", None) => test = Some(new_test(line)?),
+            (".endtest", Some(_)) => tests.extend(test.take()),
+            (_, Some(test)) => {
+                let command = line.statements.iter().map(Statement::command);
+                if let Some(other) = command.flatten().find(|c| !STEPS.contains(&c.as_str())) {
+                    return Err(test_error(
+                        &line.location(),
+                        &format!("{} can't be used with {}", other, STEPS.join(" / ")),
+                    ));
+                }
+                let steps = line.statements.iter().cloned();
+                test.steps.extend(steps.map(|step| (line.location(), step)));
+            }
+            (command, None) => {
+                return Err(test_error(
+                    &line.location(),
+                    &format!("{} outside of .test", command),
+                ))
+            }
+        }
+    }
+    if let Some(test) = test {
+        return Err(test_error(&test.location, ".test without .endtest"));
+    }
+    Ok((result, tests))
+}
+
+fn directive(line: &Line) -> Option<String> {
+    let command = line.statements.first()?.command().ok()?;
+    (command == ".test" || command == ".endtest" || STEPS.contains(&command.as_str()))
+        .then_some(command)
+}
+
+fn new_test(line: &Line) -> Result<Test, AssemblyError> {
+    let name = match (&line.statements[..], &line.statements[0].expression) {
+        ([_], Expr::StringLiteral(name)) => name.clone(),
+        _ => {
+            return Err(test_error(
+                &line.location(),
+                ".test needs a name (.test=\"name\")",
+            ))
+        }
+    };
+    Ok(Test {
+        location: line.location(),
+        name,
+        steps: vec![],
+    })
+}
+
+// register, flag or memory of .set / .expect
+enum Target {
+    Register(String),
+    Memory(u16),
+}
+
+const REGISTERS: [&str; 11] = ["A", "X", "Y", "S", "P", "C", "Z", "I", "D", "V", "N"];
+
+// failures of the test, an empty list when it passes
+pub fn run(
+    test: &Test,
+    lines: &[Line],
+    labels: &LabelTable,
+    stubs: &[(u16, Stub)],
+    max_cycles: u64,
+) -> Result<Vec<String>, AssemblyError> {
+    let mut cpu = Cpu::new();
+    cpu.load(lines);
+    for (address, stub) in stubs {
+        cpu.add_stub(*address, *stub);
+    }
+    let mut failures = vec![];
+    for (location, step) in &test.steps {
+        let values = step.expression.traverse_comma();
+        let command = step.command()?;
+        if command == ".call" {
+            let [callee] = &values[..] else {
+                return Err(test_error(location, ".call needs one label"));
+            };
+            let address = evaluate(callee, labels, location)?;
+            cpu.call(address);
+            let stop = cpu.run(cpu.cycles + max_cycles)?;
+            if stop != Stop::Return {
+                failures.push(format!(
+                    "{} {} did not return: {}, {}",
+                    location, callee, stop, cpu
+                ));
+                return Ok(failures);
+            }
+            continue;
+        }
+        let (target, bytes) = target(&values, labels, location)?;
+        match (command.as_str(), target) {
+            (".set", Target::Register(name)) => set_register(&mut cpu, &name, bytes[0]),
+            (".set", Target::Memory(address)) => {
+                for (i, byte) in bytes.iter().enumerate() {
+                    cpu.memory[address.wrapping_add(i as u16) as usize] = *byte;
+                }
+            }
+            (_, Target::Register(name)) => {
+                let actual = register(&cpu, &name);
+                if actual != bytes[0] {
+                    failures.push(format!(
+                        "{} {} expected {}, got {}",
+                        location,
+                        name,
+                        show(&name, bytes[0]),
+                        show(&name, actual)
+                    ));
+                }
+            }
+            (_, Target::Memory(address)) => {
+                for (i, expected) in bytes.iter().enumerate() {
+                    let address = address.wrapping_add(i as u16);
+                    let actual = cpu.memory[address as usize];
+                    if actual != *expected {
+                        failures.push(format!(
+                            "{} (${:04X}) expected ${:02X}, got ${:02X}",
+                            location, address, expected, actual
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(failures)
+}
+
+// X,$FA / C,1 / ($FA),$11,"text"
+fn target(
+    values: &[Expr],
+    labels: &LabelTable,
+    location: &str,
+) -> Result<(Target, Vec<u8>), AssemblyError> {
+    let (target, values) = values
+        .split_first()
+        .filter(|(_, values)| !values.is_empty())
+        .ok_or(test_error(location, "needs a target and values"))?;
+    let target = match target {
+        Expr::Identifier(name) if REGISTERS.contains(&name.as_str()) => {
+            if values.len() > 1 {
+                return Err(test_error(location, &format!("{} takes one value", name)));
+            }
+            Target::Register(name.clone())
+        }
+        Expr::Parenthesized(address) => Target::Memory(evaluate(address, labels, location)?),
+        target => {
+            return Err(test_error(
+                location,
+                &format!("{} is not a register, flag or (address)", target),
+            ))
+        }
+    };
+    let mut bytes = vec![];
+    for value in values {
+        match value {
+            Expr::StringLiteral(text) => bytes.extend(text.bytes()),
+            value => {
+                let value = evaluate(value, labels, location)?;
+                let byte = u8::try_from(value)
+                    .map_err(|_| test_error(location, &format!("{} is over a byte", value)))?;
+                bytes.push(byte);
+            }
+        }
+    }
+    if bytes.is_empty() {
+        return Err(test_error(location, "needs a target and values"));
+    }
+    Ok((target, bytes))
+}
+
+fn evaluate(expr: &Expr, labels: &LabelTable, location: &str) -> Result<u16, AssemblyError> {
+    expr.evaluate(labels, &0)
+        .map_err(|e| test_error(location, e.message()))
+}
+
+fn flag(name: &str) -> Option<u8> {
+    let bit = "CZID--VN".find(name)?;
+    Some(1 << bit)
+}
+
+fn register(cpu: &Cpu, name: &str) -> u8 {
+    match name {
+        "A" => cpu.a,
+        "X" => cpu.x,
+        "Y" => cpu.y,
+        "S" => cpu.s,
+        "P" => cpu.p,
+        flag_name => flag(flag_name).map_or(0, |flag| (cpu.p & flag != 0) as u8),
+    }
+}
+
+fn set_register(cpu: &mut Cpu, name: &str, value: u8) {
+    match name {
+        "A" => cpu.a = value,
+        "X" => cpu.x = value,
+        "Y" => cpu.y = value,
+        "S" => cpu.s = value,
+        "P" => cpu.p = value,
+        flag_name => {
+            let flag = flag(flag_name).unwrap_or(0);
+            cpu.p = if value == 0 {
+                cpu.p & !flag
+            } else {
+                cpu.p | flag
+            };
+        }
+    }
+}
+
+// flags are shown as 0 / 1
+fn show(name: &str, value: u8) -> String {
+    if flag(name).is_some() {
+        value.to_string()
+    } else {
+        format!("${:02X}", value)
+    }
+}
+
+fn test_error(location: &str, details: &str) -> AssemblyError {
+    AssemblyError::program(&format!("{} {}", location, details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Address, LabelEntry};
+    use crate::parser::{parse_body, Origin};
+
+    fn line(line_number: usize, address: u16, label: &str, body: &str, bytes: &[u8]) -> Line {
+        let label = Some(label.to_string()).filter(|label| !label.is_empty());
+        Line::new(
+            line_number,
+            address,
+            label,
+            parse_body(body).unwrap(),
+            bytes.to_vec(),
+        )
+    }
+
+    fn labels() -> LabelTable {
+        let entry = LabelEntry {
+            name: "double".to_string(),
+            line: 1,
+            address: Address::Full(0x0200),
+        };
+        LabelTable::from([("double".to_string(), entry)])
+    }
+
+    // double: A=< (0+X)=A #=!
+    fn program() -> Vec<Line> {
+        vec![
+            line(
+                1,
+                0x0200,
+                "double",
+                "A=< (0+X)=A #=!",
+                &[0x0a, 0x95, 0x00, 0x60],
+            ),
+            line(2, 0x0204, "", ".test=\"double\"", &[]),
+            line(3, 0x0204, "", ".set=A,$81 .set=X,$10", &[]),
+            line(4, 0x0204, "", ".call=double", &[]),
+            line(5, 0x0204, "", ".expect=A,2 .expect=C,1", &[]),
+            line(6, 0x0204, "", ".expect=($10),$02", &[]),
+            line(7, 0x0204, "", ".endtest", &[]),
+        ]
+    }
+
+    #[test]
+    fn test_extract() {
+        let (lines, tests) = extract(&program()).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].name, "double");
+        assert_eq!(tests[0].steps.len(), 6);
+
+        let stray = vec![line(1, 0, "", ".expect=A,1", &[])];
+        assert_eq!(
+            extract(&stray).err(),
+            Some(test_error("line: 1", ".expect outside of .test"))
+        );
+        let open = vec![line(1, 0, "", ".test=\"open\"", &[])];
+        assert_eq!(
+            extract(&open).err(),
+            Some(test_error("line: 1", ".test without .endtest"))
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let (lines, tests) = extract(&program()).unwrap();
+        let failures = run(&tests[0], &lines, &labels(), &[], 1000).unwrap();
+        assert_eq!(failures, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_run_failures() {
+        let mut program = program();
+        program[4] = line(5, 0x0204, "", ".expect=A,3 .expect=C,0", &[]);
+        let (lines, tests) = extract(&program).unwrap();
+        let failures = run(&tests[0], &lines, &labels(), &[], 1000).unwrap();
+        assert_eq!(
+            failures,
+            vec![
+                "line: 5 A expected $03, got $02".to_string(),
+                "line: 5 C expected 0, got 1".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_location() {
+        let mut program = program();
+        program[4] = line(5, 0x0204, "", ".expect=A,3", &[]);
+        for (i, line) in program.iter_mut().enumerate() {
+            line.origin = Some(Origin {
+                file: "double_test.vtl".to_string(),
+                line: i + 10,
+            });
+        }
+        let (lines, tests) = extract(&program).unwrap();
+        assert_eq!(tests[0].location, "double_test.vtl:11");
+        let failures = run(&tests[0], &lines, &labels(), &[], 1000).unwrap();
+        assert_eq!(
+            failures,
+            vec!["double_test.vtl:14 A expected $03, got $02".to_string()]
+        );
+    }
+}